@@ -1,59 +1,225 @@
-use std::{error::Error, fmt::Display, sync::mpsc::Sender};
+//! Audio format types, and the [`AudioLoopback`] interface implemented by each audio backend.
+use std::{
+    error::Error,
+    fmt::Display,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
 
 use clap::ValueEnum;
-
-use crate::{Nothing, Res};
-
+use layout::ChannelLayout;
+use negotiation::SupportedFormats;
+
+use crate::Res;
+
+/// Conversion of interleaved little-endian samples between [`SampleFormat`]s.
+pub mod convert;
+/// Dither and noise shaping for reducing bit depth.
+pub mod dither;
+/// Speaker positions of the channels in a format.
+pub mod layout;
+/// Channel remapping, downmixing and upmixing.
+pub mod mix;
+/// Negotiation of the requested format with the formats a device supports.
+pub mod negotiation;
+/// Conversion of captured audio to the format written to file.
+pub mod pipeline;
+/// Sample rate conversion of captured audio.
+pub mod resample;
+/// Platform audio backends. Each backend is gated behind its own cargo feature.
 pub mod sys;
 
 /// Audio bit depth and sample format.
-#[derive(ValueEnum, Clone, Copy)]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SampleFormat {
+    /// 8 bit unsigned integer, with silence at `0x80`.
+    #[value(name = "uint8")]
+    UInt8,
+    /// 16 bit signed integer.
     Int16,
+    /// 24 bit signed integer, packed into 3 bytes.
     Int24,
+    /// 24 bit signed integer, in the most significant 3 bytes of a 4 byte container. The least
+    /// significant byte is zero.
+    #[value(name = "int24in32")]
+    Int24In32,
+    /// 32 bit signed integer.
     Int32,
+    /// 32 bit float.
     Float32,
+    /// 64 bit float.
+    Float64,
 }
 
 impl SampleFormat {
-    /// Return the appropriaate bit depth for the selected sample format.
+    /// Return the appropriaate bit depth for the selected sample format. This is the number of
+    /// bits which carry the sample, which may be fewer than the
+    /// [`container_bits`](SampleFormat::container_bits).
     pub fn bit_depth(&self) -> u8 {
         match self {
+            SampleFormat::UInt8 => 8,
             SampleFormat::Int16 => 16,
-            SampleFormat::Int24 => 24,
+            SampleFormat::Int24 | SampleFormat::Int24In32 => 24,
             SampleFormat::Int32 => 32,
             SampleFormat::Float32 => 32,
+            SampleFormat::Float64 => 64,
+        }
+    }
+
+    /// Return the number of bits each sample is stored in.
+    pub fn container_bits(&self) -> u8 {
+        match self {
+            SampleFormat::Int24In32 => 32,
+            format => format.bit_depth(),
         }
     }
 
+    /// Return the number of bytes each sample is stored in.
+    pub fn sample_size(&self) -> usize {
+        self.container_bits() as usize / 8
+    }
+
     /// Gives the type format header for the selected audio format. `1` for PCM, `3` for float.
     pub fn type_format_header(&self) -> u16 {
         match self {
-            SampleFormat::Int16 | SampleFormat::Int24 | SampleFormat::Int32 => 1u16,
-            SampleFormat::Float32 => 3u16,
+            SampleFormat::UInt8
+            | SampleFormat::Int16
+            | SampleFormat::Int24
+            | SampleFormat::Int24In32
+            | SampleFormat::Int32 => 1u16,
+            SampleFormat::Float32 | SampleFormat::Float64 => 3u16,
+        }
+    }
+
+    /// Return the sample format matching a type format header and bit depth, if supported. The
+    /// bit depth is the container size, so 24 bit audio in 32 bit containers is read as 32 bit.
+    pub fn from_type_format_header(type_format: u16, bit_depth: u16) -> Option<SampleFormat> {
+        match (type_format, bit_depth) {
+            (1, 8) => Some(SampleFormat::UInt8),
+            (1, 16) => Some(SampleFormat::Int16),
+            (1, 24) => Some(SampleFormat::Int24),
+            (1, 32) => Some(SampleFormat::Int32),
+            (3, 32) => Some(SampleFormat::Float32),
+            (3, 64) => Some(SampleFormat::Float64),
+            _ => None,
         }
     }
 }
 
+impl Display for SampleFormat {
+    /// Writes the name used to select the format on the command line, e.g. `int16`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_possible_value().unwrap().get_name())
+    }
+}
+
 /// Audio format info requested by the user
+#[derive(Clone)]
 pub struct RequestedAudioFormatInfo {
+    /// Requested sample rate, or `None` for the device default.
     pub sample_rate: Option<u32>,
+    /// Requested number of channels, or `None` for the device default.
     pub num_channels: Option<u8>,
+    /// Requested sample format, or `None` for the device default.
     pub format: Option<SampleFormat>,
 }
 
 impl RequestedAudioFormatInfo {
+    /// Return the bit depth of the requested [`SampleFormat`], if one was requested.
     pub fn bit_depth(&self) -> Option<u8> {
         self.format.map(|f| f.bit_depth())
     }
 }
 
+/// Which side of the audio system to record from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Record the audio played through an output device (loopback).
+    #[default]
+    Render,
+    /// Record from an input device, such as a microphone or line input.
+    Capture,
+}
+
+/// Latency and buffering settings. Small chunks and buffers lower the latency, e.g. for live
+/// metering, at the cost of more overhead and a higher risk of overruns. Large chunks are more
+/// efficient for long recordings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// Number of frames sent to the [`transmitter`](std::sync::mpsc::Sender) in each chunk.
+    pub chunk_size: usize,
+
+    /// How much audio the device or server buffers between reads, or `None` for the backend's
+    /// default. Ignored by sources which aren't devices.
+    pub buffer_duration: Option<Duration>,
+
+    /// How long to wait for audio before checking whether the device is still available. Must be
+    /// at least one period of the device (ALSA). Ignored by backends which can't wait with a
+    /// timeout (PulseAudio), and by sources which aren't devices.
+    pub timeout: Duration,
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig {
+            chunk_size: 4096,
+            buffer_duration: None,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Capture options requested by the user, other than the audio format.
+#[derive(Clone, Default)]
+pub struct CaptureOptions {
+    /// Name of the device to capture from. How the name is interpreted depends on the audio
+    /// system. The system default device for the [`Direction`] is used when not set.
+    pub device: Option<String>,
+
+    /// Whether to record the audio output, or an input device. Ignored by sources which aren't
+    /// devices.
+    pub direction: Direction,
+
+    /// Patterns selecting the ports to record from, for audio systems which route audio between
+    /// named ports (JACK).
+    pub ports: Vec<String>,
+
+    /// Whether sources which aren't devices (such as files) should send audio at real-time speed,
+    /// rather than as fast as possible.
+    pub is_realtime: bool,
+
+    /// Length of audio to record. Sources which aren't devices stop producing audio once this much
+    /// has been sent.
+    pub duration: Option<Duration>,
+
+    /// Chunk size, buffer size and timeout to capture with.
+    pub buffer: BufferConfig,
+}
+
+/// A device which can be recorded from, as listed by [`AudioLoopback::devices`].
+#[derive(Clone)]
+pub struct DeviceInfo {
+    /// Identifier which the audio system uses for the device. Stable between runs.
+    pub id: String,
+    /// Human readable name of the device.
+    pub name: String,
+    /// Whether the device is recorded in loopback, or is an input device.
+    pub direction: Direction,
+    /// The format the device uses when no format is requested, if known.
+    pub default_format: Option<AudioFormatInfo>,
+}
+
 /// Basic info about the audio format to capture and write.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct AudioFormatInfo {
+    /// Number of frames per second.
     pub sample_rate: u32,
+    /// Number of interleaved channels in each frame.
     pub num_channels: u8,
+    /// Format of each sample.
     pub format: SampleFormat,
+    /// Speaker positions of the channels, from the device when it reports them.
+    pub channel_layout: ChannelLayout,
 }
 
 impl AudioFormatInfo {
@@ -62,23 +228,43 @@ impl AudioFormatInfo {
         self.format.bit_depth()
     }
 
+    /// Return the number of bits each sample is stored in, for the chosen [`SampleFormat`].
+    pub fn container_bits(&self) -> u8 {
+        self.format.container_bits()
+    }
+
     /// Return the audio type header to the chosen [`SampleFormat`].
     /// `1` should be used for PCM (integer) audio, `3` for floating point.
     pub fn type_format_header(&self) -> u16 {
         self.format.type_format_header()
     }
 
-    /// Return the number of bytes per second based on the given sample rate, bit depth and number
-    /// of channels.
-    pub fn bytes_per_second(&self) -> u32 {
-        (self.sample_rate * self.bit_depth() as u32 * self.num_channels as u32) / 8
+    /// Return the number of bytes per second based on the given sample rate, container size and
+    /// number of channels.
+    pub fn bytes_per_second(&self) -> u64 {
+        (self.sample_rate as u64 * self.container_bits() as u64 * self.num_channels as u64) / 8
     }
 
     /// Return the block alignment for the audio format.
     /// The block alignment is the number of bytes per audio frame of interleaved audio data.
-    /// It's calculated by multiplying the bytes per sample by the number of channels.
+    /// It's calculated by multiplying the bytes per sample container by the number of channels.
     pub fn block_alignment(&self) -> u16 {
-        (self.bit_depth() as u16 * self.num_channels as u16) / 8
+        (self.container_bits() as u16 * self.num_channels as u16) / 8
+    }
+
+    /// Return how long `num_frames` frames take to play.
+    pub fn duration_of(&self, num_frames: usize) -> Duration {
+        Duration::from_secs_f64(num_frames as f64 / self.sample_rate as f64)
+    }
+
+    /// Return `num_frames` frames of digital silence in this format.
+    pub fn silence(&self, num_frames: usize) -> Vec<u8> {
+        // Zero is silence for every signed integer and float format. Unsigned samples are offset.
+        let value = match self.format {
+            SampleFormat::UInt8 => 0x80,
+            _ => 0,
+        };
+        vec![value; num_frames * self.block_alignment() as usize]
     }
 }
 
@@ -86,36 +272,121 @@ impl Display for AudioFormatInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "\nSample Rate: {}\nBit Depth: {}\nNumber of channels: {}\n",
+            "\nSample Rate: {}\nBit Depth: {}\nNumber of channels: {}\nChannel layout: {}\n",
             self.sample_rate,
             self.bit_depth(),
-            self.num_channels
+            self.num_channels,
+            self.channel_layout
         )?;
         write!(
             f,
             "Sample format: {}",
             match self.format {
+                SampleFormat::UInt8 => "Unsigned integer",
                 SampleFormat::Int16 => "Integer",
                 SampleFormat::Int24 => "Integer",
+                SampleFormat::Int24In32 => "Integer, in 32 bit containers",
                 SampleFormat::Int32 => "Integer",
-                SampleFormat::Float32 => "Float",
+                SampleFormat::Float32 | SampleFormat::Float64 => "Float",
             }
         )
     }
 }
 
+/// A chunk of captured audio, with the timing info the backend could provide.
+pub struct AudioChunk {
+    /// Interleaved audio frames, in the recorder's [`AudioFormatInfo`].
+    pub data: Vec<u8>,
+
+    /// Position of the first frame in the audio stream, counting from `0` at the start of capture.
+    /// Frames lost in a discontinuity are counted, when the backend knows how many were lost.
+    pub position: u64,
+
+    /// Host time at which the first frame was captured. Backends without device timestamps
+    /// estimate it from when the data was read.
+    pub timestamp: Instant,
+
+    /// Set when audio was lost or glitched between the previous chunk and this one, e.g. after a
+    /// buffer overrun.
+    pub discontinuity: bool,
+}
+
+impl AudioChunk {
+    /// Create a chunk of continuous audio, whose first frame was captured at `timestamp`.
+    pub fn new(data: Vec<u8>, position: u64, timestamp: Instant) -> AudioChunk {
+        AudioChunk {
+            data,
+            position,
+            timestamp,
+            discontinuity: false,
+        }
+    }
+}
+
+/// A change to the device being recorded, which ends the capture loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// The system default device changed while recording the default device.
+    Changed,
+    /// The device was unplugged or disabled, or the audio system stopped.
+    Lost,
+}
+
 /// Message to be sent across the audio MPSC channel
 pub enum AudioDataMessage {
-    AudioData(Vec<u8>),
+    /// A chunk of interleaved audio frames, in the current [`AudioFormatInfo`].
+    AudioData(AudioChunk),
+    /// The capture loop stopped because of a [`DeviceEvent`], and the recorder is being reopened
+    /// on the default device.
+    DeviceEvent(DeviceEvent),
+    /// The reopened recorder uses a different format. The following audio is in this format.
+    FormatChanged(AudioFormatInfo),
+    /// Capture failed, and will be retried with a new recorder. The following audio is from the
+    /// new recorder.
+    Retrying {
+        /// Number of consecutive failures, counting from `1`.
+        attempt: u32,
+        /// How long until the recorder is reopened.
+        delay: Duration,
+        /// Description of the error which stopped capture.
+        reason: String,
+    },
+    /// The audio capture loop failed, and will stop sending data.
     Error(Box<dyn Error + Send>),
 }
 
+/// Audio capture system. Implemented by each of the platform backends in [`sys`].
 pub trait AudioLoopback: Send + Sync {
     /// Create a new instance of the `AudioLoopback` system.
-    fn create(format: RequestedAudioFormatInfo) -> Res<impl AudioLoopback>
+    fn create(format: RequestedAudioFormatInfo, options: CaptureOptions) -> Res<impl AudioLoopback>
+    where
+        Self: Sized;
+
+    /// Check whether the audio system can be used on this machine, e.g. that its libraries are
+    /// installed and its server is running.
+    fn is_available() -> bool
     where
         Self: Sized;
 
+    /// List the devices which can be recorded from, in both directions. Sources which aren't
+    /// devices have none.
+    fn devices() -> Res<Vec<DeviceInfo>>
+    where
+        Self: Sized,
+    {
+        Ok(Vec::new())
+    }
+
+    /// Query the formats the device selected by `options` can be opened with. Sources which accept
+    /// any format don't need to implement this.
+    fn supported_formats(options: &CaptureOptions) -> Res<SupportedFormats>
+    where
+        Self: Sized,
+    {
+        let _ = options;
+        Ok(SupportedFormats::any())
+    }
+
     /// Return an [`AudioFormatInfo`] struct with the format info that the loopback recorder has
     /// been configured to use. This will be initialized from the [`RequestedAudioFormatInfo`]
     /// passed to the [`AudioLoopback::create`] method, with missing values being set to the
@@ -123,7 +394,11 @@ pub trait AudioLoopback: Send + Sync {
     fn get_audio_format(&self) -> AudioFormatInfo;
 
     /// Start the audio capture loop. Audio will be written to the [`transmitter`](std::sync::mpsc::Sender).
-    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Nothing;
+    ///
+    /// Returns `None` when the source has no more audio, or the [`DeviceEvent`] which stopped the
+    /// capture, in which case the recorder can't be used again and should be recreated. Errors
+    /// which may be transient should be returned too, so a new recorder can be tried.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>>;
 }
 
 #[cfg(test)]
@@ -140,6 +415,39 @@ mod tests {
         assert_eq!(SampleFormat::Int24.bit_depth(), 24);
         assert_eq!(SampleFormat::Int32.bit_depth(), 32);
         assert_eq!(SampleFormat::Float32.bit_depth(), 32);
+        assert_eq!(SampleFormat::UInt8.bit_depth(), 8);
+        assert_eq!(SampleFormat::Int24In32.bit_depth(), 24);
+        assert_eq!(SampleFormat::Float64.bit_depth(), 64);
+    }
+
+    #[test]
+    fn sample_format_container_is_only_wider_for_24_in_32() {
+        for format in SampleFormat::value_variants() {
+            match format {
+                SampleFormat::Int24In32 => assert_eq!(format.container_bits(), 32),
+                _ => assert_eq!(format.container_bits(), format.bit_depth()),
+            }
+        }
+        assert_eq!(SampleFormat::Int24In32.sample_size(), 4);
+        assert_eq!(SampleFormat::UInt8.sample_size(), 1);
+    }
+
+    #[test]
+    fn sample_format_from_type_format_header_round_trips() {
+        for format in SampleFormat::value_variants() {
+            let header = format.type_format_header();
+            let bit_depth = format.container_bits() as u16;
+            let parsed = SampleFormat::from_type_format_header(header, bit_depth).unwrap();
+            assert_eq!(parsed.container_bits(), format.container_bits());
+            assert_eq!(parsed.type_format_header(), header);
+        }
+    }
+
+    #[test]
+    fn sample_format_from_type_format_header_rejects_unsupported_formats() {
+        assert!(SampleFormat::from_type_format_header(1, 12).is_none());
+        assert!(SampleFormat::from_type_format_header(3, 16).is_none());
+        assert!(SampleFormat::from_type_format_header(2, 16).is_none());
     }
 
     #[test]
@@ -212,6 +520,12 @@ mod tests {
         validate_bytes_per_second(48000, SampleFormat::Int24, DEFAULT_NUM_CHANNELS);
         validate_bytes_per_second(96000, SampleFormat::Int32, DEFAULT_NUM_CHANNELS);
         validate_bytes_per_second(96000, SampleFormat::Float32, DEFAULT_NUM_CHANNELS);
+        validate_bytes_per_second(8000, SampleFormat::UInt8, 1);
+        validate_bytes_per_second(48000, SampleFormat::Int24In32, DEFAULT_NUM_CHANNELS);
+        validate_bytes_per_second(96000, SampleFormat::Float64, DEFAULT_NUM_CHANNELS);
+        // More than fits in 32 bits before dividing by 8.
+        let format_info = create_audio_format_info(10_000_000, SampleFormat::Float64, 8);
+        assert_eq!(format_info.bytes_per_second(), 640_000_000);
     }
 
     #[test]
@@ -225,6 +539,31 @@ mod tests {
         validate_block_alignment(SampleFormat::Int24, DEFAULT_NUM_CHANNELS * 2);
         validate_block_alignment(SampleFormat::Int32, DEFAULT_NUM_CHANNELS * 2);
         validate_block_alignment(SampleFormat::Float32, DEFAULT_NUM_CHANNELS * 2);
+
+        validate_block_alignment(SampleFormat::UInt8, DEFAULT_NUM_CHANNELS);
+        validate_block_alignment(SampleFormat::Int24In32, DEFAULT_NUM_CHANNELS);
+        validate_block_alignment(SampleFormat::Float64, DEFAULT_NUM_CHANNELS);
+        let format_info = create_audio_format_info(48000, SampleFormat::Int24In32, 2);
+        assert_eq!(format_info.block_alignment(), 8);
+    }
+
+    #[test]
+    fn audio_format_info_duration_of_returns_correct_value() {
+        let format_info = create_audio_format_info(48000, SampleFormat::Int16, 2);
+        assert_eq!(format_info.duration_of(48000), Duration::from_secs(1));
+        assert_eq!(format_info.duration_of(4800), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn audio_format_info_silence_is_whole_frames_of_zero() {
+        let format_info = create_audio_format_info(48000, SampleFormat::Int24, 2);
+        assert_eq!(format_info.silence(10), vec![0u8; 60]);
+    }
+
+    #[test]
+    fn audio_format_info_silence_is_midpoint_for_unsigned_samples() {
+        let format_info = create_audio_format_info(8000, SampleFormat::UInt8, 2);
+        assert_eq!(format_info.silence(10), vec![0x80u8; 20]);
     }
 
     fn create_audio_format_info(
@@ -236,20 +575,21 @@ mod tests {
             sample_rate,
             num_channels,
             format: sample_format,
+            channel_layout: ChannelLayout::default_for(num_channels),
         }
     }
 
     fn validate_bytes_per_second(sample_rate: u32, sample_format: SampleFormat, num_channels: u8) {
         let format_info = create_audio_format_info(sample_rate, sample_format, num_channels);
         let expected_result =
-            (sample_rate * sample_format.bit_depth() as u32 * num_channels as u32) / 8;
+            (sample_rate as u64 * sample_format.container_bits() as u64 * num_channels as u64) / 8;
         assert_eq!(format_info.bytes_per_second(), expected_result);
     }
 
     fn validate_block_alignment(sample_format: SampleFormat, num_channels: u8) {
         let format_info =
             create_audio_format_info(DEFAULT_SAMPLE_RATE, sample_format, num_channels);
-        let expected_result: u16 = (format_info.bit_depth() as u16 * num_channels as u16) / 8;
+        let expected_result: u16 = (format_info.container_bits() as u16 * num_channels as u16) / 8;
         assert_eq!(format_info.block_alignment(), expected_result);
     }
 }
@@ -0,0 +1,243 @@
+use super::SampleFormat;
+
+/// Read the sample at the start of `bytes` as a value in the range `[-1, 1)`. Integer samples are
+/// scaled by the largest power of two for the bit depth.
+pub fn read_sample(format: SampleFormat, bytes: &[u8]) -> f64 {
+    match format {
+        SampleFormat::UInt8 => (bytes[0] as f64 - 128.0) / 2f64.powi(7),
+        SampleFormat::Int16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 2f64.powi(15),
+        SampleFormat::Int24 => {
+            // Shift into the top of an i32, so the sign is extended.
+            let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+            value as f64 / 2f64.powi(23)
+        }
+        SampleFormat::Int24In32 => {
+            let value = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) >> 8;
+            value as f64 / 2f64.powi(23)
+        }
+        SampleFormat::Int32 => {
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 2f64.powi(31)
+        }
+        SampleFormat::Float32 => {
+            f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64
+        }
+        SampleFormat::Float64 => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+    }
+}
+
+/// Append a sample in the range `[-1, 1]` to the buffer, in the given format. Integer samples are
+/// scaled by the largest power of two for the bit depth, rounded to the nearest value, and clipped
+/// to the range of the format. NaN is written as silence. Float samples are written as they are.
+pub fn write_sample(format: SampleFormat, sample: f64, buffer: &mut Vec<u8>) {
+    let scale = |bits: i32| {
+        let max = 2f64.powi(bits - 1);
+        match sample.is_nan() {
+            true => 0.0,
+            false => (sample * max).round().clamp(-max, max - 1.0),
+        }
+    };
+    match format {
+        SampleFormat::UInt8 => buffer.push((scale(8) + 128.0) as u8),
+        SampleFormat::Int16 => buffer.extend_from_slice(&(scale(16) as i16).to_le_bytes()),
+        SampleFormat::Int24 => buffer.extend_from_slice(&(scale(24) as i32).to_le_bytes()[..3]),
+        SampleFormat::Int24In32 => {
+            buffer.extend_from_slice(&((scale(24) as i32) << 8).to_le_bytes())
+        }
+        SampleFormat::Int32 => buffer.extend_from_slice(&(scale(32) as i32).to_le_bytes()),
+        SampleFormat::Float32 => buffer.extend_from_slice(&(sample as f32).to_le_bytes()),
+        SampleFormat::Float64 => buffer.extend_from_slice(&sample.to_le_bytes()),
+    }
+}
+
+/// Convert interleaved samples from one sample format to another. Widening is lossless, and
+/// narrowing rounds to the nearest value and clips, as in [`write_sample`]. A trailing partial
+/// sample is dropped.
+pub fn convert_samples(data: &[u8], from: SampleFormat, to: SampleFormat) -> Vec<u8> {
+    let num_samples = data.len() / from.sample_size();
+    let mut output = Vec::with_capacity(num_samples * to.sample_size());
+    for sample in data.chunks_exact(from.sample_size()) {
+        write_sample(to, read_sample(from, sample), &mut output);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum;
+
+    use super::*;
+
+    #[test]
+    fn write_sample_encodes_every_format() {
+        let mut buffer = Vec::new();
+        write_sample(SampleFormat::Int16, 0.5, &mut buffer);
+        assert_eq!(buffer, 16384i16.to_le_bytes());
+
+        buffer.clear();
+        write_sample(SampleFormat::Int24, -1.0, &mut buffer);
+        assert_eq!(buffer, vec![0x00, 0x00, 0x80]);
+
+        buffer.clear();
+        write_sample(SampleFormat::Int32, 1.0, &mut buffer);
+        assert_eq!(buffer, i32::MAX.to_le_bytes());
+
+        buffer.clear();
+        write_sample(SampleFormat::Float32, 0.25, &mut buffer);
+        assert_eq!(buffer, 0.25f32.to_le_bytes());
+
+        buffer.clear();
+        write_sample(SampleFormat::UInt8, 0.0, &mut buffer);
+        write_sample(SampleFormat::UInt8, -1.0, &mut buffer);
+        write_sample(SampleFormat::UInt8, 1.0, &mut buffer);
+        assert_eq!(buffer, vec![0x80, 0x00, 0xff]);
+
+        buffer.clear();
+        write_sample(SampleFormat::Int24In32, -1.0, &mut buffer);
+        assert_eq!(buffer, vec![0x00, 0x00, 0x00, 0x80]);
+
+        buffer.clear();
+        write_sample(SampleFormat::Float64, 0.25, &mut buffer);
+        assert_eq!(buffer, 0.25f64.to_le_bytes());
+    }
+
+    #[test]
+    fn read_sample_decodes_what_write_sample_encodes() {
+        for format in SampleFormat::value_variants() {
+            for value in [-1.0, -0.5, 0.0, 0.25] {
+                let mut buffer = Vec::new();
+                write_sample(*format, value, &mut buffer);
+                assert_eq!(read_sample(*format, &buffer), value);
+            }
+        }
+    }
+
+    #[test]
+    fn convert_samples_changes_sample_size() {
+        let data: Vec<u8> = [0.5f32, -0.25]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let converted = convert_samples(&data, SampleFormat::Float32, SampleFormat::Int16);
+        assert_eq!(
+            converted,
+            [16384i16.to_le_bytes(), (-8192i16).to_le_bytes()].concat()
+        );
+
+        let widened = convert_samples(&converted, SampleFormat::Int16, SampleFormat::Int24);
+        assert_eq!(widened, vec![0x00, 0x00, 0x40, 0x00, 0x00, 0xe0]);
+    }
+
+    /// Encode each value in `format`.
+    fn samples(format: SampleFormat, values: &[f64]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for value in values {
+            write_sample(format, *value, &mut buffer);
+        }
+        buffer
+    }
+
+    #[test]
+    fn every_format_converts_to_every_other_format() {
+        // Exactly representable in every format.
+        let values = [-1.0, -0.5, 0.0, 0.25, 0.5];
+        for from in SampleFormat::value_variants() {
+            for to in SampleFormat::value_variants() {
+                let converted = convert_samples(&samples(*from, &values), *from, *to);
+                assert_eq!(converted, samples(*to, &values), "{from} to {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn widening_is_lossless() {
+        let values: Vec<u8> = [i16::MIN, -12345, -1, 0, 1, 12345, i16::MAX]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let widened = [
+            SampleFormat::Int24,
+            SampleFormat::Int24In32,
+            SampleFormat::Int32,
+            SampleFormat::Float32,
+            SampleFormat::Float64,
+        ];
+        for format in widened {
+            let converted = convert_samples(&values, SampleFormat::Int16, format);
+            let restored = convert_samples(&converted, format, SampleFormat::Int16);
+            assert_eq!(restored, values, "int16 through {format}");
+        }
+
+        let converted = convert_samples(&values, SampleFormat::Int16, SampleFormat::Int32);
+        assert_eq!(converted[8..12], (-1i32 << 16).to_le_bytes());
+    }
+
+    #[test]
+    fn narrowing_rounds_to_nearest_value() {
+        // 1.5 and -1.5 steps of 16 bit audio, and just under half a step.
+        let values: Vec<u8> = [0x18000i32, -0x18000, 0x7FFF]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let converted = convert_samples(&values, SampleFormat::Int32, SampleFormat::Int16);
+        assert_eq!(
+            converted,
+            [
+                2i16.to_le_bytes(),
+                (-2i16).to_le_bytes(),
+                0i16.to_le_bytes()
+            ]
+            .concat()
+        );
+
+        let converted = convert_samples(&values, SampleFormat::Int32, SampleFormat::Int24);
+        assert_eq!(
+            converted,
+            vec![0x80, 0x01, 0x00, 0x80, 0xFE, 0xFF, 0x80, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn narrowing_clips_to_range_of_format() {
+        let values = samples(
+            SampleFormat::Float32,
+            &[1.0, 1.5, -1.0, -2.0, f64::INFINITY, f64::NEG_INFINITY],
+        );
+        let converted = convert_samples(&values, SampleFormat::Float32, SampleFormat::Int16);
+        let expected: Vec<u8> = [i16::MAX, i16::MAX, i16::MIN, i16::MIN, i16::MAX, i16::MIN]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        assert_eq!(converted, expected);
+
+        let converted = convert_samples(&values, SampleFormat::Float32, SampleFormat::Int24);
+        assert_eq!(converted[..6], [0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0x7F]);
+        assert_eq!(converted[9..12], [0x00, 0x00, 0x80]);
+
+        let converted = convert_samples(&values, SampleFormat::Float32, SampleFormat::UInt8);
+        assert_eq!(converted, vec![0xFF, 0xFF, 0x00, 0x00, 0xFF, 0x00]);
+
+        // Float formats can hold samples out of range, so they're kept.
+        let converted = convert_samples(&values, SampleFormat::Float32, SampleFormat::Float64);
+        assert_eq!(read_sample(SampleFormat::Float64, &converted[8..16]), 1.5);
+    }
+
+    #[test]
+    fn nan_is_written_as_silence() {
+        for format in [
+            SampleFormat::UInt8,
+            SampleFormat::Int16,
+            SampleFormat::Int32,
+        ] {
+            let mut buffer = Vec::new();
+            write_sample(format, f64::NAN, &mut buffer);
+            assert_eq!(buffer, samples(format, &[0.0]), "{format}");
+        }
+    }
+
+    #[test]
+    fn trailing_partial_sample_is_dropped() {
+        let data = [0x00, 0x40, 0x00];
+        let converted = convert_samples(&data, SampleFormat::Int16, SampleFormat::Float32);
+        assert_eq!(converted, 0.5f32.to_le_bytes());
+    }
+}
@@ -0,0 +1,267 @@
+use clap::ValueEnum;
+
+use super::SampleFormat;
+
+/// Coefficients of the [`Dither::Shaped`] error feedback filter, applied to the most recent
+/// quantisation errors first. Shapes the noise by `1 - z^-1`, a gentle high-pass.
+const FIRST_ORDER: [f64; 1] = [1.0];
+
+/// Coefficients of the [`Dither::Lipshitz`] error feedback filter, applied to the most recent
+/// quantisation errors first. Lipshitz's E-weighted filter for 44.1 kHz, which moves the noise
+/// out of the frequencies the ear is most sensitive to.
+const LIPSHITZ: [f64; 5] = [2.033, -2.165, 1.959, -1.590, 0.6149];
+
+/// How to dither audio when reducing its bit depth.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Round to the nearest value. The rounding error follows the signal, which is heard as
+    /// distortion on quiet material.
+    #[default]
+    None,
+    /// Add triangular (TPDF) noise of up to one step of the output format before rounding, which
+    /// turns the rounding error into a constant, white noise floor.
+    Tpdf,
+    /// TPDF dither with first-order noise shaping, which moves the noise towards high
+    /// frequencies, for any sample rate.
+    Shaped,
+    /// TPDF dither with Lipshitz's five-tap noise shaping, which moves the noise out of the
+    /// frequencies the ear is most sensitive to. Designed for 44.1 kHz, and suited to 48 kHz.
+    Lipshitz,
+}
+
+impl Dither {
+    /// Return the error feedback filter coefficients, most recent error first.
+    fn filter(&self) -> &'static [f64] {
+        match self {
+            Dither::None | Dither::Tpdf => &[],
+            Dither::Shaped => &FIRST_ORDER,
+            Dither::Lipshitz => &LIPSHITZ,
+        }
+    }
+
+    /// Return whether reducing audio from `from` to `to` should be dithered, when `processed`
+    /// tells whether the audio was processed in between, which leaves values between the steps
+    /// of any integer format.
+    pub fn is_needed(&self, from: SampleFormat, to: SampleFormat, processed: bool) -> bool {
+        let reduced = is_float(from) || from.bit_depth() > to.bit_depth() || processed;
+        *self != Dither::None && !is_float(to) && reduced
+    }
+}
+
+fn is_float(format: SampleFormat) -> bool {
+    matches!(format, SampleFormat::Float32 | SampleFormat::Float64)
+}
+
+/// SplitMix64, a small generator which is plenty for dither noise, and is the same on every
+/// platform for a seed.
+struct Noise {
+    state: u64,
+}
+
+impl Noise {
+    /// Return a uniform value in the range `[0, 1)`.
+    fn next(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // The top 53 bits fill the mantissa of an f64.
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Return triangular noise in the range `(-1, 1)`.
+    fn triangular(&mut self) -> f64 {
+        self.next() - self.next()
+    }
+}
+
+/// Quantises interleaved samples to the steps of an integer sample format, with dither and noise
+/// shaping. Samples are values in the range `[-1, 1]`, as read by
+/// [`read_sample`](super::convert::read_sample), and come out as exact values of the format, so
+/// [`write_sample`](super::convert::write_sample) writes them unchanged.
+///
+/// The noise comes from a seeded generator, so the same input and seed always give the same
+/// output.
+pub struct Ditherer {
+    dither: Dither,
+
+    /// Size of a step of the output format.
+    step: f64,
+
+    /// Range of the output format, in steps.
+    min: f64,
+    max: f64,
+
+    num_channels: usize,
+
+    /// Channel of the next sample.
+    channel: usize,
+
+    /// Recent quantisation errors of each channel, most recent first, in steps.
+    errors: Vec<Vec<f64>>,
+
+    noise: Noise,
+}
+
+impl Ditherer {
+    /// Create a ditherer for audio with `num_channels` channels, written in the integer `format`.
+    pub fn new(dither: Dither, format: SampleFormat, num_channels: u8, seed: u64) -> Ditherer {
+        let max = 2f64.powi(format.bit_depth() as i32 - 1);
+        Ditherer {
+            dither,
+            step: 1.0 / max,
+            min: -max,
+            max: max - 1.0,
+            num_channels: num_channels.max(1) as usize,
+            channel: 0,
+            errors: vec![vec![0.0; dither.filter().len()]; num_channels.max(1) as usize],
+            noise: Noise { state: seed },
+        }
+    }
+
+    /// Quantise the next chunk of interleaved samples in place. Chunks needn't end on a frame.
+    pub fn process(&mut self, samples: &mut [f64]) {
+        let filter = self.dither.filter();
+        for sample in samples {
+            let errors = &mut self.errors[self.channel];
+            self.channel = (self.channel + 1) % self.num_channels;
+            // NaN is written as silence, and mustn't reach the error feedback.
+            if sample.is_nan() {
+                continue;
+            }
+
+            let shaped = *sample / self.step
+                - filter
+                    .iter()
+                    .zip(errors.iter())
+                    .map(|(coefficient, error)| coefficient * error)
+                    .sum::<f64>();
+            let noise = match self.dither {
+                Dither::None => 0.0,
+                _ => self.noise.triangular(),
+            };
+            let quantised = (shaped + noise).round().clamp(self.min, self.max);
+            if !errors.is_empty() {
+                errors.rotate_right(1);
+                // Clipping gives errors the feedback can't correct, so they're limited to the
+                // largest error of the noise and rounding, to keep the filter stable.
+                errors[0] = (quantised - shaped).clamp(-1.5, 1.5);
+            }
+            *sample = quantised * self.step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    fn dither(dither: Dither, input: &[f64], seed: u64) -> Vec<f64> {
+        let mut output = input.to_vec();
+        Ditherer::new(dither, SampleFormat::Int16, 1, seed).process(&mut output);
+        output
+    }
+
+    /// A sine of a few steps of 16 bit audio, where rounding distortion is worst.
+    fn quiet_sine(len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|i| 3.3 / 32768.0 * (2.0 * PI * 1000.0 * i as f64 / 44100.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn output_is_deterministic_for_a_seed() {
+        let input = quiet_sine(1000);
+        for kind in Dither::value_variants() {
+            assert_eq!(dither(*kind, &input, 1), dither(*kind, &input, 1));
+        }
+        assert_ne!(
+            dither(Dither::Tpdf, &input, 1),
+            dither(Dither::Tpdf, &input, 2)
+        );
+    }
+
+    #[test]
+    fn samples_are_quantised_to_steps_of_format() {
+        let input = quiet_sine(1000);
+        for kind in Dither::value_variants() {
+            for sample in dither(*kind, &input, 1) {
+                let steps = sample * 32768.0;
+                assert_eq!(steps, steps.round());
+            }
+        }
+        // Clipped to the range of the format.
+        let mut loud: Vec<f64> = (0..100).map(|i| [1.0, -1.0][i % 2]).collect();
+        Ditherer::new(Dither::Lipshitz, SampleFormat::Int16, 1, 1).process(&mut loud);
+        assert!(loud
+            .iter()
+            .all(|sample| (-1.0..=32767.0 / 32768.0).contains(sample)));
+    }
+
+    #[test]
+    fn tpdf_error_is_at_most_one_and_a_half_steps() {
+        let input = quiet_sine(10000);
+        let output = dither(Dither::Tpdf, &input, 1);
+        for (input, output) in input.iter().zip(&output) {
+            assert!((output - input).abs() * 32768.0 <= 1.5);
+        }
+    }
+
+    #[test]
+    fn dither_preserves_levels_below_one_step() {
+        // Rounding loses a level of a third of a step, dithering keeps it on average.
+        let input = vec![0.3 / 32768.0; 100000];
+        let mean = |samples: Vec<f64>| samples.iter().sum::<f64>() / samples.len() as f64 * 32768.0;
+        assert_eq!(mean(dither(Dither::None, &input, 1)), 0.0);
+        assert!((mean(dither(Dither::Tpdf, &input, 1)) - 0.3).abs() < 0.01);
+    }
+
+    #[test]
+    fn noise_shaping_moves_noise_to_high_frequencies() {
+        // The power of the error at frequencies up to 4 kHz, where the ear is most sensitive.
+        let low_frequency_noise = |kind: Dither| {
+            let input = quiet_sine(44100);
+            let output = dither(kind, &input, 1);
+            let error: Vec<f64> = output.iter().zip(&input).map(|(o, i)| o - i).collect();
+            (1..=40)
+                .map(|i| {
+                    let omega = 2.0 * PI * (i * 100) as f64 / 44100.0;
+                    let (re, im) = error
+                        .iter()
+                        .enumerate()
+                        .fold((0.0, 0.0), |(re, im), (n, e)| {
+                            (
+                                re + e * (omega * n as f64).cos(),
+                                im + e * (omega * n as f64).sin(),
+                            )
+                        });
+                    re * re + im * im
+                })
+                .sum::<f64>()
+        };
+        let flat = low_frequency_noise(Dither::Tpdf);
+        assert!(low_frequency_noise(Dither::Shaped) < flat / 2.0);
+        assert!(low_frequency_noise(Dither::Lipshitz) < flat / 4.0);
+    }
+
+    #[test]
+    fn chunked_input_gives_same_output() {
+        let input: Vec<f64> = quiet_sine(1000)
+            .into_iter()
+            .flat_map(|sample| [sample, -sample])
+            .collect();
+        let mut whole = input.clone();
+        Ditherer::new(Dither::Lipshitz, SampleFormat::Int16, 2, 1).process(&mut whole);
+
+        // Chunks which end part way through a frame.
+        let mut chunked = input.clone();
+        let mut ditherer = Ditherer::new(Dither::Lipshitz, SampleFormat::Int16, 2, 1);
+        for chunk in chunked.chunks_mut(33) {
+            ditherer.process(chunk);
+        }
+        assert_eq!(chunked, whole);
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt::Display;
+
+/// A speaker position. The value is the `SPEAKER_*` flag used for the position in WAV channel
+/// masks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    /// Front left.
+    FrontLeft = 0x1,
+    /// Front right.
+    FrontRight = 0x2,
+    /// Front centre.
+    FrontCenter = 0x4,
+    /// Low frequency effects (LFE).
+    LowFrequency = 0x8,
+    /// Back (rear) left.
+    BackLeft = 0x10,
+    /// Back (rear) right.
+    BackRight = 0x20,
+    /// Front left of centre.
+    FrontLeftOfCenter = 0x40,
+    /// Front right of centre.
+    FrontRightOfCenter = 0x80,
+    /// Back (rear) centre.
+    BackCenter = 0x100,
+    /// Side left.
+    SideLeft = 0x200,
+    /// Side right.
+    SideRight = 0x400,
+    /// Top centre.
+    TopCenter = 0x800,
+    /// Top front left.
+    TopFrontLeft = 0x1000,
+    /// Top front centre.
+    TopFrontCenter = 0x2000,
+    /// Top front right.
+    TopFrontRight = 0x4000,
+    /// Top back left.
+    TopBackLeft = 0x8000,
+    /// Top back centre.
+    TopBackCenter = 0x10000,
+    /// Top back right.
+    TopBackRight = 0x20000,
+}
+
+impl Speaker {
+    /// Every position, in the order channels are stored in.
+    pub const ALL: [Speaker; 18] = [
+        Speaker::FrontLeft,
+        Speaker::FrontRight,
+        Speaker::FrontCenter,
+        Speaker::LowFrequency,
+        Speaker::BackLeft,
+        Speaker::BackRight,
+        Speaker::FrontLeftOfCenter,
+        Speaker::FrontRightOfCenter,
+        Speaker::BackCenter,
+        Speaker::SideLeft,
+        Speaker::SideRight,
+        Speaker::TopCenter,
+        Speaker::TopFrontLeft,
+        Speaker::TopFrontCenter,
+        Speaker::TopFrontRight,
+        Speaker::TopBackLeft,
+        Speaker::TopBackCenter,
+        Speaker::TopBackRight,
+    ];
+
+    /// Return the `SPEAKER_*` flag for the position.
+    pub fn flag(&self) -> u32 {
+        *self as u32
+    }
+
+    /// Return the usual abbreviation for the position, e.g. `FL` or `LFE`.
+    pub fn short_name(&self) -> &'static str {
+        match self {
+            Speaker::FrontLeft => "FL",
+            Speaker::FrontRight => "FR",
+            Speaker::FrontCenter => "FC",
+            Speaker::LowFrequency => "LFE",
+            Speaker::BackLeft => "BL",
+            Speaker::BackRight => "BR",
+            Speaker::FrontLeftOfCenter => "FLC",
+            Speaker::FrontRightOfCenter => "FRC",
+            Speaker::BackCenter => "BC",
+            Speaker::SideLeft => "SL",
+            Speaker::SideRight => "SR",
+            Speaker::TopCenter => "TC",
+            Speaker::TopFrontLeft => "TFL",
+            Speaker::TopFrontCenter => "TFC",
+            Speaker::TopFrontRight => "TFR",
+            Speaker::TopBackLeft => "TBL",
+            Speaker::TopBackCenter => "TBC",
+            Speaker::TopBackRight => "TBR",
+        }
+    }
+}
+
+/// The speaker positions of the channels in a format, as in a WAV channel mask. Channels are in
+/// the order of the [`Speaker::ALL`] positions, and any channels after the last position have no
+/// position. The default layout gives no channel a position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChannelLayout {
+    mask: u32,
+}
+
+impl ChannelLayout {
+    /// No channel has a known position.
+    pub const UNSPECIFIED: ChannelLayout = ChannelLayout { mask: 0 };
+
+    /// Front centre.
+    pub const MONO: ChannelLayout = ChannelLayout { mask: 0x4 };
+
+    /// Front left and right.
+    pub const STEREO: ChannelLayout = ChannelLayout { mask: 0x3 };
+
+    /// Front left and right, back left and right.
+    pub const QUAD: ChannelLayout = ChannelLayout { mask: 0x33 };
+
+    /// Front left, right and centre, LFE, back left and right.
+    pub const SURROUND_5_1: ChannelLayout = ChannelLayout { mask: 0x3F };
+
+    /// Front left, right and centre, LFE, back left and right, side left and right.
+    pub const SURROUND_7_1: ChannelLayout = ChannelLayout { mask: 0x63F };
+
+    /// Create a layout from a WAV channel mask. Reserved bits are ignored.
+    pub fn from_mask(mask: u32) -> ChannelLayout {
+        let valid_bits = Speaker::ALL
+            .iter()
+            .fold(0, |bits, speaker| bits | speaker.flag());
+        ChannelLayout {
+            mask: mask & valid_bits,
+        }
+    }
+
+    /// Create a layout from the position of each channel. Returns `None` unless the positions are
+    /// distinct, and in the order channels are stored in, which is all a layout can describe.
+    pub fn from_speakers(speakers: &[Speaker]) -> Option<ChannelLayout> {
+        let is_ordered = speakers
+            .windows(2)
+            .all(|pair| pair[0].flag() < pair[1].flag());
+        is_ordered.then(|| ChannelLayout {
+            mask: speakers
+                .iter()
+                .fold(0, |mask, speaker| mask | speaker.flag()),
+        })
+    }
+
+    /// Return the usual layout for a number of channels, or [`ChannelLayout::UNSPECIFIED`] for
+    /// channel counts without a usual layout.
+    pub fn default_for(num_channels: u8) -> ChannelLayout {
+        let mask = match num_channels {
+            1 => ChannelLayout::MONO.mask,
+            2 => ChannelLayout::STEREO.mask,
+            // Front left, right and centre.
+            3 => 0x7,
+            4 => ChannelLayout::QUAD.mask,
+            // Front left, right and centre, back left and right.
+            5 => 0x37,
+            6 => ChannelLayout::SURROUND_5_1.mask,
+            // 6.1: front left, right and centre, LFE, back centre, side left and right.
+            7 => 0x70F,
+            8 => ChannelLayout::SURROUND_7_1.mask,
+            _ => 0,
+        };
+        ChannelLayout { mask }
+    }
+
+    /// Return the WAV channel mask.
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Return the number of channels with a position.
+    pub fn num_speakers(&self) -> usize {
+        self.mask.count_ones() as usize
+    }
+
+    /// Return the positions of the channels, in channel order.
+    pub fn speakers(&self) -> impl Iterator<Item = Speaker> + '_ {
+        Speaker::ALL
+            .into_iter()
+            .filter(|speaker| self.mask & speaker.flag() != 0)
+    }
+
+    /// Return the position of a channel, if it has one.
+    pub fn position(&self, channel: usize) -> Option<Speaker> {
+        self.speakers().nth(channel)
+    }
+
+    /// Return the common name of the layout, e.g. `stereo` or `5.1`, if it has one.
+    pub fn name(&self) -> Option<&'static str> {
+        match *self {
+            ChannelLayout::MONO => Some("mono"),
+            ChannelLayout::STEREO => Some("stereo"),
+            ChannelLayout::QUAD => Some("quad"),
+            ChannelLayout::SURROUND_5_1 => Some("5.1"),
+            ChannelLayout::SURROUND_7_1 => Some("7.1"),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ChannelLayout {
+    /// Writes the name of the layout, or the positions of the channels, e.g. `FL, FR, LFE`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = self.name() {
+            return write!(f, "{name}");
+        }
+        if self.mask == 0 {
+            return write!(f, "unspecified");
+        }
+        let speakers: Vec<&str> = self.speakers().map(|s| s.short_name()).collect();
+        write!(f, "{}", speakers.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_layout_has_a_speaker_per_channel() {
+        for num_channels in 1..=8 {
+            let layout = ChannelLayout::default_for(num_channels);
+            assert_eq!(layout.num_speakers(), num_channels as usize);
+        }
+        assert_eq!(ChannelLayout::default_for(9), ChannelLayout::UNSPECIFIED);
+        assert_eq!(ChannelLayout::default_for(6), ChannelLayout::SURROUND_5_1);
+    }
+
+    #[test]
+    fn speakers_are_in_channel_order() {
+        let layout = ChannelLayout::SURROUND_5_1;
+        assert_eq!(layout.position(0), Some(Speaker::FrontLeft));
+        assert_eq!(layout.position(3), Some(Speaker::LowFrequency));
+        assert_eq!(layout.position(5), Some(Speaker::BackRight));
+        assert_eq!(layout.position(6), None);
+    }
+
+    #[test]
+    fn from_speakers_requires_channel_order() {
+        let speakers = [
+            Speaker::FrontLeft,
+            Speaker::FrontRight,
+            Speaker::LowFrequency,
+        ];
+        let layout = ChannelLayout::from_speakers(&speakers).unwrap();
+        assert_eq!(layout.mask(), 0xB);
+        assert_eq!(layout.to_string(), "FL, FR, LFE");
+
+        let speakers = [Speaker::FrontRight, Speaker::FrontLeft];
+        assert!(ChannelLayout::from_speakers(&speakers).is_none());
+        let speakers = [Speaker::FrontLeft, Speaker::FrontLeft];
+        assert!(ChannelLayout::from_speakers(&speakers).is_none());
+    }
+
+    #[test]
+    fn from_mask_ignores_reserved_bits() {
+        assert_eq!(ChannelLayout::from_mask(0x8000_0003), ChannelLayout::STEREO);
+        assert_eq!(ChannelLayout::SURROUND_7_1.to_string(), "7.1");
+        assert_eq!(ChannelLayout::UNSPECIFIED.to_string(), "unspecified");
+    }
+}
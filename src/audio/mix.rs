@@ -0,0 +1,342 @@
+use std::{error::Error, fmt::Display, str::FromStr};
+
+use crate::Res;
+
+use super::layout::{ChannelLayout, Speaker};
+use super::AudioFormatInfo;
+
+/// Coefficient for channels which are mixed into two outputs, or to the side of the listener.
+/// Keeps the power of the channel the same, at -3 dB.
+const MINUS_3_DB: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug)]
+enum MixError {
+    InvalidSpec(String),
+    ChannelOutOfRange(usize, u8),
+    MatrixColumns(usize, u8),
+}
+
+impl Error for MixError {}
+
+impl Display for MixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MixError::InvalidSpec(spec) => write!(
+                f,
+                "'{spec}' is not a channel mix. Expected mono, stereo, pick:<channels> (e.g. \
+                 pick:3,4) or matrix:<rows> (e.g. matrix:1,0,0.5;0,1,0.5)"
+            ),
+            MixError::ChannelOutOfRange(channel, num_channels) => write!(
+                f,
+                "Can't pick channel {channel}, the audio only has {num_channels} channels"
+            ),
+            MixError::MatrixColumns(columns, num_channels) => write!(
+                f,
+                "Channel matrix has {columns} columns, but the audio has {num_channels} channels"
+            ),
+        }
+    }
+}
+
+/// How to mix the captured channels into the channels written to file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelMix {
+    /// A single channel, the average of every captured channel.
+    Mono,
+    /// Stereo, downmixed from the speaker positions of the captured channels with the standard
+    /// coefficients (ITU-R BS.775): centre and surround channels are mixed in at -3 dB, and LFE is
+    /// dropped. The mix is scaled down when it could otherwise clip. Mono is copied to both sides.
+    Stereo,
+    /// The captured channels to keep, by index from `0`, in the order to write them. Channels can
+    /// be reordered or repeated.
+    Pick(Vec<usize>),
+    /// The coefficient of each captured channel in each written channel. Each row is a written
+    /// channel.
+    Matrix(Vec<Vec<f64>>),
+}
+
+impl FromStr for ChannelMix {
+    type Err = Box<dyn Error + Send + Sync>;
+
+    /// Parses `mono`, `stereo`, `pick:<channels>` or `matrix:<rows>`. Picked channels are numbered
+    /// from `1`, separated by commas, and can include ranges, e.g. `pick:3-4` or `pick:2,1`. Matrix
+    /// rows are separated by semicolons, and their coefficients by commas, e.g.
+    /// `matrix:1,0,0.5;0,1,0.5`.
+    fn from_str(spec: &str) -> Result<ChannelMix, Self::Err> {
+        let invalid = || -> Self::Err { Box::new(MixError::InvalidSpec(spec.to_owned())) };
+        let (name, values) = spec.split_once(':').unwrap_or((spec, ""));
+        match (name, values) {
+            ("mono", "") => Ok(ChannelMix::Mono),
+            ("stereo", "") => Ok(ChannelMix::Stereo),
+            ("pick", values) => {
+                let mut channels = Vec::new();
+                for value in values.split(',') {
+                    let (first, last) = value.split_once('-').unwrap_or((value, value));
+                    let first: usize = first.trim().parse().map_err(|_| invalid())?;
+                    let last: usize = last.trim().parse().map_err(|_| invalid())?;
+                    if first == 0 || last < first {
+                        return Err(invalid());
+                    }
+                    channels.extend(first - 1..last);
+                }
+                Ok(ChannelMix::Pick(channels))
+            }
+            ("matrix", values) => {
+                let rows = values
+                    .split(';')
+                    .map(|row| {
+                        row.split(',')
+                            .map(|value| value.trim().parse::<f64>())
+                            .collect::<Result<Vec<f64>, _>>()
+                    })
+                    .collect::<Result<Vec<Vec<f64>>, _>>()
+                    .map_err(|_| invalid())?;
+                let columns = rows[0].len();
+                if rows.iter().any(|row| row.len() != columns) {
+                    return Err(invalid());
+                }
+                Ok(ChannelMix::Matrix(rows))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl ChannelMix {
+    /// Return the matrix which applies the mix to audio in the given format.
+    pub fn matrix(&self, format: &AudioFormatInfo) -> Res<ChannelMatrix> {
+        let num_inputs = format.num_channels as usize;
+        let matrix = match self {
+            ChannelMix::Mono => ChannelMatrix {
+                rows: vec![vec![1.0 / num_inputs as f64; num_inputs]],
+                layout: ChannelLayout::MONO,
+            },
+            ChannelMix::Stereo => ChannelMatrix {
+                rows: stereo_downmix(format),
+                layout: ChannelLayout::STEREO,
+            },
+            ChannelMix::Pick(channels) => {
+                let mut rows = Vec::new();
+                for channel in channels {
+                    if *channel >= num_inputs {
+                        return Err(Box::new(MixError::ChannelOutOfRange(
+                            channel + 1,
+                            format.num_channels,
+                        )));
+                    }
+                    let mut row = vec![0.0; num_inputs];
+                    row[*channel] = 1.0;
+                    rows.push(row);
+                }
+                // The picked channels keep their positions, if a layout can describe them.
+                let speakers: Option<Vec<Speaker>> = channels
+                    .iter()
+                    .map(|channel| format.channel_layout.position(*channel))
+                    .collect();
+                let layout = speakers
+                    .and_then(|speakers| ChannelLayout::from_speakers(&speakers))
+                    .unwrap_or_else(|| ChannelLayout::default_for(rows.len() as u8));
+                ChannelMatrix { rows, layout }
+            }
+            ChannelMix::Matrix(rows) => {
+                if rows[0].len() != num_inputs {
+                    return Err(Box::new(MixError::MatrixColumns(
+                        rows[0].len(),
+                        format.num_channels,
+                    )));
+                }
+                ChannelMatrix {
+                    rows: rows.clone(),
+                    layout: ChannelLayout::default_for(rows.len() as u8),
+                }
+            }
+        };
+        Ok(matrix)
+    }
+}
+
+/// Return the rows of the standard stereo downmix of the channels in `format`. Channels without a
+/// position are treated as centre channels.
+fn stereo_downmix(format: &AudioFormatInfo) -> Vec<Vec<f64>> {
+    let num_inputs = format.num_channels as usize;
+    if num_inputs == 1 {
+        return vec![vec![1.0], vec![1.0]];
+    }
+    let gains: Vec<(f64, f64)> = (0..num_inputs)
+        .map(|channel| match format.channel_layout.position(channel) {
+            Some(Speaker::FrontLeft | Speaker::FrontLeftOfCenter) => (1.0, 0.0),
+            Some(Speaker::FrontRight | Speaker::FrontRightOfCenter) => (0.0, 1.0),
+            Some(Speaker::LowFrequency) => (0.0, 0.0),
+            Some(
+                Speaker::BackLeft
+                | Speaker::SideLeft
+                | Speaker::TopFrontLeft
+                | Speaker::TopBackLeft,
+            ) => (MINUS_3_DB, 0.0),
+            Some(
+                Speaker::BackRight
+                | Speaker::SideRight
+                | Speaker::TopFrontRight
+                | Speaker::TopBackRight,
+            ) => (0.0, MINUS_3_DB),
+            Some(
+                Speaker::FrontCenter
+                | Speaker::BackCenter
+                | Speaker::TopCenter
+                | Speaker::TopFrontCenter
+                | Speaker::TopBackCenter,
+            )
+            | None => (MINUS_3_DB, MINUS_3_DB),
+        })
+        .collect();
+    let mut rows = vec![
+        gains.iter().map(|gain| gain.0).collect::<Vec<f64>>(),
+        gains.iter().map(|gain| gain.1).collect(),
+    ];
+    // Full scale on every channel mustn't clip.
+    let max_gain = rows
+        .iter()
+        .map(|row| row.iter().sum::<f64>())
+        .fold(1.0, f64::max);
+    for coefficient in rows.iter_mut().flatten() {
+        *coefficient /= max_gain;
+    }
+    rows
+}
+
+/// The coefficients which mix a frame of captured channels into a frame of written channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMatrix {
+    /// The coefficient of each captured channel, for each written channel.
+    rows: Vec<Vec<f64>>,
+
+    /// Speaker positions of the written channels.
+    layout: ChannelLayout,
+}
+
+impl ChannelMatrix {
+    /// Return the number of channels written.
+    pub fn num_outputs(&self) -> u8 {
+        self.rows.len() as u8
+    }
+
+    /// Return the speaker positions of the written channels.
+    pub fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    /// Mix interleaved samples, with a channel for each column of the matrix, into interleaved
+    /// samples with a channel for each row.
+    pub fn apply(&self, input: &[f64]) -> Vec<f64> {
+        let num_inputs = self.rows[0].len();
+        let mut output = Vec::with_capacity(input.len() / num_inputs * self.rows.len());
+        for frame in input.chunks_exact(num_inputs) {
+            for row in &self.rows {
+                output.push(row.iter().zip(frame).map(|(c, sample)| c * sample).sum());
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SampleFormat;
+
+    fn format(num_channels: u8) -> AudioFormatInfo {
+        AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels,
+            format: SampleFormat::Float32,
+            channel_layout: ChannelLayout::default_for(num_channels),
+        }
+    }
+
+    #[test]
+    fn parses_presets_and_matrices() {
+        assert_eq!("mono".parse::<ChannelMix>().unwrap(), ChannelMix::Mono);
+        assert_eq!("stereo".parse::<ChannelMix>().unwrap(), ChannelMix::Stereo);
+        assert_eq!(
+            "pick:3-4,1".parse::<ChannelMix>().unwrap(),
+            ChannelMix::Pick(vec![2, 3, 0])
+        );
+        assert_eq!(
+            "matrix:1,0,0.5;0,1,0.5".parse::<ChannelMix>().unwrap(),
+            ChannelMix::Matrix(vec![vec![1.0, 0.0, 0.5], vec![0.0, 1.0, 0.5]])
+        );
+
+        for spec in [
+            "",
+            "quad",
+            "mono:1",
+            "pick:",
+            "pick:0",
+            "pick:4-3",
+            "matrix:1,0;1",
+        ] {
+            assert!(spec.parse::<ChannelMix>().is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn stereo_downmix_uses_standard_coefficients() {
+        let matrix = ChannelMix::Stereo.matrix(&format(6)).unwrap();
+        assert_eq!(matrix.layout(), ChannelLayout::STEREO);
+        // FL, FR, FC, LFE, BL, BR, scaled so a full scale signal on every channel doesn't clip.
+        let scale = 1.0 + 2.0 * MINUS_3_DB;
+        let left = [1.0, 0.0, MINUS_3_DB, 0.0, MINUS_3_DB, 0.0].map(|c| c / scale);
+        let right = [0.0, 1.0, MINUS_3_DB, 0.0, 0.0, MINUS_3_DB].map(|c| c / scale);
+        assert_eq!(matrix.rows, vec![left.to_vec(), right.to_vec()]);
+
+        let output = matrix.apply(&[1.0; 6]);
+        assert!((output[0] - 1.0).abs() < 1e-12);
+        assert!((output[1] - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn stereo_is_unchanged_by_stereo_downmix_and_mono_is_copied() {
+        let matrix = ChannelMix::Stereo.matrix(&format(2)).unwrap();
+        assert_eq!(matrix.apply(&[0.5, -0.25]), vec![0.5, -0.25]);
+
+        let matrix = ChannelMix::Stereo.matrix(&format(1)).unwrap();
+        assert_eq!(matrix.apply(&[0.5, -0.25]), vec![0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn mono_is_average_of_channels() {
+        let matrix = ChannelMix::Mono.matrix(&format(4)).unwrap();
+        assert_eq!(matrix.num_outputs(), 1);
+        assert_eq!(matrix.layout(), ChannelLayout::MONO);
+        assert_eq!(matrix.apply(&[1.0, 0.5, 0.0, 0.5]), vec![0.5]);
+    }
+
+    #[test]
+    fn pick_reorders_channels_and_keeps_positions() {
+        let matrix = ChannelMix::Pick(vec![4, 5]).matrix(&format(6)).unwrap();
+        assert_eq!(
+            matrix.apply(&[0.0, 0.1, 0.2, 0.3, 0.4, 0.5]),
+            vec![0.4, 0.5]
+        );
+        let speakers = [Speaker::BackLeft, Speaker::BackRight];
+        assert_eq!(
+            matrix.layout(),
+            ChannelLayout::from_speakers(&speakers).unwrap()
+        );
+
+        // Out of order, so the positions can't be kept.
+        let matrix = ChannelMix::Pick(vec![1, 0]).matrix(&format(2)).unwrap();
+        assert_eq!(matrix.apply(&[0.25, 0.75]), vec![0.75, 0.25]);
+        assert_eq!(matrix.layout(), ChannelLayout::STEREO);
+
+        assert!(ChannelMix::Pick(vec![2]).matrix(&format(2)).is_err());
+    }
+
+    #[test]
+    fn matrix_must_have_column_for_each_channel() {
+        let mix = ChannelMix::Matrix(vec![vec![0.5, 0.5, 1.0]]);
+        let matrix = mix.matrix(&format(3)).unwrap();
+        assert_eq!(matrix.apply(&[0.5, 0.5, -0.25]), vec![0.25]);
+        assert!(mix.matrix(&format(2)).is_err());
+    }
+}
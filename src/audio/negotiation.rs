@@ -0,0 +1,336 @@
+use std::{error::Error, fmt::Display, ops::RangeInclusive};
+
+use clap::ValueEnum;
+
+use crate::Res;
+
+use super::{AudioFormatInfo, RequestedAudioFormatInfo, SampleFormat};
+
+/// Sample rates probed on devices which only support some rates.
+pub const STANDARD_SAMPLE_RATES: [u32; 11] = [
+    8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+#[derive(Debug)]
+enum NegotiationError {
+    SampleRate(u32, Vec<u32>),
+    Channels(u8, RangeInclusive<u8>),
+    SampleFormat(SampleFormat, Vec<SampleFormat>),
+    NoSupportedFormat,
+}
+
+impl Error for NegotiationError {}
+
+impl Display for NegotiationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NegotiationError::SampleRate(rate, rates) => write!(
+                f,
+                "Sample rate {rate} Hz is not supported by the device. Supported rates: {}",
+                join(rates)
+            ),
+            NegotiationError::Channels(channels, range) => write!(
+                f,
+                "{channels} channels are not supported by the device. Supported: {} to {}",
+                range.start(),
+                range.end()
+            ),
+            NegotiationError::SampleFormat(format, formats) => write!(
+                f,
+                "Sample format {format} is not supported by the device. Supported formats: {}",
+                join(formats)
+            ),
+            NegotiationError::NoSupportedFormat => {
+                write!(f, "The device doesn't report any supported format")
+            }
+        }
+    }
+}
+
+/// How to choose the format to open the device with, when the requested format isn't supported.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FormatPolicy {
+    /// Fail unless the device supports the requested format.
+    Exact,
+    /// Use the closest format the device supports.
+    #[default]
+    Nearest,
+    /// Capture in the device's native sample format, and convert to the requested sample format in
+    /// software. Sample rates the device doesn't support are captured at the closest supported
+    /// rate, and resampled. Channel counts the device doesn't support use the closest supported
+    /// value.
+    Convert,
+}
+
+/// The formats a device can be opened with, as reported by
+/// [`AudioLoopback::supported_formats`](super::AudioLoopback::supported_formats). The sample rate,
+/// channel count and sample format are taken to be independent of each other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SupportedFormats {
+    /// The format the device uses when no format is requested, if known.
+    pub native: Option<AudioFormatInfo>,
+
+    /// Supported sample rates, or `None` when any rate is accepted, e.g. when the audio system
+    /// resamples.
+    pub sample_rates: Option<Vec<u32>>,
+
+    /// Supported numbers of channels.
+    pub channels: RangeInclusive<u8>,
+
+    /// Supported sample formats.
+    pub sample_formats: Vec<SampleFormat>,
+}
+
+impl SupportedFormats {
+    /// Formats for sources which accept any format.
+    pub fn any() -> SupportedFormats {
+        SupportedFormats {
+            native: None,
+            sample_rates: None,
+            channels: 1..=u8::MAX,
+            sample_formats: SampleFormat::value_variants().to_vec(),
+        }
+    }
+
+    /// Formats for sources which only have a single format, such as files.
+    pub fn only(format: AudioFormatInfo) -> SupportedFormats {
+        SupportedFormats {
+            native: Some(format),
+            sample_rates: Some(vec![format.sample_rate]),
+            channels: format.num_channels..=format.num_channels,
+            sample_formats: vec![format.format],
+        }
+    }
+}
+
+/// The result of negotiating a format with [`negotiate`].
+#[derive(Clone)]
+pub struct NegotiatedFormat {
+    /// Format to open the device with. Values which weren't requested are left to the backend.
+    pub device_format: RequestedAudioFormatInfo,
+
+    /// Why the format differs from the requested format, to report before recording.
+    pub changes: Vec<String>,
+}
+
+/// Choose the format to open the device with, from the requested format and the formats the device
+/// supports, following the [`FormatPolicy`].
+pub fn negotiate(
+    requested: &RequestedAudioFormatInfo,
+    supported: &SupportedFormats,
+    policy: FormatPolicy,
+) -> Res<NegotiatedFormat> {
+    let mut changes = Vec::new();
+
+    let sample_rate = match (requested.sample_rate, &supported.sample_rates) {
+        (Some(rate), Some(rates)) if !rates.contains(&rate) => {
+            if policy == FormatPolicy::Exact {
+                return Err(Box::new(NegotiationError::SampleRate(rate, rates.clone())));
+            }
+            let nearest =
+                nearest_sample_rate(rate, rates).ok_or(NegotiationError::NoSupportedFormat)?;
+            changes.push(match policy {
+                FormatPolicy::Convert => format!(
+                    "Sample rate {rate} Hz is not supported, capturing {nearest} Hz and resampling"
+                ),
+                _ => format!("Sample rate {rate} Hz is not supported, using {nearest} Hz"),
+            });
+            Some(nearest)
+        }
+        (rate, _) => rate,
+    };
+
+    let num_channels = match requested.num_channels {
+        Some(channels) if !supported.channels.contains(&channels) => {
+            if policy == FormatPolicy::Exact {
+                return Err(Box::new(NegotiationError::Channels(
+                    channels,
+                    supported.channels.clone(),
+                )));
+            }
+            let nearest = channels.clamp(*supported.channels.start(), *supported.channels.end());
+            changes.push(format!(
+                "{channels} channels are not supported, using {nearest}"
+            ));
+            Some(nearest)
+        }
+        channels => channels,
+    };
+
+    let format = match requested.format {
+        Some(format) if policy == FormatPolicy::Convert => {
+            let device_format = supported
+                .native
+                .map(|native| native.format)
+                .filter(|native| supported.sample_formats.contains(native))
+                .or_else(|| nearest_sample_format(format, &supported.sample_formats))
+                .ok_or(NegotiationError::NoSupportedFormat)?;
+            if device_format != format {
+                changes.push(format!(
+                    "Capturing {device_format} and converting to {format} in software"
+                ));
+            }
+            Some(device_format)
+        }
+        Some(format) if !supported.sample_formats.contains(&format) => {
+            if policy == FormatPolicy::Exact {
+                return Err(Box::new(NegotiationError::SampleFormat(
+                    format,
+                    supported.sample_formats.clone(),
+                )));
+            }
+            let nearest = nearest_sample_format(format, &supported.sample_formats)
+                .ok_or(NegotiationError::NoSupportedFormat)?;
+            changes.push(format!(
+                "Sample format {format} is not supported, using {nearest}"
+            ));
+            Some(nearest)
+        }
+        format => format,
+    };
+
+    Ok(NegotiatedFormat {
+        device_format: RequestedAudioFormatInfo {
+            sample_rate,
+            num_channels,
+            format,
+        },
+        changes,
+    })
+}
+
+/// Return the closest supported sample rate, preferring the higher rate when two are as close.
+fn nearest_sample_rate(rate: u32, rates: &[u32]) -> Option<u32> {
+    rates
+        .iter()
+        .copied()
+        .min_by_key(|supported| (supported.abs_diff(rate), *supported < rate))
+}
+
+/// Return the closest supported sample format: integer or float as requested if possible, then the
+/// closest bit depth, preferring more precision over less, then the same container size.
+fn nearest_sample_format(format: SampleFormat, formats: &[SampleFormat]) -> Option<SampleFormat> {
+    formats.iter().copied().min_by_key(|supported| {
+        let is_other_type = supported.type_format_header() != format.type_format_header();
+        let extra_bits = supported.bit_depth() as i32 - format.bit_depth() as i32;
+        let is_other_container = supported.container_bits() != format.container_bits();
+        (
+            is_other_type,
+            extra_bits < 0,
+            extra_bits.abs(),
+            is_other_container,
+        )
+    })
+}
+
+fn join<T: Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::layout::ChannelLayout;
+
+    fn requested(
+        sample_rate: Option<u32>,
+        num_channels: Option<u8>,
+        format: Option<SampleFormat>,
+    ) -> RequestedAudioFormatInfo {
+        RequestedAudioFormatInfo {
+            sample_rate,
+            num_channels,
+            format,
+        }
+    }
+
+    fn device() -> SupportedFormats {
+        SupportedFormats {
+            native: Some(AudioFormatInfo {
+                sample_rate: 48000,
+                num_channels: 2,
+                format: SampleFormat::Float32,
+                channel_layout: ChannelLayout::STEREO,
+            }),
+            sample_rates: Some(vec![44100, 48000, 96000]),
+            channels: 1..=2,
+            sample_formats: vec![SampleFormat::Int16, SampleFormat::Float32],
+        }
+    }
+
+    #[test]
+    fn supported_format_is_used_unchanged_by_every_policy() {
+        let request = requested(Some(44100), Some(1), Some(SampleFormat::Int16));
+        for policy in [FormatPolicy::Exact, FormatPolicy::Nearest] {
+            let negotiated = negotiate(&request, &device(), policy).unwrap();
+            assert_eq!(negotiated.device_format.sample_rate, Some(44100));
+            assert_eq!(negotiated.device_format.num_channels, Some(1));
+            assert_eq!(negotiated.device_format.format, Some(SampleFormat::Int16));
+            assert!(negotiated.changes.is_empty());
+        }
+    }
+
+    #[test]
+    fn exact_policy_rejects_unsupported_format() {
+        let devices = device();
+        let policy = FormatPolicy::Exact;
+        assert!(negotiate(&requested(Some(32000), None, None), &devices, policy).is_err());
+        assert!(negotiate(&requested(None, Some(6), None), &devices, policy).is_err());
+        let request = requested(None, None, Some(SampleFormat::Int24));
+        assert!(negotiate(&request, &devices, policy).is_err());
+    }
+
+    #[test]
+    fn nearest_policy_picks_closest_supported_values() {
+        let request = requested(Some(88200), Some(6), Some(SampleFormat::Int24));
+        let negotiated = negotiate(&request, &device(), FormatPolicy::Nearest).unwrap();
+        assert_eq!(negotiated.device_format.sample_rate, Some(96000));
+        assert_eq!(negotiated.device_format.num_channels, Some(2));
+        // Integer is preferred to float, even with fewer bits.
+        assert_eq!(negotiated.device_format.format, Some(SampleFormat::Int16));
+        assert_eq!(negotiated.changes.len(), 3);
+    }
+
+    #[test]
+    fn nearest_policy_prefers_same_container_for_same_bit_depth() {
+        let formats = [SampleFormat::Int24, SampleFormat::Int24In32];
+        let nearest = nearest_sample_format(SampleFormat::Int24In32, &formats);
+        assert_eq!(nearest, Some(SampleFormat::Int24In32));
+        let nearest = nearest_sample_format(SampleFormat::Int32, &formats);
+        assert_eq!(nearest, Some(SampleFormat::Int24In32));
+        let nearest = nearest_sample_format(SampleFormat::UInt8, &[SampleFormat::Int16]);
+        assert_eq!(nearest, Some(SampleFormat::Int16));
+    }
+
+    #[test]
+    fn convert_policy_captures_native_format_and_converts() {
+        let request = requested(None, None, Some(SampleFormat::Int16));
+        let negotiated = negotiate(&request, &device(), FormatPolicy::Convert).unwrap();
+        assert_eq!(negotiated.device_format.format, Some(SampleFormat::Float32));
+        assert_eq!(negotiated.changes.len(), 1);
+    }
+
+    #[test]
+    fn unrequested_values_are_left_to_backend() {
+        let request = requested(None, None, None);
+        let negotiated = negotiate(&request, &device(), FormatPolicy::Convert).unwrap();
+        assert!(negotiated.device_format.sample_rate.is_none());
+        assert!(negotiated.device_format.num_channels.is_none());
+        assert!(negotiated.device_format.format.is_none());
+        assert!(negotiated.changes.is_empty());
+    }
+
+    #[test]
+    fn any_format_is_supported_by_sources_without_restrictions() {
+        let request = requested(Some(12345), Some(7), Some(SampleFormat::Int24));
+        let negotiated =
+            negotiate(&request, &SupportedFormats::any(), FormatPolicy::Exact).unwrap();
+        assert_eq!(negotiated.device_format.sample_rate, Some(12345));
+        assert_eq!(negotiated.device_format.num_channels, Some(7));
+        assert_eq!(negotiated.device_format.format, Some(SampleFormat::Int24));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::Res;
+
+use super::convert::{convert_samples, read_sample, write_sample};
+use super::dither::{Dither, Ditherer};
+use super::mix::{ChannelMatrix, ChannelMix};
+use super::resample::{ResampleQuality, Resampler};
+use super::{AudioFormatInfo, SampleFormat};
+
+/// Seed for the dither noise. It's fixed, so converting the same audio always writes the same file.
+const DITHER_SEED: u64 = 0x5741_5652_4543;
+
+/// How to convert captured audio before it's written. Values left as `None` are written as
+/// captured.
+#[derive(Clone, Debug, Default)]
+pub struct Conversion {
+    /// Sample format to write.
+    pub sample_format: Option<SampleFormat>,
+
+    /// Sample rate to write, resampling the captured audio.
+    pub sample_rate: Option<u32>,
+
+    /// How to interpolate the audio when resampling.
+    pub resample_quality: ResampleQuality,
+
+    /// How to mix the captured channels into the channels written.
+    pub channel_mix: Option<ChannelMix>,
+
+    /// How to dither when the bit depth is reduced.
+    pub dither: Dither,
+}
+
+/// Converts captured audio to the format written to file, a chunk at a time. Only the stages the
+/// two formats need are run, so audio already in the output format is passed through unchanged.
+pub struct Pipeline {
+    input_format: AudioFormatInfo,
+    output_format: AudioFormatInfo,
+
+    /// Present when channels are mixed.
+    matrix: Option<ChannelMatrix>,
+
+    /// Present when the sample rates differ.
+    resampler: Option<Resampler>,
+
+    /// Present when dithering, and the bit depth is reduced.
+    ditherer: Option<Ditherer>,
+}
+
+impl Pipeline {
+    /// Create a pipeline which converts audio captured in `input_format`. Fails when the channel
+    /// mix can't be applied to the captured channels.
+    pub fn new(input_format: AudioFormatInfo, conversion: &Conversion) -> Res<Pipeline> {
+        let matrix = conversion
+            .channel_mix
+            .as_ref()
+            .map(|mix| mix.matrix(&input_format))
+            .transpose()?;
+        let output_format = AudioFormatInfo {
+            sample_rate: conversion.sample_rate.unwrap_or(input_format.sample_rate),
+            num_channels: matrix
+                .as_ref()
+                .map_or(input_format.num_channels, |m| m.num_outputs()),
+            format: conversion.sample_format.unwrap_or(input_format.format),
+            channel_layout: matrix
+                .as_ref()
+                .map_or(input_format.channel_layout, |m| m.layout()),
+        };
+        // Resampling after mixing, as downmixing leaves fewer channels to resample.
+        let resampler = (input_format.sample_rate != output_format.sample_rate).then(|| {
+            Resampler::new(
+                input_format.sample_rate,
+                output_format.sample_rate,
+                output_format.num_channels,
+                conversion.resample_quality,
+            )
+        });
+        let processed = matrix.is_some() || resampler.is_some();
+        let ditherer = conversion
+            .dither
+            .is_needed(input_format.format, output_format.format, processed)
+            .then(|| {
+                Ditherer::new(
+                    conversion.dither,
+                    output_format.format,
+                    output_format.num_channels,
+                    DITHER_SEED,
+                )
+            });
+        Ok(Pipeline {
+            input_format,
+            output_format,
+            matrix,
+            resampler,
+            ditherer,
+        })
+    }
+
+    /// Return the format of the audio the pipeline produces.
+    pub fn output_format(&self) -> AudioFormatInfo {
+        self.output_format
+    }
+
+    /// Convert the next chunk of interleaved audio. Stages which buffer audio, such as resampling,
+    /// may return less audio than they were given, with the rest following later chunks.
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        let (from, to) = (self.input_format.format, self.output_format.format);
+        if self.matrix.is_none() && self.resampler.is_none() && self.ditherer.is_none() {
+            return match from == to {
+                true => data.to_vec(),
+                false => convert_samples(data, from, to),
+            };
+        }
+
+        let mut samples: Vec<f64> = data
+            .chunks_exact(from.sample_size())
+            .map(|sample| read_sample(from, sample))
+            .collect();
+        if let Some(matrix) = &self.matrix {
+            samples = matrix.apply(&samples);
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            samples = resampler.process(&samples);
+        }
+        if let Some(ditherer) = self.ditherer.as_mut() {
+            ditherer.process(&mut samples);
+        }
+        encode(to, &samples)
+    }
+
+    /// Return the audio still buffered in the pipeline, at the end of the input.
+    pub fn flush(&mut self) -> Vec<u8> {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return Vec::new();
+        };
+        let mut samples = resampler.flush();
+        if let Some(ditherer) = self.ditherer.as_mut() {
+            ditherer.process(&mut samples);
+        }
+        encode(self.output_format.format, &samples)
+    }
+}
+
+/// Encode samples in the given sample format.
+fn encode(format: SampleFormat, samples: &[f64]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(samples.len() * format.sample_size());
+    for sample in samples {
+        write_sample(format, *sample, &mut output);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::layout::ChannelLayout;
+
+    fn format(sample_rate: u32, format: SampleFormat) -> AudioFormatInfo {
+        AudioFormatInfo {
+            sample_rate,
+            num_channels: 2,
+            format,
+            channel_layout: ChannelLayout::STEREO,
+        }
+    }
+
+    #[test]
+    fn matching_formats_are_passed_through() {
+        let format = format(48000, SampleFormat::Int16);
+        let mut pipeline = Pipeline::new(format, &Conversion::default()).unwrap();
+        assert_eq!(pipeline.output_format(), format);
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(pipeline.process(&data), data);
+        assert!(pipeline.flush().is_empty());
+    }
+
+    #[test]
+    fn resampled_audio_is_converted_to_output_format() {
+        let input = format(44100, SampleFormat::Float32);
+        let conversion = Conversion {
+            sample_format: Some(SampleFormat::Int16),
+            sample_rate: Some(48000),
+            resample_quality: ResampleQuality::Linear,
+            channel_mix: None,
+            dither: Dither::None,
+        };
+        let mut pipeline = Pipeline::new(input, &conversion).unwrap();
+        let output = pipeline.output_format();
+        assert_eq!(output, format(48000, SampleFormat::Int16));
+        let data: Vec<u8> = [0.5f32; 2 * 441]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let mut converted = pipeline.process(&data);
+        converted.extend(pipeline.flush());
+
+        assert_eq!(converted.len(), 480 * output.block_alignment() as usize);
+        // A constant signal, away from the silence after the end.
+        assert_eq!(converted[..4], [0x00, 0x40, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn channels_are_mixed_in_any_sample_format() {
+        let input = AudioFormatInfo {
+            num_channels: 4,
+            channel_layout: ChannelLayout::QUAD,
+            ..format(48000, SampleFormat::Int24)
+        };
+        let conversion = Conversion {
+            channel_mix: Some(ChannelMix::Pick(vec![3, 2])),
+            ..Conversion::default()
+        };
+        let mut pipeline = Pipeline::new(input, &conversion).unwrap();
+        assert_eq!(pipeline.output_format().num_channels, 2);
+        assert_eq!(pipeline.output_format().format, SampleFormat::Int24);
+        let data = [1, 0, 0, 2, 0, 0, 3, 0, 0, 4, 0, 0];
+        assert_eq!(pipeline.process(&data), vec![4, 0, 0, 3, 0, 0]);
+
+        let conversion = Conversion {
+            channel_mix: Some(ChannelMix::Pick(vec![4])),
+            ..Conversion::default()
+        };
+        assert!(Pipeline::new(input, &conversion).is_err());
+    }
+
+    #[test]
+    fn reduced_bit_depth_is_dithered() {
+        let input = format(48000, SampleFormat::Float32);
+        let conversion = Conversion {
+            sample_format: Some(SampleFormat::Int16),
+            dither: Dither::Tpdf,
+            ..Conversion::default()
+        };
+        // Half a step of 16 bit audio, which rounding alone writes as silence.
+        let data: Vec<u8> = [0.5f32 / 32768.0; 2 * 480]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let mut pipeline = Pipeline::new(input, &conversion).unwrap();
+        let dithered = pipeline.process(&data);
+        assert_eq!(dithered.len(), 2 * 2 * 480);
+        assert!(dithered.iter().any(|byte| *byte != 0));
+        // The same noise every time.
+        let mut pipeline = Pipeline::new(input, &conversion).unwrap();
+        assert_eq!(pipeline.process(&data), dithered);
+
+        // Nothing is lost when the bit depth isn't reduced.
+        let input = format(48000, SampleFormat::Int16);
+        let mut pipeline = Pipeline::new(input, &conversion).unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(pipeline.process(&data), data);
+    }
+}
@@ -0,0 +1,377 @@
+use std::f64::consts::PI;
+
+use clap::ValueEnum;
+
+/// Zero crossings of the sinc function on each side of the filter kernel. More give a sharper
+/// cutoff, at the cost of more work per frame.
+const ZERO_CROSSINGS: f64 = 32.0;
+
+/// Cutoff of the anti-aliasing filter, as a fraction of the lower of the two Nyquist frequencies.
+/// Leaves room for the transition band below Nyquist, so it doesn't alias.
+const ROLLOFF: f64 = 0.9;
+
+/// Number of points the [`ResampleQuality::Polyphase`] filter table holds for each input frame.
+const PHASES: usize = 512;
+
+/// How to interpolate between input frames when resampling.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Linear interpolation between neighbouring frames. Cheap, but rolls off high frequencies,
+    /// and aliases when lowering the rate.
+    Linear,
+    /// Windowed-sinc filter, evaluated exactly for every output frame. The most accurate, and the
+    /// slowest.
+    Sinc,
+    /// The windowed-sinc filter, interpolated from a precomputed table of filter phases. Nearly as
+    /// accurate as `sinc`, and much faster.
+    #[default]
+    Polyphase,
+}
+
+/// Filter used to weigh the input frames around each output frame. Positions are in input frames.
+enum Kernel {
+    Linear,
+    Sinc {
+        cutoff: f64,
+        half_width: f64,
+    },
+    Polyphase {
+        half_width: f64,
+        /// The kernel at each `1 / PHASES` of an input frame from its centre, up to `half_width`.
+        table: Vec<f64>,
+    },
+}
+
+impl Kernel {
+    fn new(quality: ResampleQuality, cutoff: f64) -> Kernel {
+        let half_width = ZERO_CROSSINGS / cutoff;
+        match quality {
+            ResampleQuality::Linear => Kernel::Linear,
+            ResampleQuality::Sinc => Kernel::Sinc { cutoff, half_width },
+            ResampleQuality::Polyphase => {
+                let len = (half_width * PHASES as f64).ceil() as usize + 2;
+                let table = (0..len)
+                    .map(|i| windowed_sinc(i as f64 / PHASES as f64, cutoff, half_width))
+                    .collect();
+                Kernel::Polyphase { half_width, table }
+            }
+        }
+    }
+
+    /// Number of input frames on each side of an output frame which contribute to it.
+    fn radius(&self) -> i64 {
+        match self {
+            Kernel::Linear => 1,
+            Kernel::Sinc { half_width, .. } | Kernel::Polyphase { half_width, .. } => {
+                half_width.ceil() as i64
+            }
+        }
+    }
+
+    /// Weight of the input frame `distance` frames from the output frame.
+    fn weight(&self, distance: f64) -> f64 {
+        match self {
+            Kernel::Linear => (1.0 - distance.abs()).max(0.0),
+            Kernel::Sinc { cutoff, half_width } => windowed_sinc(distance, *cutoff, *half_width),
+            Kernel::Polyphase { half_width, table } => {
+                let distance = distance.abs();
+                if distance >= *half_width {
+                    return 0.0;
+                }
+                let position = distance * PHASES as f64;
+                let index = position as usize;
+                let fraction = position - index as f64;
+                table[index] + (table[index + 1] - table[index]) * fraction
+            }
+        }
+    }
+}
+
+/// Low-pass filter kernel with the given cutoff, as a fraction of the input Nyquist frequency,
+/// under a Blackman window `half_width` frames wide on each side.
+fn windowed_sinc(distance: f64, cutoff: f64, half_width: f64) -> f64 {
+    if distance.abs() >= half_width {
+        return 0.0;
+    }
+    let x = PI * cutoff * distance;
+    let sinc = match x == 0.0 {
+        true => 1.0,
+        false => x.sin() / x,
+    };
+    let window = 0.42
+        + 0.5 * (PI * distance / half_width).cos()
+        + 0.08 * (2.0 * PI * distance / half_width).cos();
+    cutoff * sinc * window
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+/// Converts interleaved audio from one sample rate to another, a chunk at a time. Samples are
+/// values in the range `[-1, 1]`, as read by [`read_sample`](super::convert::read_sample).
+///
+/// Output frame `n` is interpolated at the time of input frame `n * input_rate / output_rate`, so
+/// the output lines up with the input, and has no delay. Frames before the start of the input are
+/// taken to be silent, as are the frames after its end, once the input is [flushed](Self::flush).
+pub struct Resampler {
+    num_channels: usize,
+
+    /// Input frames per output frame, as the fraction `input_step / output_step` in lowest terms.
+    input_step: u64,
+    output_step: u64,
+
+    kernel: Kernel,
+
+    /// Input frames which may still contribute to an output frame, interleaved.
+    buffer: Vec<f64>,
+
+    /// Position in the input of the first frame in the buffer.
+    buffer_start: i64,
+
+    /// Number of input frames received.
+    frames_in: u64,
+
+    /// Number of output frames produced.
+    frames_out: u64,
+
+    /// Weights of the input frames contributing to the current output frame, reused between
+    /// frames.
+    weights: Vec<f64>,
+}
+
+impl Resampler {
+    /// Create a resampler for audio with `num_channels` channels.
+    pub fn new(
+        input_rate: u32,
+        output_rate: u32,
+        num_channels: u8,
+        quality: ResampleQuality,
+    ) -> Resampler {
+        let divisor = gcd(input_rate as u64, output_rate as u64).max(1);
+        // When lowering the rate, frequencies above the output Nyquist frequency must be removed.
+        let cutoff = ROLLOFF * (output_rate as f64 / input_rate as f64).min(1.0);
+        Resampler {
+            num_channels: num_channels as usize,
+            input_step: input_rate as u64 / divisor,
+            output_step: output_rate as u64 / divisor,
+            kernel: Kernel::new(quality, cutoff),
+            buffer: Vec::new(),
+            buffer_start: 0,
+            frames_in: 0,
+            frames_out: 0,
+            weights: Vec::new(),
+        }
+    }
+
+    /// Resample the next chunk of interleaved input, and return the output frames which could be
+    /// completed. The rest follow with later chunks, or when the input is flushed.
+    pub fn process(&mut self, input: &[f64]) -> Vec<f64> {
+        let num_frames = input.len() / self.num_channels;
+        self.buffer
+            .extend_from_slice(&input[..num_frames * self.num_channels]);
+        self.frames_in += num_frames as u64;
+
+        let buffer_end = self.buffer_start + (self.buffer.len() / self.num_channels) as i64;
+        let mut output = Vec::new();
+        loop {
+            let (index, _) = self.position();
+            if index + self.kernel.radius() >= buffer_end {
+                break;
+            }
+            self.write_frame(&mut output);
+        }
+
+        // Drop the frames before the first one the next output frame needs.
+        let (index, _) = self.position();
+        let first_needed = (index - self.kernel.radius() + 1).max(self.buffer_start);
+        let num_dropped = (first_needed - self.buffer_start).min(buffer_end - self.buffer_start);
+        self.buffer
+            .drain(..num_dropped as usize * self.num_channels);
+        self.buffer_start += num_dropped;
+        output
+    }
+
+    /// Return the remaining output frames, up to the end of the input, and reset the resampler.
+    pub fn flush(&mut self) -> Vec<f64> {
+        let total_frames = (self.frames_in * self.output_step).div_ceil(self.input_step);
+        let mut output = Vec::new();
+        while self.frames_out < total_frames {
+            self.write_frame(&mut output);
+        }
+        self.buffer.clear();
+        self.buffer_start = 0;
+        self.frames_in = 0;
+        self.frames_out = 0;
+        output
+    }
+
+    /// Return the input frame at or before the next output frame, and how far past it the output
+    /// frame is, as a fraction of a frame.
+    fn position(&self) -> (i64, f64) {
+        let time = self.frames_out * self.input_step;
+        let index = time / self.output_step;
+        let fraction = (time % self.output_step) as f64 / self.output_step as f64;
+        (index as i64, fraction)
+    }
+
+    /// Interpolate the next output frame, and append it to `output`.
+    fn write_frame(&mut self, output: &mut Vec<f64>) {
+        let (index, fraction) = self.position();
+        let radius = self.kernel.radius();
+        let first = index - radius + 1;
+
+        self.weights.clear();
+        for frame in first..=index + radius {
+            let distance = (index - frame) as f64 + fraction;
+            self.weights.push(self.kernel.weight(distance));
+        }
+        // Normalising keeps the gain at exactly 1 for constant signals.
+        let total: f64 = self.weights.iter().sum();
+        if total != 0.0 {
+            self.weights.iter_mut().for_each(|weight| *weight /= total);
+        }
+
+        let num_buffered = (self.buffer.len() / self.num_channels) as i64;
+        for channel in 0..self.num_channels {
+            let mut sample = 0.0;
+            for (frame, weight) in (first..).zip(&self.weights) {
+                let offset = frame - self.buffer_start;
+                if *weight != 0.0 && (0..num_buffered).contains(&offset) {
+                    sample += weight * self.buffer[offset as usize * self.num_channels + channel];
+                }
+            }
+            output.push(sample);
+        }
+        self.frames_out += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResampleQuality; 3] = [
+        ResampleQuality::Linear,
+        ResampleQuality::Sinc,
+        ResampleQuality::Polyphase,
+    ];
+
+    /// A linear sweep from `start` to `end` Hz over `duration` seconds, sampled at `sample_rate`.
+    fn sweep(sample_rate: u32, start: f64, end: f64, duration: f64) -> Vec<f64> {
+        let num_frames = (duration * sample_rate as f64) as usize;
+        (0..num_frames)
+            .map(|frame| {
+                let time = frame as f64 / sample_rate as f64;
+                let phase = start * time + (end - start) * time * time / (2.0 * duration);
+                0.5 * (2.0 * PI * phase).sin()
+            })
+            .collect()
+    }
+
+    fn resample(input: &[f64], from: u32, to: u32, quality: ResampleQuality) -> Vec<f64> {
+        let mut resampler = Resampler::new(from, to, 1, quality);
+        let mut output = resampler.process(input);
+        output.extend(resampler.flush());
+        output
+    }
+
+    /// Largest difference between the output and the reference, away from the ends, where the
+    /// output fades in and out from silence.
+    fn max_error(output: &[f64], reference: &[f64], margin: usize) -> f64 {
+        output[margin..reference.len() - margin]
+            .iter()
+            .zip(&reference[margin..])
+            .map(|(output, reference)| (output - reference).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn output_length_matches_rate_ratio() {
+        for quality in QUALITIES {
+            let input = vec![0.0; 48000];
+            assert_eq!(resample(&input, 48000, 44100, quality).len(), 44100);
+            assert_eq!(resample(&input, 48000, 96000, quality).len(), 96000);
+            assert_eq!(resample(&input[..1001], 48000, 44100, quality).len(), 920);
+        }
+    }
+
+    #[test]
+    fn sinc_quality_matches_reference_sweeps() {
+        let cases = [
+            (48000, 44100),
+            (44100, 48000),
+            (96000, 48000),
+            (22050, 48000),
+        ];
+        for quality in [ResampleQuality::Sinc, ResampleQuality::Polyphase] {
+            for (from, to) in cases {
+                // Up to 80% of the lower Nyquist frequency, inside the passband.
+                let end = 0.4 * from.min(to) as f64;
+                let input = sweep(from, 20.0, end, 1.0);
+                let output = resample(&input, from, to, quality);
+                let reference = sweep(to, 20.0, end, 1.0);
+                let error = max_error(&output, &reference, to as usize / 100);
+                // -60 dB relative to the sweep.
+                assert!(error < 0.0005, "{quality:?} {from} to {to}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn linear_quality_matches_low_frequency_sweep() {
+        let input = sweep(48000, 20.0, 1000.0, 1.0);
+        let output = resample(&input, 48000, 44100, ResampleQuality::Linear);
+        let reference = sweep(44100, 20.0, 1000.0, 1.0);
+        assert!(max_error(&output, &reference, 441) < 0.005);
+    }
+
+    #[test]
+    fn downsampling_removes_frequencies_above_output_nyquist() {
+        // 23 kHz, above the 22.05 kHz Nyquist frequency of the output.
+        let input = sweep(48000, 23000.0, 23000.0, 1.0);
+        for quality in [ResampleQuality::Sinc, ResampleQuality::Polyphase] {
+            let output = resample(&input, 48000, 44100, quality);
+            let peak = output[441..output.len() - 441]
+                .iter()
+                .fold(0.0, |peak: f64, sample| peak.max(sample.abs()));
+            assert!(peak < 0.001, "{quality:?}: {peak}");
+        }
+    }
+
+    #[test]
+    fn chunked_input_gives_same_output() {
+        let input = sweep(48000, 100.0, 10000.0, 0.1);
+        for quality in QUALITIES {
+            let whole = resample(&input, 48000, 44100, quality);
+            let mut resampler = Resampler::new(48000, 44100, 1, quality);
+            let mut chunked = Vec::new();
+            for chunk in input.chunks(333) {
+                chunked.extend(resampler.process(chunk));
+            }
+            chunked.extend(resampler.flush());
+            assert_eq!(chunked.len(), whole.len());
+            let error = max_error(&chunked, &whole, 0);
+            assert!(error < 1e-12, "{quality:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn channels_are_resampled_independently() {
+        let left = sweep(44100, 440.0, 440.0, 0.1);
+        let input: Vec<f64> = left.iter().flat_map(|sample| [*sample, 0.25]).collect();
+        let mut resampler = Resampler::new(44100, 48000, 2, ResampleQuality::Polyphase);
+        let mut output = resampler.process(&input);
+        output.extend(resampler.flush());
+
+        let mono = resample(&left, 44100, 48000, ResampleQuality::Polyphase);
+        let output_left: Vec<f64> = output.iter().step_by(2).copied().collect();
+        assert!(max_error(&output_left, &mono, 0) < 1e-12);
+        // A constant signal keeps its level, away from the edges.
+        let right: Vec<f64> = output.iter().skip(1).step_by(2).copied().collect();
+        assert!(max_error(&right, &vec![0.25; right.len()], 100) < 1e-9);
+    }
+}
@@ -1,6 +1,307 @@
+use std::{error::Error, fmt::Display, sync::Arc};
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+use alsa::AlsaLoopbackRecorder;
+#[cfg(feature = "file")]
+use file::FileLoopbackRecorder;
+#[cfg(feature = "generator")]
+use generator::GeneratorLoopbackRecorder;
+#[cfg(all(target_os = "linux", feature = "jack"))]
+use jack::JackLoopbackRecorder;
+use log::{debug, info};
+#[cfg(all(target_os = "linux", feature = "pulse"))]
+use pulse::PulseLoopbackRecorder;
+#[cfg(feature = "stdin")]
+use stdin::StdinLoopbackRecorder;
+#[cfg(all(target_os = "windows", feature = "wasapi"))]
 use winapi::WasapiLoopbackRecorder;
 
+use crate::Res;
+
+use super::negotiation::{self, FormatPolicy, NegotiatedFormat, SupportedFormats};
+use super::{AudioLoopback, CaptureOptions, DeviceInfo, Direction, RequestedAudioFormatInfo};
+
+#[cfg(all(target_os = "linux", feature = "alsa"))]
+mod alsa;
+#[cfg(feature = "file")]
+mod file;
+#[cfg(feature = "generator")]
+mod generator;
+#[cfg(all(target_os = "linux", feature = "jack"))]
+mod jack;
+#[cfg(all(target_os = "linux", feature = "pulse"))]
+mod pulse;
+#[cfg(feature = "stdin")]
+mod stdin;
+#[cfg(all(target_os = "windows", feature = "wasapi"))]
 mod winapi;
 
-/// Captures the device audio output.
-pub type LoopbackRecorder = WasapiLoopbackRecorder;
+#[derive(Debug)]
+enum SysError {
+    NoBackendAvailable,
+    UnknownBackend(String),
+    AmbiguousDevice(String, Vec<String>),
+}
+
+impl Error for SysError {}
+
+impl Display for SysError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SysError::NoBackendAvailable => write!(f, "No audio backend available"),
+            SysError::UnknownBackend(name) => write!(f, "Unknown audio backend: {name}"),
+            SysError::AmbiguousDevice(query, names) => write!(
+                f,
+                "Device '{query}' matches more than one device: {}",
+                names.join(", ")
+            ),
+        }
+    }
+}
+
+/// An [`AudioLoopback`] implementation which has been compiled in.
+pub struct Backend {
+    /// Name used to select the backend with the `--backend` option.
+    pub name: &'static str,
+
+    /// Whether the backend can be picked automatically when no backend was requested. Backends
+    /// which don't record from a device are only used when requested.
+    pub is_default: bool,
+
+    is_available: fn() -> bool,
+
+    devices: fn() -> Res<Vec<DeviceInfo>>,
+
+    supported_formats: fn(&CaptureOptions) -> Res<SupportedFormats>,
+
+    create: fn(RequestedAudioFormatInfo, CaptureOptions) -> Res<Arc<dyn AudioLoopback>>,
+}
+
+impl Backend {
+    /// Register the [`AudioLoopback`] implementation `T` under the given name.
+    pub const fn of<T: AudioLoopback + 'static>(name: &'static str) -> Backend {
+        Backend {
+            name,
+            is_default: true,
+            is_available: T::is_available,
+            devices: T::devices,
+            supported_formats: T::supported_formats,
+            create: create_recorder::<T>,
+        }
+    }
+
+    /// Only use the backend when it's requested by name.
+    pub const fn only_when_requested(self) -> Backend {
+        Backend {
+            is_default: false,
+            ..self
+        }
+    }
+
+    /// Check whether the backend can be used on this machine.
+    pub fn is_available(&self) -> bool {
+        (self.is_available)()
+    }
+
+    /// List the devices this backend can record from.
+    pub fn devices(&self) -> Res<Vec<DeviceInfo>> {
+        (self.devices)()
+    }
+
+    /// Query the formats the device selected by `options` can be opened with.
+    pub fn supported_formats(&self, options: &CaptureOptions) -> Res<SupportedFormats> {
+        (self.supported_formats)(options)
+    }
+
+    /// Create a recorder using this backend.
+    pub fn create(
+        &self,
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<Arc<dyn AudioLoopback>> {
+        (self.create)(format, options)
+    }
+}
+
+fn create_recorder<T: AudioLoopback + 'static>(
+    format: RequestedAudioFormatInfo,
+    options: CaptureOptions,
+) -> Res<Arc<dyn AudioLoopback>> {
+    Ok(Arc::new(T::create(format, options)?))
+}
+
+/// Every backend compiled into this build, in order of preference.
+///
+/// On Linux, the sound servers are preferred, with ALSA used as a fallback when neither
+/// PulseAudio nor JACK is running.
+pub const BACKENDS: &[Backend] = &[
+    #[cfg(all(target_os = "windows", feature = "wasapi"))]
+    Backend::of::<WasapiLoopbackRecorder>("wasapi"),
+    #[cfg(all(target_os = "linux", feature = "pulse"))]
+    Backend::of::<PulseLoopbackRecorder>("pulse"),
+    #[cfg(all(target_os = "linux", feature = "jack"))]
+    Backend::of::<JackLoopbackRecorder>("jack"),
+    #[cfg(all(target_os = "linux", feature = "alsa"))]
+    Backend::of::<AlsaLoopbackRecorder>("alsa"),
+    #[cfg(feature = "file")]
+    Backend::of::<FileLoopbackRecorder>("file").only_when_requested(),
+    #[cfg(feature = "generator")]
+    Backend::of::<GeneratorLoopbackRecorder>("generator").only_when_requested(),
+    #[cfg(feature = "stdin")]
+    Backend::of::<StdinLoopbackRecorder>("stdin").only_when_requested(),
+];
+
+/// Names of the compiled-in backends, in order of preference.
+pub fn backend_names() -> Vec<&'static str> {
+    BACKENDS.iter().map(|backend| backend.name).collect()
+}
+
+/// Find a compiled-in backend by name.
+pub fn find_backend(name: &str) -> Option<&'static Backend> {
+    BACKENDS.iter().find(|backend| backend.name == name)
+}
+
+/// Pick the backend to use when none was requested: the first available default backend.
+///
+/// JACK is preferred when ports to record from were requested, since no other backend uses them.
+fn default_backend(options: &CaptureOptions) -> Option<&'static Backend> {
+    if !options.ports.is_empty() {
+        if let Some(jack) = find_backend("jack").filter(|jack| jack.is_available()) {
+            return Some(jack);
+        }
+    }
+    BACKENDS
+        .iter()
+        .find(|backend| backend.is_default && backend.is_available())
+}
+
+/// Find the device matching the `--device` query: the device with that id, otherwise the only
+/// device in the requested direction whose name contains the query (ignoring case).
+///
+/// Returns `None` when nothing matches, so names which the backend doesn't list (such as ALSA
+/// plugin PCMs) can still be passed through to it.
+pub fn select_device<'a>(
+    devices: &'a [DeviceInfo],
+    query: &str,
+    direction: Direction,
+) -> Res<Option<&'a DeviceInfo>> {
+    if let Some(device) = devices.iter().find(|device| device.id == query) {
+        return Ok(Some(device));
+    }
+
+    let query_lower = query.to_lowercase();
+    let matches: Vec<&DeviceInfo> = devices
+        .iter()
+        .filter(|device| device.direction == direction)
+        .filter(|device| device.name.to_lowercase().contains(&query_lower))
+        .collect();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [device] => Ok(Some(device)),
+        _ => Err(Box::new(SysError::AmbiguousDevice(
+            query.to_owned(),
+            matches.iter().map(|device| device.name.clone()).collect(),
+        ))),
+    }
+}
+
+/// Select the named backend. When no backend is named, the first available device backend for the
+/// platform is used.
+pub fn select_backend(name: Option<&str>, options: &CaptureOptions) -> Res<&'static Backend> {
+    let backend = match name {
+        Some(name) => {
+            find_backend(name).ok_or_else(|| SysError::UnknownBackend(name.to_owned()))?
+        }
+        None => default_backend(options).ok_or(SysError::NoBackendAvailable)?,
+    };
+    info!("Using {} audio backend", backend.name);
+    Ok(backend)
+}
+
+/// Create the recorder which captures the device audio output, using the given backend.
+///
+/// A requested device is resolved to the id of a listed device, when it matches one. The requested
+/// format is then negotiated with the formats the device supports, following the `policy`.
+pub fn create_loopback_recorder(
+    backend: &Backend,
+    format: RequestedAudioFormatInfo,
+    policy: FormatPolicy,
+    mut options: CaptureOptions,
+) -> Res<(Arc<dyn AudioLoopback>, NegotiatedFormat)> {
+    if let Some(query) = &options.device {
+        match backend.devices() {
+            Ok(devices) => {
+                if let Some(device) = select_device(&devices, query, options.direction)? {
+                    info!("Using device: {} ({})", device.name, device.id);
+                    options.device = Some(device.id.clone());
+                }
+            }
+            Err(err) => debug!("Failed to list devices: {err}"),
+        }
+    }
+
+    let supported = match backend.supported_formats(&options) {
+        Ok(supported) => supported,
+        Err(err) => {
+            debug!("Failed to query supported formats: {err}");
+            SupportedFormats::any()
+        }
+    };
+    debug!("Supported formats: {supported:?}");
+    let negotiated = negotiation::negotiate(&format, &supported, policy)?;
+    let recorder = backend.create(negotiated.device_format.clone(), options)?;
+    Ok((recorder, negotiated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_names_are_unique() {
+        let mut names = backend_names();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), BACKENDS.len());
+    }
+
+    #[test]
+    fn find_backend_returns_backend_with_matching_name() {
+        for name in backend_names() {
+            assert_eq!(find_backend(name).unwrap().name, name);
+        }
+        assert!(find_backend("unknown").is_none());
+    }
+
+    fn device(id: &str, name: &str, direction: Direction) -> DeviceInfo {
+        DeviceInfo {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            direction,
+            default_format: None,
+        }
+    }
+
+    #[test]
+    fn select_device_matches_id_or_name_substring() {
+        let devices = [
+            device("{0.0.0}.{1}", "Speakers (Realtek Audio)", Direction::Render),
+            device("{0.0.0}.{2}", "Speakers (USB DAC)", Direction::Render),
+            device("{0.0.1}.{3}", "Microphone (USB DAC)", Direction::Capture),
+        ];
+
+        let by_id = select_device(&devices, "{0.0.0}.{2}", Direction::Capture).unwrap();
+        assert_eq!(by_id.unwrap().id, "{0.0.0}.{2}");
+
+        let by_name = select_device(&devices, "usb dac", Direction::Render).unwrap();
+        assert_eq!(by_name.unwrap().id, "{0.0.0}.{2}");
+
+        let by_input_name = select_device(&devices, "usb dac", Direction::Capture).unwrap();
+        assert_eq!(by_input_name.unwrap().id, "{0.0.1}.{3}");
+
+        assert!(select_device(&devices, "hw:Loopback,1", Direction::Render)
+            .unwrap()
+            .is_none());
+        assert!(select_device(&devices, "Speakers", Direction::Render).is_err());
+    }
+}
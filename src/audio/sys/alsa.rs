@@ -0,0 +1,628 @@
+use std::ffi::{c_int, c_uint, c_ulong, c_void, CStr, CString};
+use std::ptr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use std::{error::Error, fmt::Display};
+
+use clap::ValueEnum;
+use log::{debug, error, warn};
+
+use crate::{Nothing, Res};
+
+use crate::audio::layout::{ChannelLayout, Speaker};
+use crate::audio::negotiation::{SupportedFormats, STANDARD_SAMPLE_RATES};
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, BufferConfig, CaptureOptions,
+    DeviceEvent, DeviceInfo, Direction, RequestedAudioFormatInfo, SampleFormat,
+};
+
+use ffi::Alsa;
+
+mod ffi;
+
+/// PCM opened when no device was requested: the capture side of the `snd-aloop` loopback card,
+/// which receives everything played to `hw:Loopback,0`.
+const DEFAULT_LOOPBACK_DEVICE: &str = "hw:Loopback,1";
+
+/// PCM opened when no device was requested, when recording an input device.
+const DEFAULT_CAPTURE_DEVICE: &str = "default";
+
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_NUM_CHANNELS: u8 = 2;
+
+/// Sample formats to try, in order of preference, when no format was requested. ALSA has no notion
+/// of a mix format, so the most precise format the device accepts is used.
+const PREFERRED_FORMATS: [SampleFormat; 4] = [
+    SampleFormat::Float32,
+    SampleFormat::Int32,
+    SampleFormat::Int24,
+    SampleFormat::Int16,
+];
+
+/// The `SND_CHMAP_*` position of each speaker position.
+const CHANNEL_POSITIONS: [(Speaker, c_uint); 18] = [
+    (Speaker::FrontLeft, 3),
+    (Speaker::FrontRight, 4),
+    (Speaker::BackLeft, 5),
+    (Speaker::BackRight, 6),
+    (Speaker::FrontCenter, 7),
+    (Speaker::LowFrequency, 8),
+    (Speaker::SideLeft, 9),
+    (Speaker::SideRight, 10),
+    (Speaker::BackCenter, 11),
+    (Speaker::FrontLeftOfCenter, 12),
+    (Speaker::FrontRightOfCenter, 13),
+    (Speaker::TopCenter, 21),
+    (Speaker::TopFrontLeft, 22),
+    (Speaker::TopFrontRight, 23),
+    (Speaker::TopFrontCenter, 24),
+    (Speaker::TopBackLeft, 25),
+    (Speaker::TopBackRight, 26),
+    (Speaker::TopBackCenter, 27),
+];
+
+#[derive(Debug)]
+enum AlsaError {
+    DeviceUnavailable(String),
+    UnsupportedFormat,
+    ConfigurationFailed(String),
+    AudioCaptureFailed(String),
+}
+
+impl Error for AlsaError {}
+
+impl Display for AlsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlsaError::DeviceUnavailable(reason) => {
+                write!(f, "Failed to open ALSA PCM device: {reason}")
+            }
+            AlsaError::UnsupportedFormat => write!(f, "No supported sample format on ALSA device"),
+            AlsaError::ConfigurationFailed(reason) => {
+                write!(f, "Failed to configure ALSA PCM device: {reason}")
+            }
+            AlsaError::AudioCaptureFailed(reason) => write!(f, "Audio capture failed: {reason}"),
+        }
+    }
+}
+
+/// Recorder for a named ALSA PCM, for systems without a sound server. Any capture PCM can be used,
+/// including plugin devices such as `null`. ALSA has no loopback of its own, so audio output is
+/// recorded from the capture side of the `snd-aloop` loopback card by default, and input devices
+/// from the `default` PCM.
+pub struct AlsaLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+
+    /// How long to wait for audio before warning that none arrived, in milliseconds.
+    timeout: c_int,
+
+    lib: Alsa,
+
+    /// Handle to the opened `snd_pcm_t`.
+    pcm: *mut c_void,
+}
+
+unsafe impl Send for AlsaLoopbackRecorder {}
+unsafe impl Sync for AlsaLoopbackRecorder {}
+
+impl AudioLoopback for AlsaLoopbackRecorder {
+    /// Create a new ALSA-based [`AudioLoopback`] recorder.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<AlsaLoopbackRecorder> {
+        let lib = Alsa::load()?;
+        let pcm = open_pcm(&lib, &options)?;
+        let audio_format = match configure(&lib, pcm, &format, &options.buffer) {
+            Ok(audio_format) => audio_format,
+            Err(err) => {
+                unsafe { (lib.pcm_close)(pcm) };
+                return Err(err);
+            }
+        };
+
+        Ok(AlsaLoopbackRecorder {
+            audio_format,
+            chunk_size: options.buffer.chunk_size,
+            timeout: options.buffer.timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            lib,
+            pcm,
+        })
+    }
+
+    /// Check whether the ALSA library is installed.
+    fn is_available() -> bool {
+        Alsa::load().is_ok()
+    }
+
+    /// Query the formats, channel counts and standard sample rates the PCM accepts. Plugin PCMs
+    /// such as `plug` convert, so accept everything.
+    fn supported_formats(options: &CaptureOptions) -> Res<SupportedFormats> {
+        let lib = Alsa::load()?;
+        let pcm = open_pcm(&lib, options)?;
+        let mut params = ptr::null_mut();
+        let result = check(&lib, unsafe { (lib.hw_params_malloc)(&mut params) })
+            .and_then(|_| query_hw_params(&lib, pcm, params));
+        unsafe {
+            if !params.is_null() {
+                (lib.hw_params_free)(params);
+            }
+            (lib.pcm_close)(pcm);
+        }
+        result
+    }
+
+    /// List the PCMs which can capture, from the ALSA configuration hints. PCMs on the `snd-aloop`
+    /// loopback card record the audio played to it, every other PCM is an input. The id is the PCM
+    /// name. The default format is only known once a PCM is opened, so isn't listed.
+    fn devices() -> Res<Vec<DeviceInfo>> {
+        let lib = Alsa::load()?;
+        let mut hints = ptr::null_mut();
+        check(&lib, unsafe {
+            (lib.device_name_hint)(-1, c"pcm".as_ptr(), &mut hints)
+        })?;
+
+        let mut devices = Vec::new();
+        let mut hint = hints;
+        while !unsafe { *hint }.is_null() {
+            let name = get_hint(&lib, unsafe { *hint }, c"NAME");
+            let description = get_hint(&lib, unsafe { *hint }, c"DESC");
+            let io = get_hint(&lib, unsafe { *hint }, c"IOID");
+            hint = unsafe { hint.add(1) };
+
+            // Hints without an IOID support both directions.
+            let (Some(name), None | Some("Input")) = (name, io.as_deref()) else {
+                continue;
+            };
+            let direction = match name.contains("Loopback") {
+                true => Direction::Render,
+                false => Direction::Capture,
+            };
+            devices.push(DeviceInfo {
+                id: name.clone(),
+                // Descriptions can span several lines, e.g. the card name, then the device.
+                name: description.map_or(name, |d| d.replace('\n', ", ")),
+                direction,
+                default_format: None,
+            });
+        }
+        unsafe { (lib.device_name_free_hint)(hints) };
+        Ok(devices)
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Capture audio from the PCM device. Overruns are recovered from, and logged. A device which
+    /// stops producing audio, e.g. a paused source, is waited for, and the audio after the gap is
+    /// marked as a discontinuity. Stops with [`DeviceEvent::Lost`] when the device is unplugged.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Starting ALSA capture");
+        let block_align = self.audio_format.block_alignment() as usize;
+        let mut position = 0;
+
+        loop {
+            let mut chunk = vec![0u8; block_align * self.chunk_size];
+            let mut frames_read = 0;
+            let mut discontinuity = false;
+            while frames_read < self.chunk_size {
+                if unsafe { (self.lib.pcm_wait)(self.pcm, self.timeout) } == 0 {
+                    warn!("No audio from ALSA PCM device for {}ms", self.timeout);
+                    discontinuity = true;
+                    continue;
+                }
+                // Errors from the wait are returned again by the read, and handled below.
+                let result = unsafe {
+                    (self.lib.pcm_readi)(
+                        self.pcm,
+                        chunk[frames_read * block_align..].as_mut_ptr().cast(),
+                        (self.chunk_size - frames_read) as c_ulong,
+                    )
+                };
+                if result >= 0 {
+                    frames_read += result as usize;
+                    continue;
+                }
+
+                let code = result as c_int;
+                if code == -ffi::ENODEV {
+                    warn!("ALSA PCM device was disconnected");
+                    return Ok(Some(DeviceEvent::Lost));
+                }
+                if unsafe { (self.lib.pcm_recover)(self.pcm, code, 1) } < 0 {
+                    error!("Failed to read from ALSA PCM device");
+                    let reason = describe_error(&self.lib, code);
+                    return Err(Box::new(AlsaError::AudioCaptureFailed(reason)));
+                }
+                warn!(
+                    "Recovered from ALSA capture error: {}",
+                    describe_error(&self.lib, code)
+                );
+                // Recovery leaves the PCM prepared, but not running.
+                unsafe { (self.lib.pcm_start)(self.pcm) };
+                discontinuity = true;
+            }
+
+            // The read returns as soon as the last frame is captured.
+            let timestamp = Instant::now() - self.audio_format.duration_of(self.chunk_size);
+            let chunk = AudioChunk {
+                discontinuity,
+                ..AudioChunk::new(chunk, position, timestamp)
+            };
+            position += self.chunk_size as u64;
+            transmitter.send(AudioDataMessage::AudioData(chunk))?;
+        }
+    }
+}
+
+impl Drop for AlsaLoopbackRecorder {
+    fn drop(&mut self) {
+        unsafe { (self.lib.pcm_close)(self.pcm) };
+    }
+}
+
+/// Open the capture PCM selected by the options.
+fn open_pcm(lib: &Alsa, options: &CaptureOptions) -> Res<*mut c_void> {
+    let device = match options.direction {
+        Direction::Render => options.device.as_deref().unwrap_or(DEFAULT_LOOPBACK_DEVICE),
+        Direction::Capture => options.device.as_deref().unwrap_or(DEFAULT_CAPTURE_DEVICE),
+    };
+    debug!("Opening ALSA PCM device: {device}");
+
+    let device = CString::new(device)?;
+    let mut pcm = ptr::null_mut();
+    let result = unsafe { (lib.pcm_open)(&mut pcm, device.as_ptr(), ffi::STREAM_CAPTURE, 0) };
+    if result < 0 {
+        return Err(Box::new(AlsaError::DeviceUnavailable(describe_error(
+            lib, result,
+        ))));
+    }
+    Ok(pcm)
+}
+
+fn query_hw_params(lib: &Alsa, pcm: *mut c_void, params: *mut c_void) -> Res<SupportedFormats> {
+    check(lib, unsafe { (lib.hw_params_any)(pcm, params) })?;
+
+    let sample_formats = SampleFormat::value_variants()
+        .iter()
+        .copied()
+        .filter(|f| test_format(lib, pcm, params, *f))
+        .collect();
+    let sample_rates = STANDARD_SAMPLE_RATES
+        .into_iter()
+        .filter(|rate| unsafe { (lib.hw_params_test_rate)(pcm, params, *rate, 0) == 0 })
+        .collect();
+
+    let (mut min_channels, mut max_channels): (c_uint, c_uint) = (0, 0);
+    check(lib, unsafe {
+        (lib.hw_params_get_channels_min)(params, &mut min_channels)
+    })?;
+    check(lib, unsafe {
+        (lib.hw_params_get_channels_max)(params, &mut max_channels)
+    })?;
+    let max_channels = max_channels.min(u8::MAX as c_uint) as u8;
+
+    Ok(SupportedFormats {
+        native: None,
+        sample_rates: Some(sample_rates),
+        channels: (min_channels.max(1) as u8).min(max_channels)..=max_channels,
+        sample_formats,
+    })
+}
+
+/// Apply the hardware parameters for the requested format and buffering to the PCM, and start it.
+/// Returns the format the device was configured with.
+fn configure(
+    lib: &Alsa,
+    pcm: *mut c_void,
+    format: &RequestedAudioFormatInfo,
+    buffer: &BufferConfig,
+) -> Res<AudioFormatInfo> {
+    let mut params = ptr::null_mut();
+    check(lib, unsafe { (lib.hw_params_malloc)(&mut params) })?;
+    let result = apply_hw_params(lib, pcm, params, format, buffer);
+    unsafe { (lib.hw_params_free)(params) };
+    let mut audio_format = result?;
+    if let Some(layout) = query_layout(lib, pcm, audio_format.num_channels) {
+        audio_format.channel_layout = layout;
+    }
+    // Started now, rather than by the first read, so the first wait doesn't time out.
+    check(lib, unsafe { (lib.pcm_start)(pcm) })?;
+    Ok(audio_format)
+}
+
+fn apply_hw_params(
+    lib: &Alsa,
+    pcm: *mut c_void,
+    params: *mut c_void,
+    format: &RequestedAudioFormatInfo,
+    buffer: &BufferConfig,
+) -> Res<AudioFormatInfo> {
+    check(lib, unsafe { (lib.hw_params_any)(pcm, params) })?;
+    check(lib, unsafe {
+        (lib.hw_params_set_access)(pcm, params, ffi::ACCESS_RW_INTERLEAVED)
+    })?;
+
+    let sample_format = match format.format {
+        Some(sample_format) => sample_format,
+        None => *PREFERRED_FORMATS
+            .iter()
+            .find(|f| test_format(lib, pcm, params, **f))
+            .ok_or(AlsaError::UnsupportedFormat)?,
+    };
+    let alsa_format = sample_format_to_alsa(sample_format).ok_or(AlsaError::UnsupportedFormat)?;
+    check(lib, unsafe {
+        (lib.hw_params_set_format)(pcm, params, alsa_format)
+    })?;
+
+    let mut num_channels = format.num_channels.unwrap_or(DEFAULT_NUM_CHANNELS) as c_uint;
+    check(lib, unsafe {
+        (lib.hw_params_set_channels_near)(pcm, params, &mut num_channels)
+    })?;
+
+    let mut sample_rate = format.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE) as c_uint;
+    let mut dir: c_int = 0;
+    check(lib, unsafe {
+        (lib.hw_params_set_rate_near)(pcm, params, &mut sample_rate, &mut dir)
+    })?;
+
+    let mut period_size = buffer.chunk_size as c_ulong;
+    check(lib, unsafe {
+        (lib.hw_params_set_period_size_near)(pcm, params, &mut period_size, &mut dir)
+    })?;
+
+    if let Some(buffer_duration) = buffer.buffer_duration {
+        let mut buffer_time = buffer_duration.as_micros().min(c_uint::MAX as u128) as c_uint;
+        check(lib, unsafe {
+            (lib.hw_params_set_buffer_time_near)(pcm, params, &mut buffer_time, &mut dir)
+        })?;
+        debug!("ALSA buffer time: {buffer_time}us");
+    }
+
+    check(lib, unsafe { (lib.hw_params)(pcm, params) })?;
+
+    // Waits return once a period is captured, so can't be shorter.
+    let period = Duration::from_secs_f64(period_size as f64 / sample_rate as f64);
+    if buffer.timeout < period {
+        return Err(Box::new(AlsaError::ConfigurationFailed(format!(
+            "Timeout of {}ms is shorter than the {}ms period",
+            buffer.timeout.as_millis(),
+            period.as_millis()
+        ))));
+    }
+
+    if format
+        .num_channels
+        .is_some_and(|c| c as c_uint != num_channels)
+    {
+        warn!("Requested channel count not supported, using {num_channels}");
+    }
+    if format.sample_rate.is_some_and(|r| r != sample_rate) {
+        warn!("Requested sample rate not supported, using {sample_rate}");
+    }
+
+    Ok(AudioFormatInfo {
+        sample_rate,
+        num_channels: num_channels as u8,
+        format: sample_format,
+        channel_layout: ChannelLayout::default_for(num_channels as u8),
+    })
+}
+
+/// Query the positions of the channels of a configured PCM. Returns `None` if the driver doesn't
+/// report them, or they can't be described by a [`ChannelLayout`].
+fn query_layout(lib: &Alsa, pcm: *mut c_void, num_channels: u8) -> Option<ChannelLayout> {
+    let map = unsafe { (lib.pcm_get_chmap)(pcm) };
+    if map.is_null() {
+        return None;
+    }
+    let positions: Vec<c_uint> = unsafe {
+        let channels = (*map).channels as usize;
+        let positions = (map as *const c_uint).add(1);
+        (0..channels)
+            .map(|channel| *positions.add(channel))
+            .collect()
+    };
+    unsafe { ffi::free(map as *mut c_void) };
+
+    if positions.len() != num_channels as usize {
+        return None;
+    }
+    let layout = layout_from_alsa(&positions);
+    debug!("ALSA channel map: {positions:?}, layout: {layout:?}");
+    layout
+}
+
+/// Map `SND_CHMAP_*` channel positions to a [`ChannelLayout`], if every channel has a position
+/// with a WAV equivalent.
+fn layout_from_alsa(positions: &[c_uint]) -> Option<ChannelLayout> {
+    let positions: Vec<c_uint> = positions
+        .iter()
+        .map(|position| position & ffi::CHMAP_POSITION_MASK)
+        .collect();
+    // A single mono channel is played from the centre.
+    if positions == [ffi::CHMAP_MONO] {
+        return Some(ChannelLayout::MONO);
+    }
+    let speakers = positions
+        .iter()
+        .map(|position| {
+            CHANNEL_POSITIONS
+                .iter()
+                .find(|(_, alsa_position)| alsa_position == position)
+                .map(|(speaker, _)| *speaker)
+        })
+        .collect::<Option<Vec<Speaker>>>()?;
+    ChannelLayout::from_speakers(&speakers)
+}
+
+/// Convert a negative ALSA return code to an error.
+fn check(lib: &Alsa, result: c_int) -> Nothing {
+    if result < 0 {
+        return Err(Box::new(AlsaError::ConfigurationFailed(describe_error(
+            lib, result,
+        ))));
+    }
+    Ok(())
+}
+
+/// Map a [`SampleFormat`] to the equivalent little-endian `snd_pcm_format_t`, if there is one.
+/// ALSA's 24 bit formats in 4 bytes use the least significant bytes, so have no equivalent.
+fn sample_format_to_alsa(format: SampleFormat) -> Option<c_int> {
+    match format {
+        SampleFormat::UInt8 => Some(ffi::FORMAT_U8),
+        SampleFormat::Int16 => Some(ffi::FORMAT_S16_LE),
+        SampleFormat::Int24 => Some(ffi::FORMAT_S24_3LE),
+        SampleFormat::Int24In32 => None,
+        SampleFormat::Int32 => Some(ffi::FORMAT_S32_LE),
+        SampleFormat::Float32 => Some(ffi::FORMAT_FLOAT_LE),
+        SampleFormat::Float64 => Some(ffi::FORMAT_FLOAT64_LE),
+    }
+}
+
+/// Check whether the PCM accepts a sample format.
+fn test_format(lib: &Alsa, pcm: *mut c_void, params: *mut c_void, format: SampleFormat) -> bool {
+    sample_format_to_alsa(format)
+        .is_some_and(|format| unsafe { (lib.hw_params_test_format)(pcm, params, format) == 0 })
+}
+
+/// Copy a field of a device name hint, and free the original.
+fn get_hint(lib: &Alsa, hint: *const c_void, field: &CStr) -> Option<String> {
+    let value = unsafe { (lib.device_name_get_hint)(hint, field.as_ptr()) };
+    if value.is_null() {
+        return None;
+    }
+    let text = unsafe { CStr::from_ptr(value) }
+        .to_string_lossy()
+        .into_owned();
+    unsafe { ffi::free(value.cast()) };
+    Some(text)
+}
+
+/// Return the human readable message for an ALSA error code.
+fn describe_error(lib: &Alsa, error: c_int) -> String {
+    let message = unsafe { (lib.strerror)(error) };
+    if message.is_null() {
+        return format!("error code {error}");
+    }
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn sample_formats_map_to_alsa_formats() {
+        // Values of `snd_pcm_format_t`.
+        assert_eq!(sample_format_to_alsa(SampleFormat::UInt8), Some(1));
+        assert_eq!(sample_format_to_alsa(SampleFormat::Int16), Some(2));
+        assert_eq!(sample_format_to_alsa(SampleFormat::Int24), Some(32));
+        assert_eq!(sample_format_to_alsa(SampleFormat::Int24In32), None);
+        assert_eq!(sample_format_to_alsa(SampleFormat::Int32), Some(10));
+        assert_eq!(sample_format_to_alsa(SampleFormat::Float32), Some(14));
+        assert_eq!(sample_format_to_alsa(SampleFormat::Float64), Some(16));
+        for format in PREFERRED_FORMATS {
+            assert!(sample_format_to_alsa(format).is_some());
+        }
+    }
+
+    #[test]
+    fn channel_maps_round_trip_named_layouts() {
+        for layout in [
+            ChannelLayout::MONO,
+            ChannelLayout::STEREO,
+            ChannelLayout::QUAD,
+            ChannelLayout::SURROUND_5_1,
+            ChannelLayout::SURROUND_7_1,
+        ] {
+            let positions: Vec<c_uint> = layout
+                .speakers()
+                .map(|speaker| {
+                    let entry = CHANNEL_POSITIONS.iter().find(|(s, _)| *s == speaker);
+                    entry.unwrap().1
+                })
+                .collect();
+            assert_eq!(layout_from_alsa(&positions), Some(layout));
+        }
+    }
+
+    #[test]
+    fn channel_map_positions_are_unique() {
+        for (i, (speaker, position)) in CHANNEL_POSITIONS.iter().enumerate() {
+            for (other_speaker, other_position) in &CHANNEL_POSITIONS[i + 1..] {
+                assert_ne!(speaker, other_speaker);
+                assert_ne!(position, other_position);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_from_alsa_reads_mono_and_ignores_flags() {
+        assert_eq!(
+            layout_from_alsa(&[ffi::CHMAP_MONO]),
+            Some(ChannelLayout::MONO)
+        );
+        // The phase inverse flag.
+        assert_eq!(
+            layout_from_alsa(&[3, 4 | 0x10000]),
+            Some(ChannelLayout::STEREO)
+        );
+    }
+
+    #[test]
+    fn layout_from_alsa_rejects_maps_without_a_layout() {
+        // Unknown and unused positions.
+        assert_eq!(layout_from_alsa(&[0, 1]), None);
+        // ALSA's usual 5.1 order, with the centre and LFE last, isn't the WAV channel order.
+        assert_eq!(layout_from_alsa(&[3, 4, 5, 6, 7, 8]), None);
+    }
+
+    /// Needs the ALSA library. The `null` PCM produces silence as fast as it's read.
+    #[test]
+    #[ignore]
+    fn records_from_null_pcm() {
+        let format = RequestedAudioFormatInfo {
+            sample_rate: Some(48000),
+            num_channels: Some(2),
+            format: Some(SampleFormat::Int16),
+        };
+        let options = CaptureOptions {
+            device: Some("null".to_owned()),
+            direction: Direction::Capture,
+            ..CaptureOptions::default()
+        };
+        let recorder = AlsaLoopbackRecorder::create(format, options).unwrap();
+        let audio_format = recorder.get_audio_format();
+        assert_eq!(audio_format.sample_rate, 48000);
+        assert_eq!(audio_format.num_channels, 2);
+        assert_eq!(audio_format.format, SampleFormat::Int16);
+
+        let (transmitter, receiver) = mpsc::channel();
+        let capture = thread::spawn(move || recorder.capture(transmitter).is_err());
+        let chunk_size = BufferConfig::default().chunk_size;
+        for chunk_number in 0..4 {
+            match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+                AudioDataMessage::AudioData(chunk) => {
+                    assert_eq!(chunk.position, chunk_number * chunk_size as u64);
+                    assert_eq!(chunk.data, vec![0; 4 * chunk_size]);
+                }
+                _ => panic!("Expected audio data"),
+            }
+        }
+        // Capture stops with an error once the receiver is gone.
+        drop(receiver);
+        assert!(capture.join().unwrap());
+    }
+}
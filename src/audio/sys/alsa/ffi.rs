@@ -0,0 +1,125 @@
+//! Bindings for the small subset of `libasound` used by the ALSA recorder. The library is loaded at
+//! runtime, so the application can still start on systems without ALSA installed.
+use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+
+use libloading::Library;
+
+use crate::Res;
+
+pub const STREAM_CAPTURE: c_int = 1;
+
+pub const ACCESS_RW_INTERLEAVED: c_int = 3;
+
+pub const FORMAT_U8: c_int = 1;
+pub const FORMAT_S16_LE: c_int = 2;
+pub const FORMAT_S32_LE: c_int = 10;
+pub const FORMAT_FLOAT_LE: c_int = 14;
+pub const FORMAT_FLOAT64_LE: c_int = 16;
+pub const FORMAT_S24_3LE: c_int = 32;
+
+pub const CHMAP_MONO: c_uint = 2;
+/// Masks off the phase inversion and driver specific flags of a channel position.
+pub const CHMAP_POSITION_MASK: c_uint = 0xFFFF;
+
+/// Returned (negated) by reads once the device has been unplugged.
+pub const ENODEV: c_int = 19;
+
+extern "C" {
+    /// Frees the strings returned by `snd_device_name_get_hint`, and the maps returned by
+    /// `snd_pcm_get_chmap`.
+    pub fn free(ptr: *mut c_void);
+}
+
+/// Copy a function pointer out of a loaded library.
+///
+/// # Safety
+/// `T` must match the signature of the named symbol.
+unsafe fn symbol<T: Copy>(lib: &Library, name: &[u8]) -> Res<T> {
+    Ok(*lib.get::<T>(name)?)
+}
+
+/// Leading field of `snd_pcm_chmap_t`, which is followed by the position of each channel.
+#[repr(C)]
+pub struct ChannelMap {
+    pub channels: c_uint,
+}
+
+/// Functions from `libasound`. All `snd_pcm_t` and `snd_pcm_hw_params_t` handles are opaque.
+pub struct Alsa {
+    _lib: Library,
+    pub pcm_open: unsafe extern "C" fn(*mut *mut c_void, *const c_char, c_int, c_int) -> c_int,
+    pub pcm_close: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub pcm_readi: unsafe extern "C" fn(*mut c_void, *mut c_void, c_ulong) -> c_long,
+    pub pcm_recover: unsafe extern "C" fn(*mut c_void, c_int, c_int) -> c_int,
+    pub pcm_start: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub pcm_wait: unsafe extern "C" fn(*mut c_void, c_int) -> c_int,
+    pub hw_params_malloc: unsafe extern "C" fn(*mut *mut c_void) -> c_int,
+    pub hw_params_free: unsafe extern "C" fn(*mut c_void),
+    pub hw_params_any: unsafe extern "C" fn(*mut c_void, *mut c_void) -> c_int,
+    pub hw_params_set_access: unsafe extern "C" fn(*mut c_void, *mut c_void, c_int) -> c_int,
+    pub hw_params_test_format: unsafe extern "C" fn(*mut c_void, *mut c_void, c_int) -> c_int,
+    pub hw_params_set_format: unsafe extern "C" fn(*mut c_void, *mut c_void, c_int) -> c_int,
+    pub hw_params_get_channels_min: unsafe extern "C" fn(*const c_void, *mut c_uint) -> c_int,
+    pub hw_params_get_channels_max: unsafe extern "C" fn(*const c_void, *mut c_uint) -> c_int,
+    pub hw_params_set_channels_near:
+        unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_uint) -> c_int,
+    pub hw_params_test_rate: unsafe extern "C" fn(*mut c_void, *mut c_void, c_uint, c_int) -> c_int,
+    pub hw_params_set_rate_near:
+        unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_uint, *mut c_int) -> c_int,
+    pub hw_params_set_period_size_near:
+        unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_ulong, *mut c_int) -> c_int,
+    pub hw_params_set_buffer_time_near:
+        unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_uint, *mut c_int) -> c_int,
+    pub hw_params: unsafe extern "C" fn(*mut c_void, *mut c_void) -> c_int,
+    pub pcm_get_chmap: unsafe extern "C" fn(*mut c_void) -> *mut ChannelMap,
+    pub strerror: unsafe extern "C" fn(c_int) -> *const c_char,
+    pub device_name_hint:
+        unsafe extern "C" fn(c_int, *const c_char, *mut *mut *mut c_void) -> c_int,
+    pub device_name_get_hint: unsafe extern "C" fn(*const c_void, *const c_char) -> *mut c_char,
+    pub device_name_free_hint: unsafe extern "C" fn(*mut *mut c_void) -> c_int,
+}
+
+impl Alsa {
+    pub fn load() -> Res<Self> {
+        unsafe {
+            let lib = Library::new("libasound.so.2")?;
+            Ok(Alsa {
+                pcm_open: symbol(&lib, b"snd_pcm_open\0")?,
+                pcm_close: symbol(&lib, b"snd_pcm_close\0")?,
+                pcm_readi: symbol(&lib, b"snd_pcm_readi\0")?,
+                pcm_recover: symbol(&lib, b"snd_pcm_recover\0")?,
+                pcm_start: symbol(&lib, b"snd_pcm_start\0")?,
+                pcm_wait: symbol(&lib, b"snd_pcm_wait\0")?,
+                hw_params_malloc: symbol(&lib, b"snd_pcm_hw_params_malloc\0")?,
+                hw_params_free: symbol(&lib, b"snd_pcm_hw_params_free\0")?,
+                hw_params_any: symbol(&lib, b"snd_pcm_hw_params_any\0")?,
+                hw_params_set_access: symbol(&lib, b"snd_pcm_hw_params_set_access\0")?,
+                hw_params_test_format: symbol(&lib, b"snd_pcm_hw_params_test_format\0")?,
+                hw_params_set_format: symbol(&lib, b"snd_pcm_hw_params_set_format\0")?,
+                hw_params_get_channels_min: symbol(&lib, b"snd_pcm_hw_params_get_channels_min\0")?,
+                hw_params_get_channels_max: symbol(&lib, b"snd_pcm_hw_params_get_channels_max\0")?,
+                hw_params_set_channels_near: symbol(
+                    &lib,
+                    b"snd_pcm_hw_params_set_channels_near\0",
+                )?,
+                hw_params_test_rate: symbol(&lib, b"snd_pcm_hw_params_test_rate\0")?,
+                hw_params_set_rate_near: symbol(&lib, b"snd_pcm_hw_params_set_rate_near\0")?,
+                hw_params_set_period_size_near: symbol(
+                    &lib,
+                    b"snd_pcm_hw_params_set_period_size_near\0",
+                )?,
+                hw_params_set_buffer_time_near: symbol(
+                    &lib,
+                    b"snd_pcm_hw_params_set_buffer_time_near\0",
+                )?,
+                hw_params: symbol(&lib, b"snd_pcm_hw_params\0")?,
+                pcm_get_chmap: symbol(&lib, b"snd_pcm_get_chmap\0")?,
+                strerror: symbol(&lib, b"snd_strerror\0")?,
+                device_name_hint: symbol(&lib, b"snd_device_name_hint\0")?,
+                device_name_get_hint: symbol(&lib, b"snd_device_name_get_hint\0")?,
+                device_name_free_hint: symbol(&lib, b"snd_device_name_free_hint\0")?,
+                _lib: lib,
+            })
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use std::{error::Error, fmt::Display};
+
+use log::{debug, info};
+
+use crate::wave::WaveReader;
+use crate::Res;
+
+use crate::audio::negotiation::SupportedFormats;
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    RequestedAudioFormatInfo,
+};
+
+#[derive(Debug)]
+enum FileError {
+    NoFileName,
+    FormatMismatch,
+}
+
+impl Error for FileError {}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            FileError::NoFileName => "The WAV file to replay must be given as the device",
+            FileError::FormatMismatch => "Requested format does not match the WAV file format",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Replays an existing WAV file as if it were being captured from a device. The file name is given
+/// as the device.
+///
+/// The audio is sent either as fast as possible, or paced to real time. Capture finishes once the
+/// whole file has been sent.
+pub struct FileLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+
+    /// Whether to send each chunk only once its duration has elapsed.
+    is_realtime: bool,
+
+    reader: Mutex<WaveReader>,
+}
+
+impl AudioLoopback for FileLoopbackRecorder {
+    /// Create a new [`AudioLoopback`] recorder which replays a WAV file.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<FileLoopbackRecorder> {
+        let file_name = options.device.ok_or(FileError::NoFileName)?;
+        let reader = WaveReader::open(&file_name)?;
+        let audio_format = reader.audio_format();
+
+        let format_matches = format
+            .sample_rate
+            .is_none_or(|r| r == audio_format.sample_rate)
+            && format
+                .num_channels
+                .is_none_or(|c| c == audio_format.num_channels)
+            && format.format.is_none_or(|f| f == audio_format.format);
+        if !format_matches {
+            return Err(Box::new(FileError::FormatMismatch));
+        }
+
+        Ok(FileLoopbackRecorder {
+            audio_format,
+            chunk_size: options.buffer.chunk_size,
+            is_realtime: options.is_realtime,
+            reader: Mutex::new(reader),
+        })
+    }
+
+    /// Files can always be replayed.
+    fn is_available() -> bool {
+        true
+    }
+
+    /// A file can only be replayed in its own format.
+    fn supported_formats(options: &CaptureOptions) -> Res<SupportedFormats> {
+        let file_name = options.device.as_ref().ok_or(FileError::NoFileName)?;
+        Ok(SupportedFormats::only(
+            WaveReader::open(file_name)?.audio_format(),
+        ))
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Send the audio data from the file, until the end of the file is reached.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Starting WAV file replay");
+        let mut reader = self.reader.lock().unwrap();
+        let block_align = self.audio_format.block_alignment() as usize;
+        let start = Instant::now();
+        let mut frames_sent = 0;
+
+        loop {
+            let mut chunk = vec![0u8; block_align * self.chunk_size];
+            let bytes_read = reader.read(&mut chunk)?;
+            if bytes_read == 0 {
+                break;
+            }
+            chunk.truncate(bytes_read);
+            let position = frames_sent;
+            frames_sent += bytes_read / block_align;
+
+            // When paced, each frame is "captured" at its position in the file.
+            let timestamp = match self.is_realtime {
+                true => {
+                    let elapsed = self.audio_format.duration_of(frames_sent);
+                    if let Some(delay) = (start + elapsed).checked_duration_since(Instant::now()) {
+                        thread::sleep(delay);
+                    }
+                    start + self.audio_format.duration_of(position)
+                }
+                false => Instant::now(),
+            };
+
+            let chunk = AudioChunk::new(chunk, position as u64, timestamp);
+            transmitter.send(AudioDataMessage::AudioData(chunk))?;
+        }
+        info!("Finished replaying {frames_sent} frames");
+        Ok(None)
+    }
+}
@@ -0,0 +1,456 @@
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use std::{error::Error, f64::consts::PI, fmt::Display};
+
+use log::{debug, info, warn};
+
+use crate::Res;
+
+use crate::audio::convert::write_sample;
+use crate::audio::layout::ChannelLayout;
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    RequestedAudioFormatInfo, SampleFormat,
+};
+
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_NUM_CHANNELS: u8 = 2;
+const DEFAULT_LEVEL_DB: f64 = -6.0;
+
+/// Seed for the noise generators, so generated files are identical on every run.
+const NOISE_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+#[derive(Debug)]
+enum GeneratorError {
+    UnknownSignal(String),
+    InvalidParameter(String),
+}
+
+impl Error for GeneratorError {}
+
+impl Display for GeneratorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeneratorError::UnknownSignal(name) => write!(
+                f,
+                "Unknown test signal '{name}', expected one of: sine, sweep, white, pink, impulse, silence"
+            ),
+            GeneratorError::InvalidParameter(value) => {
+                write!(f, "Invalid test signal parameter: {value}")
+            }
+        }
+    }
+}
+
+/// Test signal to generate. Parsed from the device name, in the form `name[:param[:param...]]`.
+/// Levels are given in dBFS, frequencies in Hz.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Signal {
+    /// `sine[:frequency[:level]]`
+    Sine { frequency: f64, amplitude: f64 },
+
+    /// Logarithmic sweep, repeated every `seconds`.
+    /// `sweep[:start:end[:seconds[:level]]]`
+    Sweep {
+        start: f64,
+        end: f64,
+        seconds: f64,
+        amplitude: f64,
+    },
+
+    /// `white[:level]`
+    WhiteNoise { amplitude: f64 },
+
+    /// `pink[:level]`
+    PinkNoise { amplitude: f64 },
+
+    /// Full scale single sample impulses, `rate` times per second.
+    /// `impulse[:rate]`
+    Impulse { rate: f64 },
+
+    /// Digital silence.
+    /// `silence`
+    Silence,
+}
+
+impl FromStr for Signal {
+    type Err = GeneratorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let params = parts
+            .map(|p| {
+                p.parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| GeneratorError::InvalidParameter(p.to_owned()))
+            })
+            .collect::<Result<Vec<f64>, _>>()?;
+        let param = |index: usize, default: f64| params.get(index).copied().unwrap_or(default);
+        let level = |index: usize| 10f64.powf(param(index, DEFAULT_LEVEL_DB) / 20.0);
+
+        let signal = match name {
+            "sine" => Signal::Sine {
+                frequency: param(0, 1000.0),
+                amplitude: level(1),
+            },
+            "sweep" => Signal::Sweep {
+                start: param(0, 20.0),
+                end: param(1, 20000.0),
+                seconds: param(2, 10.0),
+                amplitude: level(3),
+            },
+            "white" => Signal::WhiteNoise {
+                amplitude: level(0),
+            },
+            "pink" => Signal::PinkNoise {
+                amplitude: level(0),
+            },
+            "impulse" => Signal::Impulse {
+                rate: param(0, 1.0),
+            },
+            "silence" => Signal::Silence,
+            _ => return Err(GeneratorError::UnknownSignal(name.to_owned())),
+        };
+
+        let is_valid = match signal {
+            Signal::Sine { frequency, .. } => frequency >= 0.0,
+            Signal::Sweep {
+                start,
+                end,
+                seconds,
+                ..
+            } => start > 0.0 && end > 0.0 && seconds > 0.0,
+            Signal::Impulse { rate } => rate > 0.0,
+            _ => true,
+        };
+        if !is_valid {
+            return Err(GeneratorError::InvalidParameter(s.to_owned()));
+        }
+        Ok(signal)
+    }
+}
+
+/// Xorshift64* pseudo random number generator. Fast, and deterministic for a given seed.
+struct Rng(u64);
+
+impl Rng {
+    /// Return a uniformly distributed value in `[-1, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+/// Produces the samples of a [`Signal`], one frame at a time.
+struct SignalGenerator {
+    signal: Signal,
+    sample_rate: f64,
+
+    /// Index of the next frame.
+    frame: u64,
+
+    /// Phase of the sine generator, as a fraction of a cycle.
+    phase: f64,
+
+    rng: Rng,
+
+    /// State of the pink noise filter.
+    pink: [f64; 7],
+}
+
+impl SignalGenerator {
+    fn new(signal: Signal, sample_rate: u32) -> SignalGenerator {
+        SignalGenerator {
+            signal,
+            sample_rate: sample_rate as f64,
+            frame: 0,
+            phase: 0.0,
+            rng: Rng(NOISE_SEED),
+            pink: [0.0; 7],
+        }
+    }
+
+    /// Return the next sample, in the range `[-1, 1]`.
+    fn next_sample(&mut self) -> f64 {
+        let frame = self.frame;
+        self.frame += 1;
+
+        match self.signal {
+            Signal::Sine {
+                frequency,
+                amplitude,
+            } => {
+                let sample = (2.0 * PI * self.phase).sin();
+                self.phase = (self.phase + frequency / self.sample_rate).fract();
+                amplitude * sample
+            }
+            Signal::Sweep {
+                start,
+                end,
+                seconds,
+                amplitude,
+            } => {
+                let sweep_frames = (seconds * self.sample_rate).round().max(1.0) as u64;
+                let t = (frame % sweep_frames) as f64 / self.sample_rate;
+                let rate = (end / start).ln();
+                let phase = if rate == 0.0 {
+                    start * t
+                } else {
+                    start * seconds / rate * ((t * rate / seconds).exp() - 1.0)
+                };
+                amplitude * (2.0 * PI * phase.fract()).sin()
+            }
+            Signal::WhiteNoise { amplitude } => amplitude * self.rng.next_f64(),
+            Signal::PinkNoise { amplitude } => amplitude * self.next_pink(),
+            Signal::Impulse { rate } => {
+                let period = (self.sample_rate / rate).round().max(1.0) as u64;
+                if frame.is_multiple_of(period) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Signal::Silence => 0.0,
+        }
+    }
+
+    /// Filter white noise to pink, using Paul Kellet's refined method. The output is scaled to
+    /// stay within `[-1, 1]`.
+    fn next_pink(&mut self) -> f64 {
+        let white = self.rng.next_f64();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        (pink * 0.11).clamp(-1.0, 1.0)
+    }
+}
+
+/// Pseudo-device which generates test signals, instead of recording from a device. The signal is
+/// selected with the device name, and the same signal is written to every channel.
+///
+/// The signal is generated as fast as possible, or paced to real time. Generation stops after
+/// [`CaptureOptions::duration`], if set.
+pub struct GeneratorLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+
+    /// Whether to send each chunk only once its duration has elapsed.
+    is_realtime: bool,
+
+    /// Number of frames to generate before stopping.
+    max_frames: Option<u64>,
+
+    generator: Mutex<SignalGenerator>,
+}
+
+impl AudioLoopback for GeneratorLoopbackRecorder {
+    /// Create a new [`AudioLoopback`] test signal generator.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<GeneratorLoopbackRecorder> {
+        let signal: Signal = options.device.as_deref().unwrap_or("sine").parse()?;
+        debug!("Generating test signal: {signal:?}");
+
+        let num_channels = format.num_channels.unwrap_or(DEFAULT_NUM_CHANNELS);
+        let audio_format = AudioFormatInfo {
+            sample_rate: format.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE),
+            num_channels,
+            format: format.format.unwrap_or(SampleFormat::Float32),
+            channel_layout: ChannelLayout::default_for(num_channels),
+        };
+        let max_frames = options
+            .duration
+            .map(|d| (d.as_secs_f64() * audio_format.sample_rate as f64).round() as u64);
+        if max_frames.is_none() && !options.is_realtime {
+            warn!("Generating audio as fast as possible, with no duration set");
+        }
+
+        Ok(GeneratorLoopbackRecorder {
+            audio_format,
+            chunk_size: options.buffer.chunk_size,
+            is_realtime: options.is_realtime,
+            max_frames,
+            generator: Mutex::new(SignalGenerator::new(signal, audio_format.sample_rate)),
+        })
+    }
+
+    /// The generator doesn't depend on anything on the system.
+    fn is_available() -> bool {
+        true
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Generate the test signal, until the duration has been reached.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Starting test signal generator");
+        let mut generator = self.generator.lock().unwrap();
+        let block_align = self.audio_format.block_alignment() as usize;
+        let start = Instant::now();
+        let mut frames_sent: u64 = 0;
+
+        loop {
+            let num_frames = match self.max_frames {
+                Some(max_frames) => (max_frames - frames_sent).min(self.chunk_size as u64),
+                None => self.chunk_size as u64,
+            };
+            if num_frames == 0 {
+                break;
+            }
+
+            let mut chunk = Vec::with_capacity(block_align * num_frames as usize);
+            for _ in 0..num_frames {
+                let sample = generator.next_sample();
+                for _ in 0..self.audio_format.num_channels {
+                    write_sample(self.audio_format.format, sample, &mut chunk);
+                }
+            }
+            let position = frames_sent;
+            frames_sent += num_frames;
+
+            // When paced, each frame is "captured" at its position in the signal.
+            let timestamp = match self.is_realtime {
+                true => {
+                    let elapsed = self.audio_format.duration_of(frames_sent as usize);
+                    if let Some(delay) = (start + elapsed).checked_duration_since(Instant::now()) {
+                        thread::sleep(delay);
+                    }
+                    start + self.audio_format.duration_of(position as usize)
+                }
+                false => Instant::now(),
+            };
+
+            let chunk = AudioChunk::new(chunk, position, timestamp);
+            transmitter.send(AudioDataMessage::AudioData(chunk))?;
+        }
+        info!("Finished generating {frames_sent} frames");
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signal_parses_names_with_default_parameters() {
+        assert_eq!(
+            "sine".parse::<Signal>().unwrap(),
+            Signal::Sine {
+                frequency: 1000.0,
+                amplitude: 10f64.powf(DEFAULT_LEVEL_DB / 20.0)
+            }
+        );
+        assert_eq!("silence".parse::<Signal>().unwrap(), Signal::Silence);
+        assert_eq!(
+            "impulse".parse::<Signal>().unwrap(),
+            Signal::Impulse { rate: 1.0 }
+        );
+    }
+
+    #[test]
+    fn signal_parses_parameters() {
+        assert_eq!(
+            "sine:440:0".parse::<Signal>().unwrap(),
+            Signal::Sine {
+                frequency: 440.0,
+                amplitude: 1.0
+            }
+        );
+        assert_eq!(
+            "sweep:100:1000:2:-20".parse::<Signal>().unwrap(),
+            Signal::Sweep {
+                start: 100.0,
+                end: 1000.0,
+                seconds: 2.0,
+                amplitude: 0.1
+            }
+        );
+    }
+
+    #[test]
+    fn signal_rejects_invalid_names_and_parameters() {
+        assert!("square".parse::<Signal>().is_err());
+        assert!("sine:loud".parse::<Signal>().is_err());
+        assert!("sweep:0:1000".parse::<Signal>().is_err());
+        assert!("impulse:0".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn silence_is_all_zero() {
+        let mut generator = SignalGenerator::new(Signal::Silence, 48000);
+        assert!((0..1000).all(|_| generator.next_sample() == 0.0));
+    }
+
+    #[test]
+    fn sine_matches_reference_values() {
+        let signal = Signal::Sine {
+            frequency: 12000.0,
+            amplitude: 1.0,
+        };
+        let mut generator = SignalGenerator::new(signal, 48000);
+        let samples: Vec<f64> = (0..8).map(|_| generator.next_sample()).collect();
+        let expected = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0];
+        for (sample, expected) in samples.iter().zip(expected) {
+            assert!((sample - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn impulses_are_spaced_by_rate() {
+        let mut generator = SignalGenerator::new(Signal::Impulse { rate: 100.0 }, 48000);
+        let impulses: Vec<usize> = (0..1000)
+            .filter(|_| generator.next_sample() == 1.0)
+            .collect();
+        assert_eq!(impulses, vec![0, 480, 960]);
+    }
+
+    #[test]
+    fn noise_is_deterministic_and_in_range() {
+        for signal in [
+            Signal::WhiteNoise { amplitude: 1.0 },
+            Signal::PinkNoise { amplitude: 1.0 },
+        ] {
+            let mut first = SignalGenerator::new(signal, 48000);
+            let mut second = SignalGenerator::new(signal, 48000);
+            for _ in 0..10000 {
+                let sample = first.next_sample();
+                assert_eq!(sample, second.next_sample());
+                assert!((-1.0..=1.0).contains(&sample));
+            }
+        }
+    }
+
+    #[test]
+    fn sweep_stays_within_amplitude() {
+        let signal = Signal::Sweep {
+            start: 20.0,
+            end: 20000.0,
+            seconds: 1.0,
+            amplitude: 0.5,
+        };
+        let mut generator = SignalGenerator::new(signal, 48000);
+        assert!((0..96000).all(|_| generator.next_sample().abs() <= 0.5));
+    }
+}
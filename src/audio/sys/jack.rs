@@ -0,0 +1,594 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{error::Error, fmt::Display};
+
+use log::{debug, info, warn};
+
+use crate::{Nothing, Res};
+
+use crate::audio::layout::ChannelLayout;
+use crate::audio::negotiation::SupportedFormats;
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    DeviceInfo, Direction, RequestedAudioFormatInfo, SampleFormat,
+};
+
+use ffi::Jack;
+
+mod ffi;
+
+/// Client name registered with the JACK server.
+const CLIENT_NAME: &CStr = c"wavrec";
+
+/// Ports recorded from when none were requested: everything sent to the system outputs.
+const DEFAULT_PORT_PATTERN: &str = "^system:playback_";
+
+/// Ports recorded from when none were requested, when recording an input device: the system
+/// inputs.
+const DEFAULT_CAPTURE_PORT_PATTERN: &str = "^system:capture_";
+
+/// Number of JACK periods which can be queued before the capture loop falls behind, when no buffer
+/// duration was requested.
+const DEFAULT_PERIOD_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug)]
+enum JackError {
+    ServerUnavailable,
+    UnsupportedFormat,
+    UnsupportedSampleRate(u32),
+    NoPortsFound(String),
+    NoChannels,
+    ClientFailed(&'static str),
+}
+
+impl Error for JackError {}
+
+impl Display for JackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JackError::ServerUnavailable => write!(f, "Failed to connect to the JACK server"),
+            JackError::UnsupportedFormat => write!(f, "JACK only provides 32 bit float audio"),
+            JackError::UnsupportedSampleRate(rate) => {
+                write!(f, "Sample rate must match the JACK server rate of {rate}")
+            }
+            JackError::NoPortsFound(pattern) => write!(f, "No JACK ports matching: {pattern}"),
+            JackError::NoChannels => write!(f, "Can't record 0 channels from JACK"),
+            JackError::ClientFailed(reason) => write!(f, "JACK client failed: {reason}"),
+        }
+    }
+}
+
+/// State shared with the JACK process and shutdown callbacks, which run on threads owned by the
+/// JACK client library.
+struct ProcessState {
+    port_get_buffer: unsafe extern "C" fn(*mut c_void, u32) -> *mut c_void,
+
+    /// Registered input ports, one per channel.
+    ports: Vec<*mut c_void>,
+
+    /// Sends each period of interleaved float audio to the capture loop.
+    sender: SyncSender<Vec<u8>>,
+
+    /// Empty period buffers, returned by the capture loop once it has read them. Buffers are
+    /// allocated up front and recycled, as allocating on JACK's realtime thread can block. Only
+    /// used by the process callback.
+    free_periods: Receiver<Vec<u8>>,
+
+    /// Returns period buffers which couldn't be queued to the free buffers.
+    recycler: SyncSender<Vec<u8>>,
+
+    /// Number of frames dropped because the capture loop fell behind.
+    dropped_frames: AtomicUsize,
+
+    is_shut_down: AtomicBool,
+}
+
+/// Client registered with the JACK server. Deactivated and closed when dropped.
+struct Client {
+    lib: Jack,
+
+    /// Handle to the `jack_client_t`.
+    handle: *mut c_void,
+}
+
+impl Client {
+    /// Open a client on an already running JACK server.
+    fn open() -> Res<Client> {
+        let lib = Jack::load()?;
+        let mut status: c_int = 0;
+        let handle =
+            unsafe { (lib.client_open)(CLIENT_NAME.as_ptr(), ffi::NO_START_SERVER, &mut status) };
+        if handle.is_null() {
+            return Err(Box::new(JackError::ServerUnavailable));
+        }
+        Ok(Client { lib, handle })
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        unsafe {
+            (self.lib.deactivate)(self.handle);
+            (self.lib.client_close)(self.handle);
+        }
+    }
+}
+
+/// Recorder which runs as a JACK client. An input port is registered for each channel, and
+/// connected to the ports selected with [`CaptureOptions::ports`], or the port named by
+/// [`CaptureOptions::device`].
+///
+/// Connections are only made when recording starts, so later changes in the JACK graph are not
+/// followed.
+pub struct JackLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+
+    /// How long to wait for the next period before checking whether the server has shut down.
+    timeout: Duration,
+
+    /// Declared before `state`, so the client is closed before the state used by its callbacks is
+    /// freed.
+    client: Client,
+
+    state: Box<ProcessState>,
+
+    periods: Mutex<Receiver<Vec<u8>>>,
+
+    /// Returns period buffers to the process callback once they've been read.
+    recycler: SyncSender<Vec<u8>>,
+}
+
+unsafe impl Send for JackLoopbackRecorder {}
+unsafe impl Sync for JackLoopbackRecorder {}
+
+impl AudioLoopback for JackLoopbackRecorder {
+    /// Create a new JACK-based [`AudioLoopback`] recorder.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<JackLoopbackRecorder> {
+        debug!("Connecting to JACK server");
+        let client = Client::open()?;
+        let lib = &client.lib;
+
+        if format
+            .format
+            .is_some_and(|f| !matches!(f, SampleFormat::Float32))
+        {
+            return Err(Box::new(JackError::UnsupportedFormat));
+        }
+        let sample_rate = unsafe { (lib.get_sample_rate)(client.handle) };
+        if format.sample_rate.is_some_and(|r| r != sample_rate) {
+            return Err(Box::new(JackError::UnsupportedSampleRate(sample_rate)));
+        }
+
+        let mut patterns = options.ports;
+        if let Some(device) = &options.device {
+            patterns.push(format!("^{}$", escape_pattern(device)));
+        }
+        if patterns.is_empty() {
+            patterns.push(match options.direction {
+                Direction::Render => DEFAULT_PORT_PATTERN.to_owned(),
+                Direction::Capture => DEFAULT_CAPTURE_PORT_PATTERN.to_owned(),
+            });
+        }
+        let sources = find_source_ports(lib, client.handle, &patterns);
+        if sources.is_empty() {
+            return Err(Box::new(JackError::NoPortsFound(patterns.join(", "))));
+        }
+
+        // Every matching port is recorded, up to the most channels a format can have.
+        let num_channels = format
+            .num_channels
+            .unwrap_or(u8::try_from(sources.len()).unwrap_or(u8::MAX));
+        if num_channels == 0 {
+            return Err(Box::new(JackError::NoChannels));
+        }
+        if num_channels as usize != sources.len() {
+            warn!(
+                "Recording {num_channels} channels from {} matching JACK ports",
+                sources.len()
+            );
+        }
+
+        let mut ports = Vec::with_capacity(num_channels as usize);
+        for channel in 1..=num_channels {
+            let name = CString::new(format!("in_{channel}"))?;
+            let port = unsafe {
+                (lib.port_register)(
+                    client.handle,
+                    name.as_ptr(),
+                    ffi::DEFAULT_AUDIO_TYPE.as_ptr(),
+                    ffi::PORT_IS_INPUT,
+                    0,
+                )
+            };
+            if port.is_null() {
+                return Err(Box::new(JackError::ClientFailed(
+                    "could not register input port",
+                )));
+            }
+            ports.push(port);
+        }
+
+        // The server's buffer size is fixed, so the requested buffer is made up in the queue.
+        let period_size = unsafe { (lib.get_buffer_size)(client.handle) }.max(1) as usize;
+        let queue_size = options
+            .buffer
+            .buffer_duration
+            .map_or(DEFAULT_PERIOD_QUEUE_SIZE, |d| {
+                let buffer_frames = (d.as_secs_f64() * sample_rate as f64).ceil() as usize;
+                buffer_frames.div_ceil(period_size).max(1)
+            });
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        // Enough buffers to fill the queue, and one more being read by the capture loop. The
+        // callback drops a period when none are free, as when the queue is full.
+        let num_buffers = queue_size + 1;
+        let (recycler, free_periods) = mpsc::sync_channel(num_buffers);
+        let period_bytes = period_size * num_channels as usize * 4;
+        for _ in 0..num_buffers {
+            recycler.send(Vec::with_capacity(period_bytes))?;
+        }
+        let state = Box::new(ProcessState {
+            port_get_buffer: lib.port_get_buffer,
+            ports,
+            sender,
+            free_periods,
+            recycler: recycler.clone(),
+            dropped_frames: AtomicUsize::new(0),
+            is_shut_down: AtomicBool::new(false),
+        });
+        let recorder = JackLoopbackRecorder {
+            audio_format: AudioFormatInfo {
+                sample_rate,
+                num_channels,
+                format: SampleFormat::Float32,
+                // JACK ports have no speaker positions, so the usual layout is assumed.
+                channel_layout: ChannelLayout::default_for(num_channels),
+            },
+            chunk_size: options.buffer.chunk_size,
+            timeout: options.buffer.timeout,
+            client,
+            state,
+            periods: Mutex::new(receiver),
+            recycler,
+        };
+        recorder.activate(&sources)?;
+        info!("Connected to JACK ports: {}", patterns.join(", "));
+
+        Ok(recorder)
+    }
+
+    /// Check whether the JACK library is installed, and a server is running.
+    fn is_available() -> bool {
+        Client::open().is_ok()
+    }
+
+    /// JACK audio is always 32 bit float at the server's sample rate. Any number of ports can be
+    /// registered.
+    fn supported_formats(_options: &CaptureOptions) -> Res<SupportedFormats> {
+        let client = Client::open()?;
+        let sample_rate = unsafe { (client.lib.get_sample_rate)(client.handle) };
+        Ok(SupportedFormats {
+            native: None,
+            sample_rates: Some(vec![sample_rate]),
+            channels: 1..=u8::MAX,
+            sample_formats: vec![SampleFormat::Float32],
+        })
+    }
+
+    /// List the audio ports. Input ports, and the output ports of other clients, record the audio
+    /// being played. Physical output ports are the system inputs. The id is the full port name.
+    fn devices() -> Res<Vec<DeviceInfo>> {
+        let client = Client::open()?;
+        let lib = &client.lib;
+        let default_format = AudioFormatInfo {
+            sample_rate: unsafe { (lib.get_sample_rate)(client.handle) },
+            num_channels: 1,
+            format: SampleFormat::Float32,
+            channel_layout: ChannelLayout::MONO,
+        };
+
+        let names = unsafe {
+            (lib.get_ports)(
+                client.handle,
+                ptr::null(),
+                ffi::DEFAULT_AUDIO_TYPE.as_ptr(),
+                0,
+            )
+        };
+        let mut devices = Vec::new();
+        for name in take_port_list(lib, names) {
+            let Ok(c_name) = CString::new(name.as_str()) else {
+                continue;
+            };
+            let port = unsafe { (lib.port_by_name)(client.handle, c_name.as_ptr()) };
+            if port.is_null() {
+                continue;
+            }
+            let flags = unsafe { (lib.port_flags)(port) };
+            let is_system_input =
+                flags & ffi::PORT_IS_OUTPUT != 0 && flags & ffi::PORT_IS_PHYSICAL != 0;
+            devices.push(DeviceInfo {
+                id: name.clone(),
+                name,
+                direction: match is_system_input {
+                    true => Direction::Capture,
+                    false => Direction::Render,
+                },
+                default_format: Some(default_format),
+            });
+        }
+        Ok(devices)
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Capture audio from the connected JACK ports. Stops with [`DeviceEvent::Lost`] when the
+    /// server shuts down.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Starting JACK capture");
+        let periods = self.periods.lock().unwrap();
+        let chunk_bytes = self.audio_format.block_alignment() as usize * self.chunk_size;
+        let block_align = self.audio_format.block_alignment() as usize;
+        let mut sample_queue: Vec<u8> = Vec::with_capacity(2 * chunk_bytes);
+        let mut position = 0;
+        let mut discontinuity = false;
+
+        loop {
+            match periods.recv_timeout(self.timeout) {
+                Ok(period) => {
+                    sample_queue.extend_from_slice(&period);
+                    // There's room for every buffer, so this never blocks.
+                    let _ = self.recycler.send(period);
+                }
+                Err(RecvTimeoutError::Timeout)
+                    if !self.state.is_shut_down.load(Ordering::Relaxed) =>
+                {
+                    continue
+                }
+                Err(_) => {
+                    warn!("JACK server shut down during capture");
+                    return Ok(Some(DeviceEvent::Lost));
+                }
+            }
+
+            // Dropped periods are only noticed once the next one arrives, so the lost frames are
+            // counted from the end of the queue, not at their exact position.
+            let dropped_frames = self.state.dropped_frames.swap(0, Ordering::Relaxed);
+            if dropped_frames > 0 {
+                warn!("Dropped {dropped_frames} JACK frames, capture is falling behind");
+                position += dropped_frames as u64;
+                discontinuity = true;
+            }
+
+            while sample_queue.len() >= chunk_bytes {
+                let queued_frames = sample_queue.len() / block_align;
+                let timestamp = Instant::now() - self.audio_format.duration_of(queued_frames);
+                let data = sample_queue.drain(..chunk_bytes).collect();
+                let chunk = AudioChunk {
+                    discontinuity,
+                    ..AudioChunk::new(data, position, timestamp)
+                };
+                position += self.chunk_size as u64;
+                discontinuity = false;
+                transmitter.send(AudioDataMessage::AudioData(chunk))?;
+            }
+        }
+    }
+}
+
+impl JackLoopbackRecorder {
+    /// Install the callbacks, activate the client and connect each input port to its sources.
+    fn activate(&self, sources: &[Vec<String>]) -> Nothing {
+        let lib = &self.client.lib;
+        let handle = self.client.handle;
+        let state = &*self.state as *const ProcessState as *mut c_void;
+        unsafe {
+            (lib.set_process_callback)(handle, process, state);
+            (lib.on_shutdown)(handle, shutdown, state);
+        }
+        if unsafe { (lib.activate)(handle) } != 0 {
+            return Err(Box::new(JackError::ClientFailed(
+                "could not activate client",
+            )));
+        }
+
+        for (port, channel_sources) in self.state.ports.iter().zip(sources) {
+            let destination = unsafe { (lib.port_name)(*port) };
+            for source in channel_sources {
+                let source = CString::new(source.as_str())?;
+                if unsafe { (lib.connect)(handle, source.as_ptr(), destination) } != 0 {
+                    warn!("Failed to connect JACK port {}", source.to_string_lossy());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find the ports to connect to each channel, for the given port name patterns.
+///
+/// Output ports are connected directly. Input ports, such as `system:playback_1`, are recorded by
+/// connecting to every port currently feeding them.
+fn find_source_ports(lib: &Jack, client: *mut c_void, patterns: &[String]) -> Vec<Vec<String>> {
+    let mut sources = Vec::new();
+    for pattern in patterns {
+        let Ok(pattern) = CString::new(pattern.as_str()) else {
+            continue;
+        };
+        let names = unsafe {
+            (lib.get_ports)(
+                client,
+                pattern.as_ptr(),
+                ffi::DEFAULT_AUDIO_TYPE.as_ptr(),
+                0,
+            )
+        };
+        for name in take_port_list(lib, names) {
+            let Ok(c_name) = CString::new(name.as_str()) else {
+                continue;
+            };
+            let port = unsafe { (lib.port_by_name)(client, c_name.as_ptr()) };
+            if port.is_null() {
+                continue;
+            }
+            if unsafe { (lib.port_flags)(port) } & ffi::PORT_IS_OUTPUT != 0 {
+                sources.push(vec![name]);
+            } else {
+                let connections = unsafe { (lib.port_get_all_connections)(client, port) };
+                sources.push(take_port_list(lib, connections));
+            }
+        }
+    }
+    sources
+}
+
+/// Escape a port name, so it only matches itself when used as a port pattern.
+fn escape_pattern(name: &str) -> String {
+    let mut pattern = String::with_capacity(name.len());
+    for c in name.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Copy a `NULL` terminated list of port names returned by JACK, and free the original.
+fn take_port_list(lib: &Jack, list: *mut *const c_char) -> Vec<String> {
+    let mut names = Vec::new();
+    if list.is_null() {
+        return names;
+    }
+    let mut entry = list;
+    unsafe {
+        while !(*entry).is_null() {
+            names.push(CStr::from_ptr(*entry).to_string_lossy().into_owned());
+            entry = entry.add(1);
+        }
+        (lib.free)(list.cast());
+    }
+    names
+}
+
+/// JACK process callback. Interleaves the port buffers for the period into a free period buffer
+/// and queues it for the capture loop. Nothing is allocated, so the realtime thread isn't blocked.
+extern "C" fn process(num_frames: u32, arg: *mut c_void) -> c_int {
+    let state = unsafe { &*(arg as *const ProcessState) };
+    let num_frames = num_frames as usize;
+    let Ok(mut period) = state.free_periods.try_recv() else {
+        // Every buffer is queued, the capture loop is falling behind.
+        state
+            .dropped_frames
+            .fetch_add(num_frames, Ordering::Relaxed);
+        return 0;
+    };
+
+    // Within the buffer's capacity, unless the server's buffer size has grown.
+    let num_channels = state.ports.len();
+    period.resize(num_frames * num_channels * 4, 0);
+    for (channel, port) in state.ports.iter().enumerate() {
+        let buffer = unsafe { (state.port_get_buffer)(*port, num_frames as u32) as *const f32 };
+        for frame in 0..num_frames {
+            let sample = unsafe { *buffer.add(frame) };
+            let offset = (frame * num_channels + channel) * 4;
+            period[offset..offset + 4].copy_from_slice(&sample.to_le_bytes());
+        }
+    }
+
+    if let Err(err) = state.sender.try_send(period) {
+        state
+            .dropped_frames
+            .fetch_add(num_frames, Ordering::Relaxed);
+        // Back to the free buffers, rather than freeing it here.
+        let (TrySendError::Full(period) | TrySendError::Disconnected(period)) = err;
+        let _ = state.recycler.try_send(period);
+    }
+    0
+}
+
+/// JACK shutdown callback, called when the server stops or disconnects the client.
+extern "C" fn shutdown(arg: *mut c_void) {
+    let state = unsafe { &*(arg as *const ProcessState) };
+    state.is_shut_down.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::audio::BufferConfig;
+
+    #[test]
+    fn escape_pattern_escapes_regex_characters() {
+        assert_eq!(escape_pattern("system:playback_1"), "system:playback_1");
+        assert_eq!(
+            escape_pattern("PulseAudio JACK Sink"),
+            "PulseAudio JACK Sink"
+        );
+        assert_eq!(escape_pattern("app.exe:out"), "app\\.exe:out");
+        assert_eq!(
+            escape_pattern("synth (voice 1):out"),
+            "synth \\(voice 1\\):out"
+        );
+        assert_eq!(escape_pattern("mixer[2]:out_$L"), "mixer\\[2\\]:out_\\$L");
+        assert_eq!(escape_pattern("C:\\out"), "C:\\\\out");
+        assert_eq!(
+            escape_pattern("a+b*c?d|e{1}^"),
+            "a\\+b\\*c\\?d\\|e\\{1\\}\\^"
+        );
+    }
+
+    /// Needs a running JACK server with system capture ports, e.g. `jackd -d dummy`.
+    #[test]
+    #[ignore]
+    fn records_from_system_capture_ports() {
+        let format = RequestedAudioFormatInfo {
+            sample_rate: None,
+            num_channels: None,
+            format: None,
+        };
+        let options = CaptureOptions {
+            direction: Direction::Capture,
+            ..CaptureOptions::default()
+        };
+        let recorder = Arc::new(JackLoopbackRecorder::create(format, options).unwrap());
+        let audio_format = recorder.get_audio_format();
+        assert_eq!(audio_format.format, SampleFormat::Float32);
+        assert!(audio_format.num_channels > 0);
+
+        let (transmitter, receiver) = mpsc::channel();
+        let capture = thread::spawn({
+            let recorder = Arc::clone(&recorder);
+            move || recorder.capture(transmitter).is_err()
+        });
+        match receiver.recv_timeout(Duration::from_secs(5)).unwrap() {
+            AudioDataMessage::AudioData(chunk) => {
+                let chunk_size = BufferConfig::default().chunk_size;
+                assert_eq!(
+                    chunk.data.len(),
+                    audio_format.block_alignment() as usize * chunk_size
+                );
+            }
+            _ => panic!("Expected audio data"),
+        }
+        // Capture stops with an error once the receiver is gone.
+        drop(receiver);
+        assert!(capture.join().unwrap());
+    }
+}
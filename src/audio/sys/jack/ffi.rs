@@ -0,0 +1,89 @@
+//! Bindings for the small subset of `libjack` used by the JACK recorder. The library is loaded at
+//! runtime, so the application can still start on systems without JACK installed.
+use std::ffi::{c_char, c_int, c_ulong, c_void};
+
+use libloading::Library;
+
+use crate::Res;
+
+pub const NO_START_SERVER: c_int = 0x01;
+
+pub const PORT_IS_INPUT: c_ulong = 0x1;
+pub const PORT_IS_OUTPUT: c_int = 0x2;
+pub const PORT_IS_PHYSICAL: c_int = 0x4;
+
+pub const DEFAULT_AUDIO_TYPE: &std::ffi::CStr = c"32 bit float mono audio";
+
+pub type ProcessCallback = extern "C" fn(u32, *mut c_void) -> c_int;
+pub type ShutdownCallback = extern "C" fn(*mut c_void);
+
+/// Copy a function pointer out of a loaded library.
+///
+/// # Safety
+/// `T` must match the signature of the named symbol.
+unsafe fn symbol<T: Copy>(lib: &Library, name: &[u8]) -> Res<T> {
+    Ok(*lib.get::<T>(name)?)
+}
+
+/// Functions from `libjack`. All `jack_client_t` and `jack_port_t` handles are opaque.
+pub struct Jack {
+    _lib: Library,
+    pub client_open: unsafe extern "C" fn(*const c_char, c_int, *mut c_int, ...) -> *mut c_void,
+    pub client_close: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub get_sample_rate: unsafe extern "C" fn(*mut c_void) -> u32,
+    pub get_buffer_size: unsafe extern "C" fn(*mut c_void) -> u32,
+    pub port_register: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        *const c_char,
+        c_ulong,
+        c_ulong,
+    ) -> *mut c_void,
+    pub port_name: unsafe extern "C" fn(*mut c_void) -> *const c_char,
+    pub port_by_name: unsafe extern "C" fn(*mut c_void, *const c_char) -> *mut c_void,
+    pub port_flags: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub port_get_buffer: unsafe extern "C" fn(*mut c_void, u32) -> *mut c_void,
+    pub port_get_all_connections:
+        unsafe extern "C" fn(*mut c_void, *mut c_void) -> *mut *const c_char,
+    pub get_ports: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        *const c_char,
+        c_ulong,
+    ) -> *mut *const c_char,
+    pub connect: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int,
+    pub set_process_callback:
+        unsafe extern "C" fn(*mut c_void, ProcessCallback, *mut c_void) -> c_int,
+    pub on_shutdown: unsafe extern "C" fn(*mut c_void, ShutdownCallback, *mut c_void),
+    pub activate: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub deactivate: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub free: unsafe extern "C" fn(*mut c_void),
+}
+
+impl Jack {
+    pub fn load() -> Res<Self> {
+        unsafe {
+            let lib = Library::new("libjack.so.0")?;
+            Ok(Jack {
+                client_open: symbol(&lib, b"jack_client_open\0")?,
+                client_close: symbol(&lib, b"jack_client_close\0")?,
+                get_sample_rate: symbol(&lib, b"jack_get_sample_rate\0")?,
+                get_buffer_size: symbol(&lib, b"jack_get_buffer_size\0")?,
+                port_register: symbol(&lib, b"jack_port_register\0")?,
+                port_name: symbol(&lib, b"jack_port_name\0")?,
+                port_by_name: symbol(&lib, b"jack_port_by_name\0")?,
+                port_flags: symbol(&lib, b"jack_port_flags\0")?,
+                port_get_buffer: symbol(&lib, b"jack_port_get_buffer\0")?,
+                port_get_all_connections: symbol(&lib, b"jack_port_get_all_connections\0")?,
+                get_ports: symbol(&lib, b"jack_get_ports\0")?,
+                connect: symbol(&lib, b"jack_connect\0")?,
+                set_process_callback: symbol(&lib, b"jack_set_process_callback\0")?,
+                on_shutdown: symbol(&lib, b"jack_on_shutdown\0")?,
+                activate: symbol(&lib, b"jack_activate\0")?,
+                deactivate: symbol(&lib, b"jack_deactivate\0")?,
+                free: symbol(&lib, b"jack_free\0")?,
+                _lib: lib,
+            })
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use std::{error::Error, fmt::Display};
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+
+use crate::{Nothing, Res};
+
+use crate::audio::layout::{ChannelLayout, Speaker};
+use crate::audio::negotiation::SupportedFormats;
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    DeviceInfo, Direction, RequestedAudioFormatInfo, SampleFormat,
+};
+
+use ffi::{
+    BufferAttr, ChannelMap, Pulse, PulseSimple, SampleSpec, ServerInfo, SinkInfo, SourceInfo,
+};
+
+mod ffi;
+
+/// Application name reported to the sound server.
+const CLIENT_NAME: &CStr = c"wavrec";
+
+/// Stream name reported to the sound server.
+const STREAM_NAME: &CStr = c"Loopback capture";
+
+/// Special source name which always refers to the monitor of the default sink.
+const DEFAULT_MONITOR: &str = "@DEFAULT_MONITOR@";
+
+/// Special sink name which always refers to the default sink.
+const DEFAULT_SINK: &CStr = c"@DEFAULT_SINK@";
+
+/// Special source name which always refers to the default source.
+const DEFAULT_SOURCE: &CStr = c"@DEFAULT_SOURCE@";
+
+/// How often to check whether the default device has changed, while recording it.
+const DEFAULT_DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The `pa_channel_position_t` of each speaker position.
+const CHANNEL_POSITIONS: [(Speaker, c_int); 18] = [
+    (Speaker::FrontLeft, 1),
+    (Speaker::FrontRight, 2),
+    (Speaker::FrontCenter, 3),
+    (Speaker::BackCenter, 4),
+    (Speaker::BackLeft, 5),
+    (Speaker::BackRight, 6),
+    (Speaker::LowFrequency, 7),
+    (Speaker::FrontLeftOfCenter, 8),
+    (Speaker::FrontRightOfCenter, 9),
+    (Speaker::SideLeft, 10),
+    (Speaker::SideRight, 11),
+    (Speaker::TopCenter, 44),
+    (Speaker::TopFrontLeft, 45),
+    (Speaker::TopFrontRight, 46),
+    (Speaker::TopFrontCenter, 47),
+    (Speaker::TopBackLeft, 48),
+    (Speaker::TopBackRight, 49),
+    (Speaker::TopBackCenter, 50),
+];
+
+#[derive(Debug)]
+enum PulseError {
+    ServerUnavailable,
+    ServerQueryFailed,
+    UnsupportedFormat(SampleFormat),
+    StreamFailed(String),
+}
+
+impl Error for PulseError {}
+
+impl Display for PulseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PulseError::ServerUnavailable => {
+                write!(f, "Failed to connect to the PulseAudio server")
+            }
+            PulseError::ServerQueryFailed => write!(f, "Failed to query the default device"),
+            PulseError::UnsupportedFormat(format) => {
+                write!(f, "Sample format {format} is not supported by PulseAudio")
+            }
+            PulseError::StreamFailed(reason) => {
+                write!(f, "Failed to open PulseAudio record stream: {reason}")
+            }
+        }
+    }
+}
+
+/// Loopback recorder for Linux systems running PulseAudio, or PipeWire with its PulseAudio
+/// compatibility layer. Records from the `.monitor` source of the default sink, or from the default
+/// source when recording an input device, unless another source was requested.
+///
+/// Unlike WASAPI loopback, the monitor source keeps producing (silent) audio while nothing is
+/// playing.
+pub struct PulseLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+
+    lib: PulseSimple,
+
+    /// Asynchronous API, used to watch the default device during capture.
+    pulse: Pulse,
+
+    direction: Direction,
+
+    /// Name of the default sink or source when the stream was opened, if recording the default
+    /// device.
+    default_device: Option<String>,
+
+    /// Handle to the `pa_simple` record stream.
+    stream: *mut c_void,
+}
+
+unsafe impl Send for PulseLoopbackRecorder {}
+unsafe impl Sync for PulseLoopbackRecorder {}
+
+impl AudioLoopback for PulseLoopbackRecorder {
+    /// Create a new PulseAudio-based [`AudioLoopback`] recorder.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<PulseLoopbackRecorder> {
+        debug!("Initializing PulseAudio");
+        let lib = PulseSimple::load()?;
+        let pulse = Pulse::load()?;
+        let (default_spec, default_map) =
+            get_spec(&pulse, options.direction, options.device.as_deref())?;
+        let default_device = match options.device {
+            Some(_) => None,
+            None => Some(get_default_device_name(&pulse, options.direction)?),
+        };
+
+        let sample_format = format.format.unwrap_or_else(|| {
+            sample_format_from_pulse(default_spec.format).unwrap_or(SampleFormat::Float32)
+        });
+        let sample_rate = format.sample_rate.unwrap_or(default_spec.rate);
+        let num_channels = format.num_channels.unwrap_or(default_spec.channels);
+        // Keep the device's positions when recording all of its channels, otherwise the server
+        // remixes to the usual layout for the channel count.
+        let channel_layout = match num_channels == default_spec.channels {
+            true => layout_from_pulse(&default_map),
+            false => None,
+        }
+        .unwrap_or_else(|| ChannelLayout::default_for(num_channels));
+
+        let audio_format = AudioFormatInfo {
+            sample_rate,
+            num_channels,
+            format: sample_format,
+            channel_layout,
+        };
+        // Without a position for every channel, the server picks its default map.
+        let channel_map = layout_to_pulse(channel_layout, num_channels);
+
+        let spec = SampleSpec {
+            format: sample_format_to_pulse(sample_format)
+                .ok_or(PulseError::UnsupportedFormat(sample_format))?,
+            rate: sample_rate,
+            channels: num_channels,
+        };
+
+        let chunk_size = options.buffer.chunk_size;
+        // The server buffers up to `maxlength` bytes while the stream isn't read.
+        let maxlength = options.buffer.buffer_duration.map_or(u32::MAX, |duration| {
+            let buffer_frames = (duration.as_secs_f64() * sample_rate as f64).ceil() as usize;
+            (buffer_frames.max(chunk_size) * audio_format.block_alignment() as usize) as u32
+        });
+        let attr = BufferAttr {
+            maxlength,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: (chunk_size * audio_format.block_alignment() as usize) as u32,
+        };
+
+        let source = match (options.device.clone(), options.direction) {
+            (Some(device), _) => CString::new(device)?,
+            (None, Direction::Render) => CString::new(DEFAULT_MONITOR)?,
+            (None, Direction::Capture) => DEFAULT_SOURCE.to_owned(),
+        };
+        debug!("Opening PulseAudio source: {}", source.to_string_lossy());
+
+        let mut error: c_int = 0;
+        let stream = unsafe {
+            (lib.new)(
+                ptr::null(),
+                CLIENT_NAME.as_ptr(),
+                ffi::STREAM_RECORD,
+                source.as_ptr(),
+                STREAM_NAME.as_ptr(),
+                &spec,
+                channel_map
+                    .as_ref()
+                    .map_or(ptr::null(), |map| map as *const ChannelMap),
+                &attr,
+                &mut error,
+            )
+        };
+        if stream.is_null() {
+            return Err(Box::new(PulseError::StreamFailed(describe_error(
+                &lib, error,
+            ))));
+        }
+
+        Ok(PulseLoopbackRecorder {
+            audio_format,
+            chunk_size,
+            lib,
+            pulse,
+            direction: options.direction,
+            default_device,
+            stream,
+        })
+    }
+
+    /// Check whether the PulseAudio libraries are installed, and a server is accepting
+    /// connections.
+    fn is_available() -> bool {
+        PulseSimple::load().is_ok()
+            && Pulse::load().is_ok_and(|pulse| Connection::open(&pulse).is_ok())
+    }
+
+    /// The server converts and resamples streams to the source's format, so any rate, and any
+    /// sample format PulseAudio has, can be requested.
+    fn supported_formats(options: &CaptureOptions) -> Res<SupportedFormats> {
+        let pulse = Pulse::load()?;
+        let (spec, map) = get_spec(&pulse, options.direction, options.device.as_deref())?;
+        Ok(SupportedFormats {
+            native: sample_format_from_pulse(spec.format).map(|format| AudioFormatInfo {
+                sample_rate: spec.rate,
+                num_channels: spec.channels,
+                format,
+                channel_layout: layout_from_pulse(&map)
+                    .unwrap_or_else(|| ChannelLayout::default_for(spec.channels)),
+            }),
+            sample_rates: None,
+            channels: 1..=ffi::CHANNELS_MAX as u8,
+            sample_formats: SampleFormat::value_variants()
+                .iter()
+                .copied()
+                .filter(|format| sample_format_to_pulse(*format).is_some())
+                .collect(),
+        })
+    }
+
+    /// List the sources on the server. Monitor sources record the audio played to their sink. The
+    /// id is the source name.
+    fn devices() -> Res<Vec<DeviceInfo>> {
+        extern "C" fn on_source_info(
+            _: *mut c_void,
+            info: *const SourceInfo,
+            eol: c_int,
+            userdata: *mut c_void,
+        ) {
+            if eol != 0 || info.is_null() {
+                return;
+            }
+            let devices = unsafe { &mut *(userdata as *mut Vec<DeviceInfo>) };
+            let info = unsafe { &*info };
+            let text = |s: *const c_char| match s.is_null() {
+                true => String::new(),
+                false => unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned(),
+            };
+            devices.push(DeviceInfo {
+                id: text(info.name),
+                name: text(info.description),
+                direction: match info.monitor_of_sink {
+                    ffi::INVALID_INDEX => Direction::Capture,
+                    _ => Direction::Render,
+                },
+                default_format: sample_format_from_pulse(info.sample_spec.format).map(|format| {
+                    AudioFormatInfo {
+                        sample_rate: info.sample_spec.rate,
+                        num_channels: info.sample_spec.channels,
+                        format,
+                        channel_layout: layout_from_pulse(&info.channel_map).unwrap_or_else(|| {
+                            ChannelLayout::default_for(info.sample_spec.channels)
+                        }),
+                    }
+                }),
+            });
+        }
+
+        let pulse = Pulse::load()?;
+        let connection = Connection::open(&pulse)?;
+        let mut devices: Vec<DeviceInfo> = Vec::new();
+        let operation = unsafe {
+            (pulse.context_get_source_info_list)(
+                connection.context,
+                on_source_info,
+                &mut devices as *mut Vec<DeviceInfo> as *mut c_void,
+            )
+        };
+        connection.wait_for(operation)?;
+        Ok(devices)
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Capture audio from the source. Stops with [`DeviceEvent::Changed`] when recording the
+    /// default device and the server's default changes, or [`DeviceEvent::Lost`] when the stream
+    /// fails, e.g. because the source was removed.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Starting PulseAudio capture");
+        let chunk_bytes = self.audio_format.block_alignment() as usize * self.chunk_size;
+        let mut position = 0;
+        let mut last_poll = Instant::now();
+
+        loop {
+            let mut chunk = vec![0u8; chunk_bytes];
+            let mut error: c_int = 0;
+            let result = unsafe {
+                (self.lib.read)(
+                    self.stream,
+                    chunk.as_mut_ptr().cast(),
+                    chunk.len(),
+                    &mut error,
+                )
+            };
+            if result < 0 {
+                warn!(
+                    "Failed to read from PulseAudio record stream: {}",
+                    describe_error(&self.lib, error)
+                );
+                return Ok(Some(DeviceEvent::Lost));
+            }
+
+            // The read returns as soon as the last frame is captured. The server doesn't report
+            // overruns through the simple API.
+            let timestamp = Instant::now() - self.audio_format.duration_of(self.chunk_size);
+            let chunk = AudioChunk::new(chunk, position, timestamp);
+            position += self.chunk_size as u64;
+            transmitter.send(AudioDataMessage::AudioData(chunk))?;
+
+            if let Some(device) = &self.default_device {
+                if last_poll.elapsed() >= DEFAULT_DEVICE_POLL_INTERVAL {
+                    last_poll = Instant::now();
+                    match get_default_device_name(&self.pulse, self.direction) {
+                        Ok(name) if name != *device => {
+                            info!("PulseAudio default device changed to {name}");
+                            return Ok(Some(DeviceEvent::Changed));
+                        }
+                        Ok(_) => {}
+                        Err(err) => debug!("Failed to query PulseAudio default device: {err}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PulseLoopbackRecorder {
+    fn drop(&mut self) {
+        unsafe { (self.lib.free)(self.stream) };
+    }
+}
+
+/// Map a [`SampleFormat`] to the equivalent little-endian `pa_sample_format_t`, if there is one.
+/// PulseAudio's 24 bit format in 4 bytes uses the least significant bytes, so has no equivalent.
+fn sample_format_to_pulse(format: SampleFormat) -> Option<c_int> {
+    match format {
+        SampleFormat::UInt8 => Some(ffi::SAMPLE_U8),
+        SampleFormat::Int16 => Some(ffi::SAMPLE_S16LE),
+        SampleFormat::Int24 => Some(ffi::SAMPLE_S24LE),
+        SampleFormat::Int32 => Some(ffi::SAMPLE_S32LE),
+        SampleFormat::Float32 => Some(ffi::SAMPLE_FLOAT32LE),
+        SampleFormat::Int24In32 | SampleFormat::Float64 => None,
+    }
+}
+
+/// Map a `pa_sample_format_t` to a [`SampleFormat`], if there is an equivalent.
+fn sample_format_from_pulse(format: c_int) -> Option<SampleFormat> {
+    match format {
+        ffi::SAMPLE_U8 => Some(SampleFormat::UInt8),
+        ffi::SAMPLE_S16LE => Some(SampleFormat::Int16),
+        ffi::SAMPLE_S24LE => Some(SampleFormat::Int24),
+        ffi::SAMPLE_S32LE => Some(SampleFormat::Int32),
+        ffi::SAMPLE_FLOAT32LE => Some(SampleFormat::Float32),
+        _ => None,
+    }
+}
+
+/// Map a `pa_channel_map` to a [`ChannelLayout`]. Returns `None` if a channel has a position
+/// without a WAV equivalent, e.g. an auxiliary channel, or the positions are in an order a layout
+/// can't describe.
+fn layout_from_pulse(map: &ChannelMap) -> Option<ChannelLayout> {
+    let channels = &map.map[..(map.channels as usize).min(ffi::CHANNELS_MAX)];
+    // A single mono channel is played from the centre.
+    if channels == [ffi::CHANNEL_POSITION_MONO] {
+        return Some(ChannelLayout::MONO);
+    }
+    let speakers = channels
+        .iter()
+        .map(|position| {
+            CHANNEL_POSITIONS
+                .iter()
+                .find(|(_, pulse_position)| pulse_position == position)
+                .map(|(speaker, _)| *speaker)
+        })
+        .collect::<Option<Vec<Speaker>>>()?;
+    ChannelLayout::from_speakers(&speakers)
+}
+
+/// Map a [`ChannelLayout`] to a `pa_channel_map`, if the layout has a position for every channel.
+fn layout_to_pulse(layout: ChannelLayout, num_channels: u8) -> Option<ChannelMap> {
+    if layout.num_speakers() != num_channels as usize {
+        return None;
+    }
+    let mut map = ChannelMap {
+        channels: num_channels,
+        map: [0; ffi::CHANNELS_MAX],
+    };
+    for (channel, speaker) in layout.speakers().enumerate() {
+        let (_, position) = CHANNEL_POSITIONS.iter().find(|(s, _)| *s == speaker)?;
+        map.map[channel] = *position;
+    }
+    Some(map)
+}
+
+/// Return the human readable message for a PulseAudio error code.
+fn describe_error(lib: &PulseSimple, error: c_int) -> String {
+    let message = unsafe { (lib.strerror)(error) };
+    if message.is_null() {
+        return format!("error code {error}");
+    }
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Connection to the server through the asynchronous API. Disconnects and frees the mainloop when
+/// dropped.
+struct Connection<'a> {
+    pulse: &'a Pulse,
+    mainloop: *mut c_void,
+    context: *mut c_void,
+}
+
+impl<'a> Connection<'a> {
+    fn open(pulse: &'a Pulse) -> Res<Self> {
+        let mainloop = unsafe { (pulse.mainloop_new)() };
+        if mainloop.is_null() {
+            return Err(Box::new(PulseError::ServerUnavailable));
+        }
+        let api = unsafe { (pulse.mainloop_get_api)(mainloop) };
+        let context = unsafe { (pulse.context_new)(api, CLIENT_NAME.as_ptr()) };
+        let connection = Connection {
+            pulse,
+            mainloop,
+            context,
+        };
+        if context.is_null()
+            || unsafe { (pulse.context_connect)(context, ptr::null(), 0, ptr::null()) } < 0
+        {
+            return Err(Box::new(PulseError::ServerUnavailable));
+        }
+
+        loop {
+            connection.iterate()?;
+            match unsafe { (pulse.context_get_state)(context) } {
+                ffi::CONTEXT_READY => return Ok(connection),
+                ffi::CONTEXT_FAILED | ffi::CONTEXT_TERMINATED => {
+                    return Err(Box::new(PulseError::ServerUnavailable))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Run a single blocking iteration of the mainloop.
+    fn iterate(&self) -> Nothing {
+        if unsafe { (self.pulse.mainloop_iterate)(self.mainloop, 1, ptr::null_mut()) } < 0 {
+            return Err(Box::new(PulseError::ServerUnavailable));
+        }
+        Ok(())
+    }
+
+    /// Iterate the mainloop until the given `pa_operation` has completed.
+    fn wait_for(&self, operation: *mut c_void) -> Nothing {
+        if operation.is_null() {
+            return Err(Box::new(PulseError::ServerQueryFailed));
+        }
+        let result = loop {
+            if unsafe { (self.pulse.operation_get_state)(operation) } != ffi::OPERATION_RUNNING {
+                break Ok(());
+            }
+            if let Err(err) = self.iterate() {
+                break Err(err);
+            }
+        };
+        unsafe { (self.pulse.operation_unref)(operation) };
+        result
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.context.is_null() {
+                (self.pulse.context_disconnect)(self.context);
+                (self.pulse.context_unref)(self.context);
+            }
+            (self.pulse.mainloop_free)(self.mainloop);
+        }
+    }
+}
+
+/// Query the sample spec and channel map of the given source. Without a source, queries the
+/// server's default sink, or its default source when recording an input device.
+fn get_spec(
+    pulse: &Pulse,
+    direction: Direction,
+    device: Option<&str>,
+) -> Res<(SampleSpec, ChannelMap)> {
+    extern "C" fn on_sink_info(
+        _: *mut c_void,
+        info: *const SinkInfo,
+        eol: c_int,
+        userdata: *mut c_void,
+    ) {
+        if eol == 0 && !info.is_null() {
+            store_spec(
+                unsafe { ((*info).sample_spec, (*info).channel_map) },
+                userdata,
+            );
+        }
+    }
+
+    extern "C" fn on_source_info(
+        _: *mut c_void,
+        info: *const SourceInfo,
+        eol: c_int,
+        userdata: *mut c_void,
+    ) {
+        if eol == 0 && !info.is_null() {
+            store_spec(
+                unsafe { ((*info).sample_spec, (*info).channel_map) },
+                userdata,
+            );
+        }
+    }
+
+    fn store_spec(spec: (SampleSpec, ChannelMap), userdata: *mut c_void) {
+        let result = unsafe { &mut *(userdata as *mut Option<(SampleSpec, ChannelMap)>) };
+        *result = Some(spec);
+    }
+
+    debug!("Querying PulseAudio device format");
+    let connection = Connection::open(pulse)?;
+    let mut spec: Option<(SampleSpec, ChannelMap)> = None;
+    let userdata = &mut spec as *mut Option<(SampleSpec, ChannelMap)> as *mut c_void;
+    let device = device.map(CString::new).transpose()?;
+    let operation = unsafe {
+        match (&device, direction) {
+            (Some(device), _) => (pulse.context_get_source_info_by_name)(
+                connection.context,
+                device.as_ptr(),
+                on_source_info,
+                userdata,
+            ),
+            (None, Direction::Render) => (pulse.context_get_sink_info_by_name)(
+                connection.context,
+                DEFAULT_SINK.as_ptr(),
+                on_sink_info,
+                userdata,
+            ),
+            (None, Direction::Capture) => (pulse.context_get_source_info_by_name)(
+                connection.context,
+                DEFAULT_SOURCE.as_ptr(),
+                on_source_info,
+                userdata,
+            ),
+        }
+    };
+    connection.wait_for(operation)?;
+    spec.ok_or_else(|| Box::new(PulseError::ServerQueryFailed) as Box<dyn Error>)
+}
+
+/// Query the name of the server's default sink, or its default source when recording an input
+/// device.
+fn get_default_device_name(pulse: &Pulse, direction: Direction) -> Res<String> {
+    extern "C" fn on_server_info(_: *mut c_void, info: *const ServerInfo, userdata: *mut c_void) {
+        if info.is_null() {
+            return;
+        }
+        let (direction, result) = unsafe { &mut *(userdata as *mut (Direction, Option<String>)) };
+        let name = match direction {
+            Direction::Render => unsafe { (*info).default_sink_name },
+            Direction::Capture => unsafe { (*info).default_source_name },
+        };
+        if !name.is_null() {
+            *result = Some(
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+    }
+
+    let connection = Connection::open(pulse)?;
+    let mut result: (Direction, Option<String>) = (direction, None);
+    let operation = unsafe {
+        (pulse.context_get_server_info)(
+            connection.context,
+            on_server_info,
+            &mut result as *mut (Direction, Option<String>) as *mut c_void,
+        )
+    };
+    connection.wait_for(operation)?;
+    result
+        .1
+        .ok_or_else(|| Box::new(PulseError::ServerQueryFailed) as Box<dyn Error>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_formats_round_trip() {
+        for format in SampleFormat::value_variants() {
+            match sample_format_to_pulse(*format) {
+                Some(pulse_format) => {
+                    assert_eq!(sample_format_from_pulse(pulse_format), Some(*format));
+                }
+                None => assert!(matches!(
+                    format,
+                    SampleFormat::Int24In32 | SampleFormat::Float64
+                )),
+            }
+        }
+        // `PA_SAMPLE_S16BE` has no sample format.
+        assert_eq!(sample_format_from_pulse(4), None);
+    }
+
+    #[test]
+    fn named_layouts_round_trip() {
+        for layout in [
+            ChannelLayout::MONO,
+            ChannelLayout::STEREO,
+            ChannelLayout::QUAD,
+            ChannelLayout::SURROUND_5_1,
+            ChannelLayout::SURROUND_7_1,
+        ] {
+            let num_channels = layout.num_speakers() as u8;
+            let map = layout_to_pulse(layout, num_channels).unwrap();
+            assert_eq!(map.channels, num_channels);
+            assert_eq!(layout_from_pulse(&map), Some(layout));
+        }
+    }
+
+    #[test]
+    fn layout_to_pulse_requires_a_speaker_per_channel() {
+        assert!(layout_to_pulse(ChannelLayout::STEREO, 3).is_none());
+        assert!(layout_to_pulse(ChannelLayout::UNSPECIFIED, 2).is_none());
+    }
+
+    #[test]
+    fn layout_from_pulse_reads_mono() {
+        let mut map = ChannelMap {
+            channels: 1,
+            map: [0; ffi::CHANNELS_MAX],
+        };
+        map.map[0] = ffi::CHANNEL_POSITION_MONO;
+        assert_eq!(layout_from_pulse(&map), Some(ChannelLayout::MONO));
+        // Auxiliary channels have no speaker.
+        map.map[0] = 12;
+        assert_eq!(layout_from_pulse(&map), None);
+    }
+}
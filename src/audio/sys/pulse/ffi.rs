@@ -0,0 +1,214 @@
+//! Bindings for the small subset of `libpulse` and `libpulse-simple` used by the PulseAudio
+//! recorder. The libraries are loaded at runtime, so the application can still start (and use
+//! other backends) on systems without a sound server installed.
+use std::ffi::{c_char, c_int, c_void};
+
+use libloading::Library;
+
+use crate::Res;
+
+pub const STREAM_RECORD: c_int = 2;
+
+pub const SAMPLE_U8: c_int = 0;
+pub const SAMPLE_S16LE: c_int = 3;
+pub const SAMPLE_FLOAT32LE: c_int = 5;
+pub const SAMPLE_S32LE: c_int = 7;
+pub const SAMPLE_S24LE: c_int = 9;
+
+pub const CONTEXT_READY: c_int = 4;
+pub const CONTEXT_FAILED: c_int = 5;
+pub const CONTEXT_TERMINATED: c_int = 6;
+
+pub const OPERATION_RUNNING: c_int = 0;
+
+pub const CHANNELS_MAX: usize = 32;
+
+pub const CHANNEL_POSITION_MONO: c_int = 0;
+
+pub const INVALID_INDEX: u32 = u32::MAX;
+
+/// `pa_sample_spec`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SampleSpec {
+    pub format: c_int,
+    pub rate: u32,
+    pub channels: u8,
+}
+
+/// `pa_buffer_attr`
+#[repr(C)]
+pub struct BufferAttr {
+    pub maxlength: u32,
+    pub tlength: u32,
+    pub prebuf: u32,
+    pub minreq: u32,
+    pub fragsize: u32,
+}
+
+/// `pa_channel_map`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ChannelMap {
+    pub channels: u8,
+    pub map: [c_int; CHANNELS_MAX],
+}
+
+/// `pa_cvolume`
+#[repr(C)]
+pub struct CVolume {
+    pub channels: u8,
+    pub values: [u32; CHANNELS_MAX],
+}
+
+/// Leading fields of `pa_sink_info`. Only ever read through a pointer handed to us by the server,
+/// so the remaining fields can safely be left out.
+#[repr(C)]
+pub struct SinkInfo {
+    pub name: *const c_char,
+    pub index: u32,
+    pub description: *const c_char,
+    pub sample_spec: SampleSpec,
+    pub channel_map: ChannelMap,
+    pub owner_module: u32,
+    pub volume: CVolume,
+    pub mute: c_int,
+    pub monitor_source: u32,
+    pub monitor_source_name: *const c_char,
+}
+
+pub type SinkInfoCallback = extern "C" fn(*mut c_void, *const SinkInfo, c_int, *mut c_void);
+
+/// Leading fields of `pa_source_info`, read the same way as [`SinkInfo`].
+#[repr(C)]
+pub struct SourceInfo {
+    pub name: *const c_char,
+    pub index: u32,
+    pub description: *const c_char,
+    pub sample_spec: SampleSpec,
+    pub channel_map: ChannelMap,
+    pub owner_module: u32,
+    pub volume: CVolume,
+    pub mute: c_int,
+    pub monitor_of_sink: u32,
+}
+
+pub type SourceInfoCallback = extern "C" fn(*mut c_void, *const SourceInfo, c_int, *mut c_void);
+
+/// Leading fields of `pa_server_info`, read the same way as [`SinkInfo`].
+#[repr(C)]
+pub struct ServerInfo {
+    pub user_name: *const c_char,
+    pub host_name: *const c_char,
+    pub server_version: *const c_char,
+    pub server_name: *const c_char,
+    pub sample_spec: SampleSpec,
+    pub default_sink_name: *const c_char,
+    pub default_source_name: *const c_char,
+}
+
+pub type ServerInfoCallback = extern "C" fn(*mut c_void, *const ServerInfo, *mut c_void);
+
+/// Copy a function pointer out of a loaded library.
+///
+/// # Safety
+/// `T` must match the signature of the named symbol.
+unsafe fn symbol<T: Copy>(lib: &Library, name: &[u8]) -> Res<T> {
+    Ok(*lib.get::<T>(name)?)
+}
+
+/// Functions from `libpulse-simple`, used for the blocking record stream.
+pub struct PulseSimple {
+    _lib: Library,
+    pub new: unsafe extern "C" fn(
+        *const c_char,
+        *const c_char,
+        c_int,
+        *const c_char,
+        *const c_char,
+        *const SampleSpec,
+        *const ChannelMap,
+        *const BufferAttr,
+        *mut c_int,
+    ) -> *mut c_void,
+    pub read: unsafe extern "C" fn(*mut c_void, *mut c_void, usize, *mut c_int) -> c_int,
+    pub free: unsafe extern "C" fn(*mut c_void),
+    pub strerror: unsafe extern "C" fn(c_int) -> *const c_char,
+}
+
+impl PulseSimple {
+    pub fn load() -> Res<Self> {
+        unsafe {
+            let lib = Library::new("libpulse-simple.so.0")?;
+            Ok(PulseSimple {
+                new: symbol(&lib, b"pa_simple_new\0")?,
+                read: symbol(&lib, b"pa_simple_read\0")?,
+                free: symbol(&lib, b"pa_simple_free\0")?,
+                strerror: symbol(&lib, b"pa_strerror\0")?,
+                _lib: lib,
+            })
+        }
+    }
+}
+
+/// Functions from `libpulse`, used to query the server through the asynchronous API.
+pub struct Pulse {
+    _lib: Library,
+    pub mainloop_new: unsafe extern "C" fn() -> *mut c_void,
+    pub mainloop_get_api: unsafe extern "C" fn(*mut c_void) -> *mut c_void,
+    pub mainloop_iterate: unsafe extern "C" fn(*mut c_void, c_int, *mut c_int) -> c_int,
+    pub mainloop_free: unsafe extern "C" fn(*mut c_void),
+    pub context_new: unsafe extern "C" fn(*mut c_void, *const c_char) -> *mut c_void,
+    pub context_connect:
+        unsafe extern "C" fn(*mut c_void, *const c_char, c_int, *const c_void) -> c_int,
+    pub context_get_state: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub context_disconnect: unsafe extern "C" fn(*mut c_void),
+    pub context_unref: unsafe extern "C" fn(*mut c_void),
+    pub context_get_server_info:
+        unsafe extern "C" fn(*mut c_void, ServerInfoCallback, *mut c_void) -> *mut c_void,
+    pub context_get_sink_info_by_name: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        SinkInfoCallback,
+        *mut c_void,
+    ) -> *mut c_void,
+    pub context_get_source_info_by_name: unsafe extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        SourceInfoCallback,
+        *mut c_void,
+    ) -> *mut c_void,
+    pub context_get_source_info_list:
+        unsafe extern "C" fn(*mut c_void, SourceInfoCallback, *mut c_void) -> *mut c_void,
+    pub operation_get_state: unsafe extern "C" fn(*mut c_void) -> c_int,
+    pub operation_unref: unsafe extern "C" fn(*mut c_void),
+}
+
+impl Pulse {
+    pub fn load() -> Res<Self> {
+        unsafe {
+            let lib = Library::new("libpulse.so.0")?;
+            Ok(Pulse {
+                mainloop_new: symbol(&lib, b"pa_mainloop_new\0")?,
+                mainloop_get_api: symbol(&lib, b"pa_mainloop_get_api\0")?,
+                mainloop_iterate: symbol(&lib, b"pa_mainloop_iterate\0")?,
+                mainloop_free: symbol(&lib, b"pa_mainloop_free\0")?,
+                context_new: symbol(&lib, b"pa_context_new\0")?,
+                context_connect: symbol(&lib, b"pa_context_connect\0")?,
+                context_get_state: symbol(&lib, b"pa_context_get_state\0")?,
+                context_disconnect: symbol(&lib, b"pa_context_disconnect\0")?,
+                context_unref: symbol(&lib, b"pa_context_unref\0")?,
+                context_get_server_info: symbol(&lib, b"pa_context_get_server_info\0")?,
+                context_get_sink_info_by_name: symbol(&lib, b"pa_context_get_sink_info_by_name\0")?,
+                context_get_source_info_by_name: symbol(
+                    &lib,
+                    b"pa_context_get_source_info_by_name\0",
+                )?,
+                context_get_source_info_list: symbol(&lib, b"pa_context_get_source_info_list\0")?,
+                operation_get_state: symbol(&lib, b"pa_operation_get_state\0")?,
+                operation_unref: symbol(&lib, b"pa_operation_unref\0")?,
+                _lib: lib,
+            })
+        }
+    }
+}
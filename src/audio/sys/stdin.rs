@@ -0,0 +1,158 @@
+use std::io::{self, ErrorKind, Read};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+use std::{error::Error, fmt::Display};
+
+use log::{debug, info, warn};
+
+use crate::Res;
+
+use crate::audio::layout::ChannelLayout;
+use crate::audio::{
+    AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    RequestedAudioFormatInfo,
+};
+
+#[derive(Debug)]
+enum StdinError {
+    FormatRequired,
+}
+
+impl Error for StdinError {}
+
+impl Display for StdinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            StdinError::FormatRequired => {
+                "The format, sample rate and channel count of the raw audio on stdin must be given"
+            }
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Reads raw interleaved little-endian PCM from stdin, e.g. piped from `ffmpeg`, `sox` or
+/// `arecord`. There is no header to read the format from, so the full format must be requested.
+///
+/// Capture finishes at the end of the input. A trailing partial frame is discarded.
+pub struct StdinLoopbackRecorder {
+    pub audio_format: AudioFormatInfo,
+
+    /// Numer of audio blocks to send to the [`transmitter`](std::sync::mpsc::Sender) in a single
+    /// write.
+    chunk_size: usize,
+}
+
+impl AudioLoopback for StdinLoopbackRecorder {
+    /// Create a new [`AudioLoopback`] recorder which reads raw PCM from stdin.
+    #[allow(refining_impl_trait)]
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<StdinLoopbackRecorder> {
+        let (Some(sample_rate), Some(num_channels), Some(format)) =
+            (format.sample_rate, format.num_channels, format.format)
+        else {
+            return Err(Box::new(StdinError::FormatRequired));
+        };
+
+        Ok(StdinLoopbackRecorder {
+            audio_format: AudioFormatInfo {
+                sample_rate,
+                num_channels,
+                format,
+                // Raw PCM has no channel positions, so the usual layout is assumed.
+                channel_layout: ChannelLayout::default_for(num_channels),
+            },
+            chunk_size: options.buffer.chunk_size,
+        })
+    }
+
+    /// Stdin can always be read.
+    fn is_available() -> bool {
+        true
+    }
+
+    fn get_audio_format(&self) -> AudioFormatInfo {
+        self.audio_format
+    }
+
+    /// Send the audio data from stdin, until the end of the input is reached.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+        debug!("Reading raw audio from stdin");
+        let mut stdin = io::stdin().lock();
+        let block_align = self.audio_format.block_alignment() as usize;
+        let mut frames_sent = 0;
+
+        loop {
+            let mut chunk = vec![0u8; block_align * self.chunk_size];
+            let bytes_read = read_full(&mut stdin, &mut chunk)?;
+            let whole_frames = bytes_read / block_align;
+            if bytes_read % block_align != 0 {
+                warn!(
+                    "Discarding {} bytes of incomplete frame at end of input",
+                    bytes_read % block_align
+                );
+            }
+            if whole_frames == 0 {
+                break;
+            }
+            chunk.truncate(whole_frames * block_align);
+            // There's no timing info in raw PCM, so the audio is taken to have just been captured.
+            let timestamp = Instant::now() - self.audio_format.duration_of(whole_frames);
+            let chunk = AudioChunk::new(chunk, frames_sent as u64, timestamp);
+            frames_sent += whole_frames;
+            let is_finished = whole_frames < self.chunk_size;
+
+            transmitter.send(AudioDataMessage::AudioData(chunk))?;
+            if is_finished {
+                break;
+            }
+        }
+        info!("Finished reading {frames_sent} frames from stdin");
+        Ok(None)
+    }
+}
+
+/// Read into the buffer until it's full or the end of the input is reached, and return the number
+/// of bytes read.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> Res<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buffer.len() {
+        match reader.read(&mut buffer[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Box::new(err)),
+        }
+    }
+    Ok(bytes_read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::SampleFormat;
+
+    #[test]
+    fn create_requires_full_format() {
+        let format = RequestedAudioFormatInfo {
+            sample_rate: Some(48000),
+            num_channels: None,
+            format: Some(SampleFormat::Int16),
+        };
+        assert!(StdinLoopbackRecorder::create(format, CaptureOptions::default()).is_err());
+    }
+
+    #[test]
+    fn read_full_reads_across_short_reads_until_end_of_input() {
+        let input: Vec<u8> = (0..10).collect();
+        // Chained readers return a short read at each boundary.
+        let mut reader = input[..3].chain(&input[3..5]).chain(&input[5..]);
+        let mut buffer = [0u8; 8];
+        assert_eq!(read_full(&mut reader, &mut buffer).unwrap(), 8);
+        assert_eq!(buffer, input[..8]);
+        assert_eq!(read_full(&mut reader, &mut buffer).unwrap(), 2);
+        assert_eq!(read_full(&mut reader, &mut buffer).unwrap(), 0);
+    }
+}
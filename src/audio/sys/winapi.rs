@@ -1,21 +1,29 @@
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use std::{collections::VecDeque, error::Error, fmt::Display};
 
-use log::{debug, error};
-use wasapi::{AudioClient, Direction, SampleType, ShareMode, WaveFormat};
+use log::{debug, error, info, warn};
+use wasapi::{
+    AudioClient, Device, DeviceCollection, DeviceState, Direction, SampleType, ShareMode,
+    WaveFormat,
+};
 
-use crate::{Nothing, Res};
+use crate::Res;
 
+use crate::audio::layout::ChannelLayout;
+use crate::audio::negotiation::{SupportedFormats, STANDARD_SAMPLE_RATES};
 use crate::audio::{
-    AudioDataMessage, AudioFormatInfo, AudioLoopback, RequestedAudioFormatInfo, SampleFormat,
+    self, AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions,
+    DeviceEvent, DeviceInfo, RequestedAudioFormatInfo, SampleFormat,
 };
 
-const TIMEOUT: u32 = 1000000;
+/// How often to check whether the device is still active, and whether the default device changed.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 enum WasapiError {
     InitMtaFailure,
-    InvalidBitDepth,
+    UnsupportedMixFormat,
     AudioCaptureFailed,
 }
 
@@ -25,14 +33,14 @@ impl Display for WasapiError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let message = match self {
             WasapiError::InitMtaFailure => "Failed to initialize WASAPI MTA",
-            WasapiError::InvalidBitDepth => "Invalid bit depth requested",
+            WasapiError::UnsupportedMixFormat => "The device mix format is not supported",
             WasapiError::AudioCaptureFailed => "Audio capture failed",
         };
         write!(f, "{}", message)
     }
 }
 
-/// Loopback recorder for Windows.
+/// Loopback recorder for Windows. Can also record from a capture device, such as a microphone.
 pub struct WasapiLoopbackRecorder {
     pub audio_format: AudioFormatInfo,
 
@@ -42,8 +50,21 @@ pub struct WasapiLoopbackRecorder {
     /// write.
     chunk_size: usize,
 
-    /// WASAPI [`AudioClient`] for the rendering device,
+    /// How long to wait for the next audio event, in milliseconds. Loopback capture gets no events
+    /// while nothing is playing, so this is also how often the device is checked when idle.
+    timeout: u32,
+
+    /// WASAPI [`AudioClient`] for the rendering or capture device,
     client: AudioClient,
+
+    /// The endpoint being recorded.
+    device: Device,
+
+    device_direction: Direction,
+
+    /// Whether the system default endpoint is being recorded, so changes to the default should be
+    /// followed.
+    is_default_device: bool,
 }
 
 unsafe impl Send for WasapiLoopbackRecorder {}
@@ -52,19 +73,24 @@ unsafe impl Sync for WasapiLoopbackRecorder {}
 impl AudioLoopback for WasapiLoopbackRecorder {
     /// Create a new WASAPI-based [`AudioLoopback`] recorder.
     #[allow(refining_impl_trait)]
-    fn create(format: RequestedAudioFormatInfo) -> Res<WasapiLoopbackRecorder> {
+    fn create(
+        format: RequestedAudioFormatInfo,
+        options: CaptureOptions,
+    ) -> Res<WasapiLoopbackRecorder> {
         debug!("Initializing WASAPI");
-        if wasapi::initialize_mta().ok().is_err() {
-            return Err(Box::new(WasapiError::InitMtaFailure));
-        };
-
-        let rendering_device = wasapi::get_default_device(&Direction::Render)?;
-        let mut client = rendering_device.get_iaudioclient()?;
+        let (device, device_direction) = open_device(&options)?;
+        let is_default_device = options.device.is_none();
+        let mut client = device.get_iaudioclient()?;
 
         let default_format = client.get_mixformat()?;
-        let bit_depth = format
-            .bit_depth()
-            .unwrap_or(default_format.get_bitspersample() as u8);
+        let sample_format = match format.format {
+            Some(sample_format) => sample_format,
+            None => {
+                audio_format_from_wasapi(&default_format)
+                    .ok_or(WasapiError::UnsupportedMixFormat)?
+                    .format
+            }
+        };
         let sample_rate = format
             .sample_rate
             .unwrap_or(default_format.get_samplespersec());
@@ -72,52 +98,111 @@ impl AudioLoopback for WasapiLoopbackRecorder {
             .num_channels
             .unwrap_or(default_format.get_nchannels() as u8);
 
-        let sample_type = match format.format {
-            Some(SampleFormat::Int16) | Some(SampleFormat::Int24) | Some(SampleFormat::Int32) => {
-                &SampleType::Int
-            }
-            Some(SampleFormat::Float32) => &SampleType::Float,
-            _ => &default_format.get_subformat()?,
+        let sample_type = match sample_format.type_format_header() {
+            3 => &SampleType::Float,
+            _ => &SampleType::Int,
+        };
+
+        // Keep the mix format's speaker positions when recording all of its channels.
+        let channel_layout = match num_channels as u16 == default_format.get_nchannels() {
+            true => ChannelLayout::from_mask(default_format.get_dwchannelmask()),
+            false => ChannelLayout::default_for(num_channels),
         };
 
         let audio_format = AudioFormatInfo {
             sample_rate,
             num_channels,
-            format: match sample_type {
-                SampleType::Float => SampleFormat::Float32,
-                SampleType::Int => match bit_depth {
-                    16 => SampleFormat::Int16,
-                    24 => SampleFormat::Int24,
-                    32 => SampleFormat::Int32,
-                    _ => return Err(Box::new(WasapiError::InvalidBitDepth)),
-                },
-            },
+            format: sample_format,
+            channel_layout,
         };
 
+        // 24 bit samples in 32 bit containers have fewer valid bits than stored bits.
         let wasapi_format = WaveFormat::new(
-            bit_depth as usize,
-            bit_depth as usize,
+            sample_format.container_bits() as usize,
+            sample_format.bit_depth() as usize,
             sample_type,
             sample_rate as usize,
             num_channels as usize,
-            None,
+            Some(channel_layout.mask()),
         );
 
+        // Buffer durations are in 100ns units. Shorter buffers than the device minimum are
+        // rejected.
         let (_, min_time) = client.get_periods()?;
+        let buffer_time = options.buffer.buffer_duration.map_or(min_time, |duration| {
+            ((duration.as_nanos() / 100) as i64).max(min_time)
+        });
         client.initialize_client(
             &wasapi_format,
-            min_time,
+            buffer_time,
             &Direction::Capture,
             &ShareMode::Shared,
             true,
         )?;
 
-        let chunk_size = 4096;
         Ok(WasapiLoopbackRecorder {
             audio_format,
             wasapi_format,
-            chunk_size,
+            chunk_size: options.buffer.chunk_size,
+            timeout: options.buffer.timeout.as_millis().min(u32::MAX as u128) as u32,
             client,
+            device,
+            device_direction,
+            is_default_device,
+        })
+    }
+
+    /// WASAPI is part of every supported version of Windows.
+    fn is_available() -> bool {
+        true
+    }
+
+    /// List the active render and capture endpoints. The id is the endpoint ID string.
+    fn devices() -> Res<Vec<DeviceInfo>> {
+        if wasapi::initialize_mta().ok().is_err() {
+            return Err(Box::new(WasapiError::InitMtaFailure));
+        };
+
+        let mut devices = Vec::new();
+        for (direction, device_direction) in [
+            (audio::Direction::Render, Direction::Render),
+            (audio::Direction::Capture, Direction::Capture),
+        ] {
+            for device in &DeviceCollection::new(&device_direction)? {
+                let device = device?;
+                let default_format = device
+                    .get_iaudioclient()
+                    .and_then(|client| client.get_mixformat())
+                    .ok()
+                    .and_then(|format| audio_format_from_wasapi(&format));
+                devices.push(DeviceInfo {
+                    id: device.get_id()?,
+                    name: device.get_friendlyname()?,
+                    direction,
+                    default_format,
+                });
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Shared mode streams are converted to and from the mix format by the audio engine, so any
+    /// standard sample rate, and any sample format other than 64 bit float, can be requested.
+    fn supported_formats(options: &CaptureOptions) -> Res<SupportedFormats> {
+        let (device, _) = open_device(options)?;
+        let mix_format = device.get_iaudioclient()?.get_mixformat()?;
+        Ok(SupportedFormats {
+            native: audio_format_from_wasapi(&mix_format),
+            sample_rates: Some(STANDARD_SAMPLE_RATES.to_vec()),
+            channels: 1..=8,
+            sample_formats: vec![
+                SampleFormat::UInt8,
+                SampleFormat::Int16,
+                SampleFormat::Int24,
+                SampleFormat::Int24In32,
+                SampleFormat::Int32,
+                SampleFormat::Float32,
+            ],
         })
     }
 
@@ -125,8 +210,10 @@ impl AudioLoopback for WasapiLoopbackRecorder {
         self.audio_format
     }
 
-    /// Capture audio from the loopback stream.
-    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Nothing {
+    /// Capture audio from the loopback or capture stream. Stops with a [`DeviceEvent`] when the
+    /// endpoint is removed, its stream is invalidated, or the default endpoint changes while it's
+    /// being recorded.
+    fn capture(&self, transmitter: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
         debug!("Preparing WASAPI loopback capture");
 
         let block_align = self.wasapi_format.get_blockalign();
@@ -139,36 +226,133 @@ impl AudioLoopback for WasapiLoopbackRecorder {
             100 * block_align as usize * (1024 + 2 * buffer_frame_count as usize),
         );
         self.client.start_stream()?;
+        let mut position = 0;
+        let mut discontinuity = false;
+        let mut last_device_check = Instant::now();
 
-        'capture: loop {
+        loop {
             while sample_queue.len() > block_align as usize * self.chunk_size {
+                // The newest frame in the queue was captured just now.
+                let queued_frames = sample_queue.len() / block_align as usize;
+                let timestamp = Instant::now() - self.audio_format.duration_of(queued_frames);
                 let mut chunk = vec![0u8; block_align as usize * self.chunk_size];
                 for e in chunk.iter_mut() {
                     match sample_queue.pop_front() {
                         // Successfully read the next sample, save it to the chunk.
                         Some(value) => *e = value,
-                        // Otherwise, signal the error state and shut down the audio loop.
+                        // Otherwise, stop the stream and report the error.
                         None => {
                             error!("Failed to read next sample from audio device");
-                            let message =
-                                AudioDataMessage::Error(Box::new(WasapiError::AudioCaptureFailed));
-                            transmitter.send(message)?;
                             self.client.stop_stream()?;
-                            break 'capture;
+                            return Err(Box::new(WasapiError::AudioCaptureFailed));
                         }
                     };
                 }
 
+                let chunk = AudioChunk {
+                    discontinuity,
+                    ..AudioChunk::new(chunk, position, timestamp)
+                };
+                position += self.chunk_size as u64;
+                discontinuity = false;
                 transmitter.send(AudioDataMessage::AudioData(chunk))?;
             }
 
-            capture_client.read_from_device_to_deque(&mut sample_queue)?;
-            if event_handle.wait_for_event(TIMEOUT).is_err() {
-                error!("WASAPI timed out waiting for next audio event");
-                self.client.stop_stream()?;
-                break;
+            match capture_client.read_from_device_to_deque(&mut sample_queue) {
+                Ok(flags) if flags.data_discontinuity => {
+                    warn!("WASAPI reported a glitch in the audio stream");
+                    discontinuity = true;
+                }
+                Ok(_) => {}
+                // The stream is invalidated when the endpoint is removed, or its format changes.
+                Err(err) => {
+                    warn!("WASAPI capture stream was invalidated: {err}");
+                    let _ = self.client.stop_stream();
+                    return Ok(Some(DeviceEvent::Lost));
+                }
+            }
+            let is_timed_out = event_handle.wait_for_event(self.timeout).is_err();
+            if is_timed_out || last_device_check.elapsed() >= DEVICE_POLL_INTERVAL {
+                last_device_check = Instant::now();
+                if let Some(event) = self.check_device()? {
+                    self.client.stop_stream()?;
+                    return Ok(Some(event));
+                }
             }
         }
-        Ok(())
     }
 }
+
+impl WasapiLoopbackRecorder {
+    /// Check whether the endpoint is still active and, when recording the default endpoint,
+    /// whether it's still the default.
+    fn check_device(&self) -> Res<Option<DeviceEvent>> {
+        let state = self.device.get_state()?;
+        if state != DeviceState::Active {
+            warn!("WASAPI endpoint is no longer active: {state}");
+            return Ok(Some(DeviceEvent::Lost));
+        }
+        if self.is_default_device {
+            let default_device = wasapi::get_default_device(&self.device_direction)?;
+            if default_device.get_id()? != self.device.get_id()? {
+                info!(
+                    "WASAPI default endpoint changed to {}",
+                    default_device.get_friendlyname()?
+                );
+                return Ok(Some(DeviceEvent::Changed));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Initialize WASAPI and open the endpoint selected by the options. Capturing from a render device
+/// opens it in loopback mode.
+fn open_device(options: &CaptureOptions) -> Res<(Device, Direction)> {
+    if wasapi::initialize_mta().ok().is_err() {
+        return Err(Box::new(WasapiError::InitMtaFailure));
+    };
+
+    let device_direction = match options.direction {
+        audio::Direction::Render => Direction::Render,
+        audio::Direction::Capture => Direction::Capture,
+    };
+    let device = match &options.device {
+        Some(device) => find_device(&device_direction, device)?,
+        None => wasapi::get_default_device(&device_direction)?,
+    };
+    Ok((device, device_direction))
+}
+
+/// Find an endpoint by its ID, or by its friendly name.
+fn find_device(direction: &Direction, device: &str) -> Res<Device> {
+    let collection = DeviceCollection::new(direction)?;
+    for endpoint in &collection {
+        let endpoint = endpoint?;
+        if endpoint.get_id()? == device {
+            return Ok(endpoint);
+        }
+    }
+    Ok(collection.get_device_with_name(device)?)
+}
+
+/// Map a WASAPI mix format to the equivalent [`AudioFormatInfo`], if it's a supported format.
+fn audio_format_from_wasapi(format: &WaveFormat) -> Option<AudioFormatInfo> {
+    let bits = (format.get_bitspersample(), format.get_validbitspersample());
+    let sample_format = match (format.get_subformat().ok()?, bits) {
+        (SampleType::Float, (32, _)) => SampleFormat::Float32,
+        (SampleType::Float, (64, _)) => SampleFormat::Float64,
+        (SampleType::Int, (8, _)) => SampleFormat::UInt8,
+        (SampleType::Int, (16, _)) => SampleFormat::Int16,
+        (SampleType::Int, (24, _)) => SampleFormat::Int24,
+        (SampleType::Int, (32, 24)) => SampleFormat::Int24In32,
+        (SampleType::Int, (32, _)) => SampleFormat::Int32,
+        _ => return None,
+    };
+    Some(AudioFormatInfo {
+        sample_rate: format.get_samplespersec(),
+        num_channels: format.get_nchannels() as u8,
+        format: sample_format,
+        channel_layout: ChannelLayout::from_mask(format.get_dwchannelmask()),
+    })
+}
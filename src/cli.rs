@@ -1,7 +1,14 @@
-use clap::{Parser, ValueEnum};
+use std::time::Duration;
+
+use clap::{builder::PossibleValuesParser, value_parser, Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 
-use crate::audio::SampleFormat;
+use crate::audio::dither::Dither;
+use crate::audio::mix::ChannelMix;
+use crate::audio::negotiation::FormatPolicy;
+use crate::audio::resample::ResampleQuality;
+use crate::audio::{sys, BufferConfig, SampleFormat};
+use crate::wave::HeaderFormat;
 
 #[derive(ValueEnum, Clone, Copy)]
 pub enum LogLevel {
@@ -13,15 +20,30 @@ pub enum LogLevel {
     Trace,
 }
 
+/// Commands other than recording.
+#[derive(Subcommand, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// List the devices each available backend can record from, or only those of `--backend`.
+    Devices,
+}
+
 /// Command line arguments, courtesty of [`clap`]. Implements [`clap::Parser`].
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Args {
-    /// The file name to write to. Extension `.wav` will be appended if not specified.
-    file_name: String,
+    /// The file name to write to. Extension `.wav` will be appended if not specified. Only
+    /// optional when a [`Command`] is given.
+    #[arg(required = true)]
+    file_name: Option<String>,
+
+    /// Run a command instead of recording.
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
     /// Sample format to write. Supports signed integer and float audio of various bit depths.
     /// This value will be requested from the audio device, and will determine the format of the
-    /// output WAV file.
+    /// output WAV file, unless `--output-format` is given. Must be given for the `stdin` backend,
+    /// along with the sample rate and channel count.
     #[arg(short, long, help = "Sample format to use (float/int and bit depth)")]
     pub format: Option<SampleFormat>,
 
@@ -30,6 +52,7 @@ pub struct Args {
     #[arg(
         short,
         long,
+        value_parser = value_parser!(u32).range(1..),
         help = "Sample rate to request from audio device and write to file"
     )]
     pub sample_rate: Option<u32>,
@@ -39,6 +62,171 @@ pub struct Args {
     #[arg(short, long, help = "Number of channels to capture")]
     pub channels: Option<u8>,
 
+    /// What to do when the device doesn't support the requested format. `exact` fails, `nearest`
+    /// records in the closest supported format, and `convert` records in the device's native
+    /// sample format and converts to the requested sample format in software. The negotiated
+    /// format, and the reasons for any changes, are reported before recording starts.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = FormatPolicy::Nearest,
+        help = "How to handle formats the device doesn't support"
+    )]
+    pub format_policy: FormatPolicy,
+
+    /// Sample format to write to the file, when it should differ from the captured sample format,
+    /// e.g. to capture float from the mixer but archive 24 bit integers. The captured audio is
+    /// converted in software, clipping samples out of the range of integer formats. Defaults to
+    /// the captured sample format, or the requested format with `--format-policy convert`.
+    #[arg(long, help = "Sample format to write to file, converting if needed")]
+    pub output_format: Option<SampleFormat>,
+
+    /// Sample rate to write to the file, when it should differ from the captured sample rate, e.g.
+    /// to archive everything at 48 kHz whatever rate the mixer runs at. The captured audio is
+    /// resampled in software. Defaults to the captured sample rate, or the requested sample rate
+    /// with `--format-policy convert`.
+    #[arg(
+        long,
+        value_parser = value_parser!(u32).range(1..),
+        help = "Sample rate to write to file, resampling if needed"
+    )]
+    pub output_rate: Option<u32>,
+
+    /// How to interpolate the audio when resampling. `linear` is the cheapest, `sinc` the most
+    /// accurate, and `polyphase` nearly as accurate as `sinc`, and much faster.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ResampleQuality::Polyphase,
+        help = "Resampling quality"
+    )]
+    pub resample_quality: ResampleQuality,
+
+    /// How to mix the captured channels into the channels written to file. `mono` averages every
+    /// channel. `stereo` downmixes with the standard coefficients for the speaker positions, e.g.
+    /// 5.1 loopback to stereo, and copies mono to both sides. `pick:<channels>` keeps the listed
+    /// channels, numbered from 1, in the order given, e.g. `pick:3-4` or `pick:2,1`.
+    /// `matrix:<rows>` applies a matrix with a row of coefficients for each written channel, and a
+    /// column for each captured channel, e.g. `matrix:1,0,0.5;0,1,0.5`.
+    #[arg(
+        long,
+        help = "Channel mix: mono, stereo, pick:<channels> or matrix:<rows>"
+    )]
+    pub channel_mix: Option<ChannelMix>,
+
+    /// How to dither when the written bit depth is lower than the captured one, e.g. capturing
+    /// float and writing 16 bit integers. Dithering replaces the distortion of rounding quiet
+    /// material with a constant noise floor, and noise shaping moves that noise to frequencies
+    /// where it's heard less. The noise is seeded, so the same audio always gives the same file.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Dither::None,
+        help = "Dither when reducing bit depth"
+    )]
+    pub dither: Dither,
+
+    /// The audio backend to capture with. Only backends compiled into this build can be
+    /// selected. Uses the first backend available on this system if not specified.
+    #[arg(
+        short,
+        long,
+        value_parser = PossibleValuesParser::new(sys::backend_names()),
+        help = "Audio backend to capture with"
+    )]
+    pub backend: Option<String>,
+
+    /// The device to capture from, as listed by the `devices` command: either its id, or part of
+    /// its name. Otherwise, this is passed to the backend as is: the source name for PulseAudio,
+    /// the port name for JACK, the PCM name for ALSA (e.g. `plughw:1,0`), or the WAV file to
+    /// replay for the `file` backend. For the `generator` backend, this is the test signal to
+    /// generate, e.g. `sine:440`, `sweep`, `pink` or `silence`. Uses the system default device if
+    /// not specified.
+    #[arg(short, long, help = "Id or name of the device to capture from")]
+    pub device: Option<String>,
+
+    /// Record from an input device, such as a microphone or line input, instead of the audio
+    /// output. `--device` then names the input device to use.
+    #[arg(
+        short,
+        long,
+        help = "Record from an input device instead of the audio output"
+    )]
+    pub input: bool,
+
+    /// JACK ports to record from, as regular expressions matched against full port names. Output
+    /// ports are recorded directly. Input ports, such as `system:playback_1`, are recorded by
+    /// connecting to every port feeding them. Each matching port is recorded to its own channel.
+    /// Can be given multiple times. Defaults to the system playback ports.
+    #[arg(short, long = "port", help = "JACK ports to record from (regex)")]
+    pub ports: Vec<String>,
+
+    /// Replay files and generate test signals at real-time speed. By default, the `file` and
+    /// `generator` backends send the audio as fast as possible.
+    #[arg(short, long, help = "Replay files and test signals at real-time speed")]
+    pub realtime: bool,
+
+    /// Stop recording once this many seconds of audio have been written. Records until
+    /// terminated if not specified.
+    #[arg(
+        short = 't',
+        long,
+        value_parser = parse_duration,
+        help = "Number of seconds to record"
+    )]
+    pub duration: Option<f64>,
+
+    /// Fill gaps in the audio stream with silence, so the recording keeps in step with the wall
+    /// clock. Loopback capture stops while nothing is playing, which otherwise makes recordings
+    /// shorter than the session.
+    #[arg(
+        short = 'g',
+        long,
+        help = "Fill gaps when nothing is playing with silence"
+    )]
+    pub fill_gaps: bool,
+
+    /// Number of frames in each chunk of audio passed from the capture thread to the writer. Small
+    /// chunks give lower latency, e.g. for live metering. Large chunks are more efficient for long
+    /// recordings. Defaults to 4096.
+    #[arg(
+        long,
+        value_parser = value_parser!(u32).range(1..),
+        help = "Number of frames in each chunk of captured audio"
+    )]
+    pub chunk_size: Option<u32>,
+
+    /// How much audio the device or sound server buffers between reads, in milliseconds. Larger
+    /// buffers are less likely to overrun on a busy system. Uses the backend default if not
+    /// specified (the minimum period for WASAPI).
+    #[arg(
+        long,
+        value_parser = value_parser!(u32).range(1..),
+        help = "Device buffer length in milliseconds"
+    )]
+    pub buffer_ms: Option<u32>,
+
+    /// How long to wait for audio, in milliseconds, before checking whether the device is still
+    /// available. Defaults to 2000.
+    #[arg(
+        long,
+        value_parser = value_parser!(u32).range(1..),
+        help = "Milliseconds to wait for audio before checking the device"
+    )]
+    pub timeout_ms: Option<u32>,
+
+    /// Which WAV header to write. `auto` writes a `WAVE_FORMAT_EXTENSIBLE` header, with the channel
+    /// positions and valid bits per sample, when the format needs one: more than 2 channels, or
+    /// integer samples of more than 16 bits. `classic` always writes the original 16 byte header,
+    /// for older tools which don't read extensible headers.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HeaderFormat::Auto,
+        help = "WAV header layout to write"
+    )]
+    pub wav_header: HeaderFormat,
+
     /// The log level. `Off` to disable, `Trace` is the most  granular.
     /// Corresponds to [`log::LevelFilter`] values.
     #[arg(short, long, default_value = "info", help = "The logging level to use")]
@@ -47,11 +235,27 @@ pub struct Args {
 
 impl Args {
     /// Get the file name to write to. If file name is missing extension, it will be appended here.
+    /// Empty when a [`Command`] was given instead.
     pub fn file_name(&self) -> String {
-        if !self.file_name.ends_with(".wav") {
-            return format!("{}.wav", &self.file_name[..]);
+        let file_name = self.file_name.as_deref().unwrap_or_default();
+        if !file_name.ends_with(".wav") && !file_name.is_empty() {
+            return format!("{}.wav", file_name);
         };
-        self.file_name.clone()
+        file_name.to_owned()
+    }
+
+    /// Get the buffering settings, with defaults for the options which weren't given.
+    pub fn buffer_config(&self) -> BufferConfig {
+        let defaults = BufferConfig::default();
+        BufferConfig {
+            chunk_size: self
+                .chunk_size
+                .map_or(defaults.chunk_size, |frames| frames as usize),
+            buffer_duration: self.buffer_ms.map(|ms| Duration::from_millis(ms as u64)),
+            timeout: self
+                .timeout_ms
+                .map_or(defaults.timeout, |ms| Duration::from_millis(ms as u64)),
+        }
     }
 
     /// Map the log level config property to a [`log::LevelFilter`] value.
@@ -67,6 +271,14 @@ impl Args {
     }
 }
 
+/// Parse a duration in seconds, which must be positive.
+fn parse_duration(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+        _ => Err(format!("'{value}' is not a positive number of seconds")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -75,10 +287,28 @@ mod tests {
     #[test]
     fn test_file_name_without_extension_is_modified() {
         let args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Info,
         };
 
@@ -88,10 +318,28 @@ mod tests {
     #[test]
     fn test_file_name_with_extension_is_unchanged() {
         let args = Args {
-            file_name: String::from("somefile.wav"),
+            file_name: Some(String::from("somefile.wav")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Info,
         };
 
@@ -101,18 +349,54 @@ mod tests {
     #[test]
     fn test_including_extension_is_optional() {
         let args_1 = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Info,
         };
 
         let args_2 = Args {
-            file_name: String::from("somefile.wav"),
+            file_name: Some(String::from("somefile.wav")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Info,
         };
 
@@ -122,50 +406,158 @@ mod tests {
     #[test]
     fn test_log_level_returns_correct_level_filter() {
         let off_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Off,
         };
 
         let error_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Error,
         };
 
         let warn_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Warn,
         };
 
         let info_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Info,
         };
 
         let debug_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Debug,
         };
 
         let trace_level_args = Args {
-            file_name: String::from("somefile"),
+            file_name: Some(String::from("somefile")),
+            command: None,
             format: None,
             sample_rate: None,
             channels: None,
+            format_policy: FormatPolicy::Nearest,
+            output_format: None,
+            output_rate: None,
+            resample_quality: ResampleQuality::Polyphase,
+            channel_mix: None,
+            dither: Dither::None,
+            backend: None,
+            device: None,
+            input: false,
+            ports: vec![],
+            realtime: false,
+            duration: None,
+            fill_gaps: false,
+            chunk_size: None,
+            buffer_ms: None,
+            timeout_ms: None,
+            wav_header: HeaderFormat::Auto,
             log_level: LogLevel::Trace,
         };
 
@@ -176,4 +568,136 @@ mod tests {
         assert_eq!(debug_level_args.log_level(), LevelFilter::Debug);
         assert_eq!(trace_level_args.log_level(), LevelFilter::Trace);
     }
+
+    #[test]
+    fn test_duration_must_be_positive() {
+        assert_eq!(parse_duration("2.5"), Ok(2.5));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("inf").is_err());
+        assert!(parse_duration("ten").is_err());
+    }
+
+    #[test]
+    fn test_buffer_config_uses_defaults_for_missing_options() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.buffer_config(), BufferConfig::default());
+
+        let args = Args::try_parse_from([
+            "wavrec",
+            "somefile",
+            "--chunk-size",
+            "256",
+            "--buffer-ms",
+            "50",
+        ])
+        .unwrap();
+        let buffer = args.buffer_config();
+        assert_eq!(buffer.chunk_size, 256);
+        assert_eq!(buffer.buffer_duration, Some(Duration::from_millis(50)));
+        assert_eq!(buffer.timeout, BufferConfig::default().timeout);
+
+        assert!(Args::try_parse_from(["wavrec", "somefile", "--chunk-size", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_format_policy_defaults_to_nearest() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.format_policy, FormatPolicy::Nearest);
+
+        let args =
+            Args::try_parse_from(["wavrec", "somefile", "--format-policy", "convert"]).unwrap();
+        assert_eq!(args.format_policy, FormatPolicy::Convert);
+
+        assert!(Args::try_parse_from(["wavrec", "somefile", "--format-policy", "any"]).is_err());
+    }
+
+    #[test]
+    fn test_output_format_is_independent_of_requested_format() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.output_format, None);
+
+        let args = Args::try_parse_from([
+            "wavrec",
+            "somefile",
+            "--format",
+            "float32",
+            "--output-format",
+            "int24",
+        ])
+        .unwrap();
+        assert_eq!(args.format, Some(SampleFormat::Float32));
+        assert_eq!(args.output_format, Some(SampleFormat::Int24));
+    }
+
+    #[test]
+    fn test_output_rate_and_resample_quality() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.output_rate, None);
+        assert_eq!(args.resample_quality, ResampleQuality::Polyphase);
+
+        let args = Args::try_parse_from([
+            "wavrec",
+            "somefile",
+            "--output-rate",
+            "48000",
+            "--resample-quality",
+            "sinc",
+        ])
+        .unwrap();
+        assert_eq!(args.output_rate, Some(48000));
+        assert_eq!(args.resample_quality, ResampleQuality::Sinc);
+
+        assert!(Args::try_parse_from(["wavrec", "somefile", "--output-rate", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_channel_mix_is_parsed() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.channel_mix, None);
+
+        let args =
+            Args::try_parse_from(["wavrec", "somefile", "--channel-mix", "pick:3-4"]).unwrap();
+        assert_eq!(args.channel_mix, Some(ChannelMix::Pick(vec![2, 3])));
+
+        assert!(Args::try_parse_from(["wavrec", "somefile", "--channel-mix", "5.1"]).is_err());
+    }
+
+    #[test]
+    fn test_sample_rate_must_be_positive() {
+        let args = Args::try_parse_from(["wavrec", "somefile", "-s", "44100"]).unwrap();
+        assert_eq!(args.sample_rate, Some(44100));
+        assert!(Args::try_parse_from(["wavrec", "somefile", "-s", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_dither_defaults_to_none() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.dither, Dither::None);
+
+        let args = Args::try_parse_from(["wavrec", "somefile", "--dither", "lipshitz"]).unwrap();
+        assert_eq!(args.dither, Dither::Lipshitz);
+    }
+
+    #[test]
+    fn test_wav_header_defaults_to_auto() {
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.wav_header, HeaderFormat::Auto);
+
+        let args = Args::try_parse_from(["wavrec", "somefile", "--wav-header", "classic"]).unwrap();
+        assert_eq!(args.wav_header, HeaderFormat::Classic);
+    }
+
+    #[test]
+    fn test_file_name_is_only_optional_with_command() {
+        assert!(Args::try_parse_from(["wavrec"]).is_err());
+
+        let args = Args::try_parse_from(["wavrec", "devices"]).unwrap();
+        assert_eq!(args.command, Some(Command::Devices));
+        assert_eq!(args.file_name(), "");
+
+        let args = Args::try_parse_from(["wavrec", "somefile"]).unwrap();
+        assert_eq!(args.command, None);
+        assert_eq!(args.file_name(), "somefile.wav");
+    }
 }
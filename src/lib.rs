@@ -7,32 +7,63 @@
 //!
 //! Audio format settings and other options can be set by setting the desired values via the
 //! [`cli::Args`] parameter.
+//!
+//! The [`audio`] format types and [`wave`] file writer don't depend on any audio backend, and are
+//! always available. Backends are selected with cargo features (`wasapi`, `pulse`, `alsa`, `jack`,
+//! `file`, `generator` and `stdin`), which are all enabled by default. Each device backend is only
+//! built on the platform it supports.
 #[warn(missing_docs)]
-mod audio;
+pub mod audio;
 pub mod cli;
-mod wave;
+/// WAV file generation.
+pub mod wave;
 
 use audio::{
-    sys::LoopbackRecorder, AudioDataMessage, AudioFormatInfo, AudioLoopback,
-    RequestedAudioFormatInfo,
+    negotiation::{FormatPolicy, NegotiatedFormat},
+    pipeline::{Conversion, Pipeline},
+    sys, AudioChunk, AudioDataMessage, AudioFormatInfo, AudioLoopback, CaptureOptions, DeviceEvent,
+    Direction, RequestedAudioFormatInfo,
 };
 use cli::Args;
-use log::{error, info};
+use log::{error, info, warn};
 use std::{
     error::Error,
     fmt::Display,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, Sender},
+        mpsc::{self, Receiver, Sender, TryRecvError},
         Arc,
     },
     thread,
+    time::{Duration, Instant},
 };
-use wave::WaveWriter;
+use wave::{HeaderFormat, WaveWriter};
 
 type Res<T> = Result<T, Box<dyn Error>>;
 type Nothing = Res<()>;
 
+/// How long after the end of the previous chunk a chunk can start, before the delay is treated as a
+/// gap in the audio stream.
+const GAP_TOLERANCE: Duration = Duration::from_millis(200);
+
+/// Number of frames of silence written at a time when filling a gap, so long gaps don't need
+/// all their silence in memory at once.
+const SILENCE_BLOCK_FRAMES: usize = 4096;
+
+/// Delay before the first attempt to restart failed capture. Doubled after each further failure.
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest delay between attempts to restart failed capture.
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Number of consecutive failures after which capture is abandoned. With the delays above, this
+/// rides out outages of around three minutes.
+const RETRY_MAX_ATTEMPTS: u32 = 10;
+
+/// How long capture must run before it's considered recovered, and the failure count is reset.
+const RETRY_RESET_AFTER: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 struct AppError {
     message: String,
@@ -52,7 +83,17 @@ impl Display for AppError {
 /// WAV file. See the [`cli::Args`] struct for options.
 ///
 /// The application will only capture data while there is audio playing. When the audio device is
-/// not in use, nothing will be captured.
+/// not in use, nothing will be captured, unless [`fill_gaps`](cli::Args::fill_gaps) is set.
+///
+/// The requested format is negotiated with the formats the device supports, following the
+/// [`format_policy`](cli::Args::format_policy), and any changes are logged before recording starts.
+/// The file is written in the captured sample format and rate, or converted to the
+/// [`output_format`](cli::Args::output_format) and resampled to the
+/// [`output_rate`](cli::Args::output_rate) when they are set.
+///
+/// When the device is lost, or the default device changes while recording it, the recorder is
+/// reopened on the new device. If its format differs, the following audio is written to a new
+/// file, named after the requested file with the segment number appended (e.g. `out-2.wav`).
 pub fn run(args: Args) -> Nothing {
     let is_running = Arc::new(AtomicBool::new(true));
     let (audio_transmitter, audio_receiver): (
@@ -66,18 +107,130 @@ pub fn run(args: Args) -> Nothing {
         format: args.format,
     };
 
-    let loopback_stream: Arc<dyn AudioLoopback> =
-        Arc::new(LoopbackRecorder::create(requested_format)?);
+    let options = CaptureOptions {
+        device: args.device.clone(),
+        direction: if args.input {
+            Direction::Capture
+        } else {
+            Direction::Render
+        },
+        ports: args.ports.clone(),
+        is_realtime: args.realtime,
+        duration: args.duration.map(Duration::from_secs_f64),
+        buffer: args.buffer_config(),
+    };
+
+    let backend = sys::select_backend(args.backend.as_deref(), &options)?;
+    let policy = args.format_policy;
+    let (loopback_stream, negotiated) =
+        sys::create_loopback_recorder(backend, requested_format.clone(), policy, options.clone())?;
+    report_negotiation(&negotiated);
     let audio_format = loopback_stream.get_audio_format();
     info!("Loopback recorder initialized with format: {audio_format}");
 
+    // Converting to the requested sample format and rate, rather than whatever the device
+    // delivers, keeps the output format the same when the device is reopened with another format.
+    let converting = policy == FormatPolicy::Convert;
+    let conversion = Conversion {
+        sample_format: args
+            .output_format
+            .or(requested_format.format.filter(|_| converting)),
+        sample_rate: args
+            .output_rate
+            .or(requested_format.sample_rate.filter(|_| converting)),
+        resample_quality: args.resample_quality,
+        channel_mix: args.channel_mix.clone(),
+        dither: args.dither,
+    };
+
     setup_terminate_handler(Arc::clone(&is_running))?;
-    run_audio_thread(audio_transmitter, Arc::clone(&loopback_stream));
-    run_processing_loop(&args.file_name(), audio_receiver, audio_format, is_running)?;
+    let reopen = move || {
+        let (recorder, negotiated) = sys::create_loopback_recorder(
+            backend,
+            requested_format.clone(),
+            policy,
+            options.clone(),
+        )?;
+        report_negotiation(&negotiated);
+        Ok(recorder)
+    };
+    // Backends which are only used when requested don't record from a device, and restart from
+    // the beginning when recreated, so aren't retried.
+    let is_device = backend.is_default;
+    run_audio_thread(audio_transmitter, loopback_stream, is_device, reopen);
+    let output = OutputOptions {
+        conversion,
+        fill_gaps: args.fill_gaps,
+        header_format: args.wav_header,
+    };
+    run_processing_loop(
+        &args.file_name(),
+        audio_receiver,
+        audio_format,
+        args.duration.map(Duration::from_secs_f64),
+        output,
+        is_running,
+    )?;
 
     Ok(())
 }
 
+/// List the devices each available backend can record from, or only those of the backend requested
+/// in the [CLI args](cli::Args).
+pub fn list_devices(args: &Args) -> Nothing {
+    let backends: Vec<&sys::Backend> = match &args.backend {
+        Some(name) => sys::find_backend(name).into_iter().collect(),
+        None => sys::BACKENDS
+            .iter()
+            .filter(|backend| backend.is_default && backend.is_available())
+            .collect(),
+    };
+    if backends.is_empty() {
+        return Err(Box::new(AppError {
+            message: String::from("No audio backend available"),
+        }));
+    }
+
+    for backend in backends {
+        println!("{}:", backend.name);
+        let devices = match backend.devices() {
+            Ok(devices) => devices,
+            Err(err) => {
+                println!("  Failed to list devices: {err}");
+                continue;
+            }
+        };
+        if devices.is_empty() {
+            println!("  No devices found");
+        }
+        for device in devices {
+            let direction = match device.direction {
+                Direction::Render => "output",
+                Direction::Capture => "input",
+            };
+            let format = device
+                .default_format
+                .map(|format| {
+                    format!(
+                        " ({} Hz, {} channels, {})",
+                        format.sample_rate, format.num_channels, format.format
+                    )
+                })
+                .unwrap_or_default();
+            println!("  [{direction}] {}{format}", device.name);
+            println!("      id: {}", device.id);
+        }
+    }
+    Ok(())
+}
+
+/// Log how the negotiated format differs from the requested format.
+fn report_negotiation(negotiated: &NegotiatedFormat) {
+    for change in &negotiated.changes {
+        warn!("{change}");
+    }
+}
+
 /// Initializes the Ctrl-C handler.
 fn setup_terminate_handler(is_running_flag: Arc<AtomicBool>) -> Nothing {
     let result = ctrlc::set_handler(move || {
@@ -98,40 +251,639 @@ fn setup_terminate_handler(is_running_flag: Arc<AtomicBool>) -> Nothing {
 ///
 /// This thread will run in the background, and continuously send data to the provided
 /// [`transmitter`](std::sync::mpsc::Sender), when the audio device is in use.
+///
+/// The thread supervises capture. When it stops because of a [`DeviceEvent`], the recorder is
+/// recreated with `reopen`, and capture continues on the new device. When capture fails, or the
+/// recorder can't be recreated, it's retried with an increasing delay, until
+/// [`RETRY_MAX_ATTEMPTS`] consecutive attempts have failed. Sources which aren't devices aren't
+/// retried, as they'd start again from the beginning.
 fn run_audio_thread(
     transmitter: Sender<AudioDataMessage>,
     loopback_stream: Arc<dyn AudioLoopback>,
+    is_device: bool,
+    reopen: impl Fn() -> Res<Arc<dyn AudioLoopback>> + Send + 'static,
 ) {
     info!("Starting audio thread");
     thread::spawn(move || {
-        let _ = loopback_stream.capture(transmitter);
+        let mut format = loopback_stream.get_audio_format();
+        let mut loopback_stream = Some(loopback_stream);
+        let mut failures = 0;
+        loop {
+            let stream = match loopback_stream.take() {
+                Some(stream) => Ok(stream),
+                None => reopen(),
+            };
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    failures += 1;
+                    let reason = format!("Failed to reopen audio device: {err}");
+                    match wait_to_retry(&transmitter, failures, reason) {
+                        true => continue,
+                        false => break,
+                    }
+                }
+            };
+
+            let new_format = stream.get_audio_format();
+            if new_format != format {
+                info!("Loopback recorder reopened with format: {new_format}");
+                format = new_format;
+                if transmitter
+                    .send(AudioDataMessage::FormatChanged(new_format))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+
+            let started = Instant::now();
+            let result = stream.capture(transmitter.clone());
+            // Release the device before reopening it, in case it can only be opened once.
+            drop(stream);
+            if started.elapsed() >= RETRY_RESET_AFTER {
+                failures = 0;
+            }
+            match result {
+                Ok(None) => break,
+                Ok(Some(event)) => {
+                    if transmitter
+                        .send(AudioDataMessage::DeviceEvent(event))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(err) if !is_device => {
+                    let message = AudioDataMessage::Error(Box::new(AppError {
+                        message: err.to_string(),
+                    }));
+                    let _ = transmitter.send(message);
+                    break;
+                }
+                Err(err) => {
+                    failures += 1;
+                    if !wait_to_retry(&transmitter, failures, err.to_string()) {
+                        break;
+                    }
+                }
+            }
+        }
     });
 }
 
+/// Report a capture failure, and wait before the next attempt. Returns `false` when capture should
+/// be abandoned, after reporting the final error.
+fn wait_to_retry(transmitter: &Sender<AudioDataMessage>, attempt: u32, reason: String) -> bool {
+    if attempt > RETRY_MAX_ATTEMPTS {
+        let message = AudioDataMessage::Error(Box::new(AppError {
+            message: format!("Giving up after {RETRY_MAX_ATTEMPTS} retries: {reason}"),
+        }));
+        let _ = transmitter.send(message);
+        return false;
+    }
+    let delay = retry_delay(attempt);
+    let message = AudioDataMessage::Retrying {
+        attempt,
+        delay,
+        reason,
+    };
+    if transmitter.send(message).is_err() {
+        return false;
+    }
+    thread::sleep(delay);
+    true
+}
+
+/// Return the delay before retrying capture after `attempt` consecutive failures.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_INITIAL_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(RETRY_MAX_DELAY)
+}
+
 /// Handles the audio data received from the audio thread.
 ///
-/// Audio data received will be written to the WAV file requested in the [CLI args](cli::Args).
+/// Audio data received will be written to the WAV file requested in the [CLI args](cli::Args). The
+/// loop runs until the application is terminated, the audio thread stops sending data, or
+/// `duration` has been recorded. When the audio format changes, a new file segment is started.
+///
+/// The captured audio is converted as set in the [`OutputOptions`] before being written, and a new
+/// segment is only started when the converted format changes.
 fn run_processing_loop(
     file_name: &str,
     receiver: Receiver<AudioDataMessage>,
     format: AudioFormatInfo,
+    duration: Option<Duration>,
+    output: OutputOptions,
     is_running: Arc<AtomicBool>,
 ) -> Nothing {
     info!("Starting processing loop");
+    let mut next_position = 0;
+    let mut segment_count = 1;
+    // Length of the finished segments.
+    let mut recorded = Duration::ZERO;
+    // The format of the audio received from the audio thread.
+    let mut capture_format = format;
+    let mut pipeline = Pipeline::new(format, &output.conversion)?;
+    let segment_format = pipeline.output_format();
+    if segment_format != format {
+        info!("Converting audio to: {segment_format}");
+    }
+    let mut segment = Segment::open(file_name.to_owned(), segment_format, duration, &output)?;
     // Handle the captured data sent from the audio thread
-    let mut file_writer = WaveWriter::open(file_name, format)?;
     while is_running.load(Ordering::Relaxed) {
-        let _ = receiver.try_recv().map(|chunk| match chunk {
-            AudioDataMessage::AudioData(chunk) => file_writer.write(chunk),
+        if segment.is_complete() {
+            info!("Recorded requested duration");
+            break;
+        }
+        let chunk = match receiver.try_recv() {
+            Ok(chunk) => chunk,
+            Err(TryRecvError::Empty) => continue,
+            // The audio thread has finished, e.g. at the end of a replayed file.
+            Err(TryRecvError::Disconnected) => {
+                info!("Audio capture finished");
+                break;
+            }
+        };
+        // Errors writing the file, e.g. when the disk is full, stop recording. The header of the
+        // file is still updated for the audio written, when the segment is dropped.
+        match chunk {
+            AudioDataMessage::AudioData(chunk) => {
+                let num_frames = chunk.data.len() / capture_format.block_alignment() as usize;
+                if chunk.discontinuity || chunk.position != next_position {
+                    warn!(
+                        "Discontinuity in audio stream at {}, {} frames lost",
+                        format_position(segment.frames_written, segment.format.sample_rate),
+                        chunk.position.saturating_sub(next_position)
+                    );
+                }
+                next_position = chunk.position + num_frames as u64;
+                let data = pipeline.process(&chunk.data);
+                segment.write(AudioChunk { data, ..chunk })
+            }
+            AudioDataMessage::DeviceEvent(event) => {
+                match event {
+                    DeviceEvent::Changed => info!("Default audio device changed, reopening"),
+                    DeviceEvent::Lost => warn!("Audio device lost, reopening"),
+                }
+                // The reopened recorder counts positions from the start of its own stream.
+                next_position = 0;
+                Ok(())
+            }
+            AudioDataMessage::Retrying {
+                attempt,
+                delay,
+                reason,
+            } => {
+                warn!(
+                    "{reason}. Retrying in {:.1}s (attempt {attempt} of {RETRY_MAX_ATTEMPTS})",
+                    delay.as_secs_f64()
+                );
+                next_position = 0;
+                Ok(())
+            }
+            AudioDataMessage::FormatChanged(format) => {
+                capture_format = format;
+                // The end of the audio in the old format.
+                segment.write_frames(pipeline.flush())?;
+                pipeline = Pipeline::new(capture_format, &output.conversion)?;
+                let format = pipeline.output_format();
+                if format == segment.format {
+                    info!("Audio format changed to {capture_format}, converting to {format}");
+                    continue;
+                }
+                recorded += segment.format.duration_of(segment.frames_written);
+                segment.finish()?;
+                segment_count += 1;
+                let segment_name = segment_file_name(file_name, segment_count);
+                info!("Audio format changed, continuing in new file: {segment_name}");
+                let remaining = duration.map(|duration| duration.saturating_sub(recorded));
+                segment = Segment::open(segment_name, format, remaining, &output)?;
+                Ok(())
+            }
             AudioDataMessage::Error(err) => {
                 error!("Error while writing WAV file: {err}");
                 is_running.store(false, Ordering::Relaxed);
                 Ok(())
             }
-        });
+        }?;
+    }
+    segment.write_frames(pipeline.flush())?;
+    segment.finish()
+}
+
+/// How the processing loop writes the captured audio to file.
+struct OutputOptions {
+    /// How to convert the captured audio before it's written.
+    conversion: Conversion,
+
+    /// Whether to fill gaps in the audio stream with silence, so the file stays in step with the
+    /// wall clock.
+    fill_gaps: bool,
+
+    /// Which `fmt ` chunk layout to write.
+    header_format: HeaderFormat,
+}
+
+/// A WAV file being written by the processing loop, in a single audio format.
+struct Segment {
+    file_name: String,
+    format: AudioFormatInfo,
+    file_writer: WaveWriter,
+    frames_written: usize,
+
+    /// Number of frames to write before the requested duration has been recorded.
+    max_frames: Option<usize>,
+
+    gap_detector: Option<GapDetector>,
+}
+
+impl Segment {
+    /// Open the segment's file. Its position `0` is the current time, for filling gaps.
+    fn open(
+        file_name: String,
+        format: AudioFormatInfo,
+        duration: Option<Duration>,
+        output: &OutputOptions,
+    ) -> Res<Segment> {
+        info!("Creating file: {file_name}");
+        let file_writer = WaveWriter::open(&file_name, format, output.header_format)?;
+        Ok(Segment {
+            file_name,
+            format,
+            file_writer,
+            frames_written: 0,
+            max_frames: duration.map(|duration| {
+                (duration.as_secs_f64() * format.sample_rate as f64).round() as usize
+            }),
+            gap_detector: output
+                .fill_gaps
+                .then(|| GapDetector::new(format.sample_rate, Instant::now())),
+        })
+    }
+
+    /// Whether the requested duration has been recorded.
+    fn is_complete(&self) -> bool {
+        self.max_frames
+            .is_some_and(|max_frames| self.frames_written >= max_frames)
+    }
+
+    /// Write a chunk of audio, after any silence needed to fill a gap before it.
+    fn write(&mut self, chunk: AudioChunk) -> Nothing {
+        let block_align = self.format.block_alignment() as usize;
+        let num_frames = chunk.data.len() / block_align;
+        if let Some(gap_detector) = self.gap_detector.as_mut() {
+            let silence =
+                gap_detector.missing_frames(chunk.timestamp, num_frames, self.frames_written);
+            if silence > 0 {
+                log_gap(silence, self.frames_written, self.format.sample_rate);
+                self.write_silence(silence)?;
+            }
+        }
+        self.write_frames(chunk.data)
+    }
+
+    /// Write frames of silence, a block at a time, up to the requested duration.
+    fn write_silence(&mut self, num_frames: usize) -> Nothing {
+        let mut remaining = num_frames;
+        while remaining > 0 && !self.is_complete() {
+            let block = remaining.min(SILENCE_BLOCK_FRAMES);
+            self.write_frames(self.format.silence(block))?;
+            remaining -= block;
+        }
+        Ok(())
+    }
+
+    /// Write audio which follows on from the previous chunk, up to the requested duration.
+    fn write_frames(&mut self, mut data: Vec<u8>) -> Nothing {
+        let block_align = self.format.block_alignment() as usize;
+        if let Some(max_frames) = self.max_frames {
+            data.truncate(max_frames.saturating_sub(self.frames_written) * block_align);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.frames_written += data.len() / block_align;
+        self.file_writer.write(data)
+    }
+
+    /// Fill a gap which is still open when recording stops, and write the final WAV file.
+    fn finish(mut self) -> Nothing {
+        if let Some(gap_detector) = self.gap_detector.as_mut() {
+            let silence = gap_detector.missing_frames(Instant::now(), 0, self.frames_written);
+            let silence = self.max_frames.map_or(silence, |max_frames| {
+                silence.min(max_frames.saturating_sub(self.frames_written))
+            });
+            if silence > 0 {
+                log_gap(silence, self.frames_written, self.format.sample_rate);
+                self.write_silence(silence)?;
+            }
+        }
+        info!("Finishing file: {}", self.file_name);
+        self.file_writer.commit()?;
+        self.file_writer.close()?;
+        Ok(())
+    }
+}
+
+/// Return the file name for a segment of the recording after the first, by appending the segment
+/// number to the requested file name, e.g. `out-2.wav`.
+fn segment_file_name(file_name: &str, segment: usize) -> String {
+    let path = Path::new(file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{segment}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{segment}"),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+/// Detects gaps in the audio stream against the wall clock, from the capture timestamps of each
+/// chunk, such as when loopback capture stops while nothing is playing.
+///
+/// Only chunks starting late count as gaps. Drift between the device clock and the wall clock
+/// during continuous audio is left alone, and only corrected by the next gap.
+struct GapDetector {
+    sample_rate: u32,
+
+    /// When recording started, which is frame `0` of the file.
+    start: Instant,
+
+    /// When the frame after the last chunk should be captured, if the audio is continuous.
+    next_timestamp: Instant,
+}
+
+impl GapDetector {
+    fn new(sample_rate: u32, start: Instant) -> GapDetector {
+        GapDetector {
+            sample_rate,
+            start,
+            next_timestamp: start,
+        }
+    }
+
+    /// Return the number of frames of silence to write before a chunk of `num_frames` captured at
+    /// `timestamp`, so the chunk lines up with the wall clock. `0` when the chunk isn't late.
+    fn missing_frames(
+        &mut self,
+        timestamp: Instant,
+        num_frames: usize,
+        frames_written: usize,
+    ) -> usize {
+        let delay = timestamp.saturating_duration_since(self.next_timestamp);
+        let chunk_duration = Duration::from_secs_f64(num_frames as f64 / self.sample_rate as f64);
+        self.next_timestamp = self.next_timestamp.max(timestamp + chunk_duration);
+        if delay < GAP_TOLERANCE {
+            return 0;
+        }
+
+        let elapsed = timestamp.saturating_duration_since(self.start);
+        let expected_frames = (elapsed.as_secs_f64() * self.sample_rate as f64).round() as usize;
+        expected_frames.saturating_sub(frames_written)
+    }
+}
+
+/// Log the position and length of a gap filled with silence.
+fn log_gap(num_frames: usize, position: usize, sample_rate: u32) {
+    info!(
+        "Filled gap of {:.3}s with silence at {}",
+        num_frames as f64 / sample_rate as f64,
+        format_position(position, sample_rate)
+    );
+}
+
+/// Format a frame position as `HH:MM:SS.mmm`.
+fn format_position(position: usize, sample_rate: u32) -> String {
+    let millis = (position as u64 * 1000) / sample_rate as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    #[cfg(feature = "file")]
+    use clap::Parser;
+    use uuid::Uuid;
+
+    use super::*;
+    use audio::{layout::ChannelLayout, SampleFormat};
+    use wave::WaveReader;
+    #[cfg(feature = "file")]
+    use wave::WaveWriter;
+
+    #[test]
+    fn gap_detector_ignores_chunks_starting_in_time() {
+        let start = Instant::now();
+        let mut gap_detector = GapDetector::new(1000, start);
+        assert_eq!(gap_detector.missing_frames(start, 100, 0), 0);
+        // Late, but within the tolerance.
+        let timestamp = start + Duration::from_millis(250);
+        assert_eq!(gap_detector.missing_frames(timestamp, 100, 100), 0);
+    }
+
+    #[test]
+    fn gap_detector_fills_gap_up_to_wall_clock() {
+        let start = Instant::now();
+        let mut gap_detector = GapDetector::new(1000, start);
+        assert_eq!(gap_detector.missing_frames(start, 100, 0), 0);
+
+        // Nothing is captured for 5 seconds after the first chunk.
+        let timestamp = start + Duration::from_millis(5100);
+        assert_eq!(gap_detector.missing_frames(timestamp, 100, 100), 5000);
+
+        // A gap which is still open when recording stops.
+        let now = timestamp + Duration::from_millis(1100);
+        assert_eq!(gap_detector.missing_frames(now, 0, 5200), 1000);
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_maximum() {
+        assert_eq!(retry_delay(1), RETRY_INITIAL_DELAY);
+        assert_eq!(retry_delay(2), RETRY_INITIAL_DELAY * 2);
+        assert_eq!(retry_delay(4), RETRY_INITIAL_DELAY * 8);
+        assert_eq!(retry_delay(RETRY_MAX_ATTEMPTS), RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), RETRY_MAX_DELAY);
+    }
+
+    struct FailingRecorder;
+
+    impl AudioLoopback for FailingRecorder {
+        fn create(_: RequestedAudioFormatInfo, _: CaptureOptions) -> Res<impl AudioLoopback> {
+            Ok(FailingRecorder)
+        }
+
+        fn is_available() -> bool {
+            true
+        }
+
+        fn get_audio_format(&self) -> AudioFormatInfo {
+            AudioFormatInfo {
+                sample_rate: 8000,
+                num_channels: 1,
+                format: SampleFormat::Int16,
+                channel_layout: ChannelLayout::MONO,
+            }
+        }
+
+        fn capture(&self, _: Sender<AudioDataMessage>) -> Res<Option<DeviceEvent>> {
+            Err(Box::new(AppError {
+                message: "read failed".to_owned(),
+            }))
+        }
+    }
+
+    #[test]
+    fn failed_sources_which_are_not_devices_are_not_reopened() {
+        let (transmitter, receiver) = mpsc::channel();
+        let reopen = || -> Res<Arc<dyn AudioLoopback>> { panic!("reopened") };
+        run_audio_thread(transmitter, Arc::new(FailingRecorder), false, reopen);
+
+        let message = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            matches!(message, AudioDataMessage::Error(err) if err.to_string() == "read failed")
+        );
+        // The thread has finished.
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn segment_file_name_appends_segment_number_before_extension() {
+        assert_eq!(segment_file_name("out.wav", 2), "out-2.wav");
+        assert_eq!(segment_file_name("rec.d/out.wav", 3), "rec.d/out-3.wav");
+        assert_eq!(segment_file_name("out", 2), "out-2");
+    }
+
+    #[test]
+    fn format_position_formats_hours_to_milliseconds() {
+        assert_eq!(format_position(0, 48000), "00:00:00.000");
+        assert_eq!(format_position(48000 * 3723 + 24000, 48000), "01:02:03.500");
+    }
+
+    #[test]
+    #[cfg(feature = "file")]
+    fn run_replays_wave_file_into_identical_wave_file() {
+        let format = AudioFormatInfo {
+            sample_rate: 44100,
+            num_channels: 2,
+            format: SampleFormat::Int16,
+            channel_layout: ChannelLayout::STEREO,
+        };
+        // Not a whole number of chunks, so the final partial chunk must also be written.
+        let values: Vec<u8> = (0..4 * 10000).map(|i| i as u8).collect();
+        let input_file = temp_file_name();
+        let output_file = temp_file_name();
+        let mut writer = WaveWriter::open(&input_file, format, HeaderFormat::Auto).unwrap();
+        writer.write(values.clone()).unwrap();
+        writer.commit().unwrap();
+        writer.close().unwrap();
+
+        let args = Args::parse_from([
+            "wavrec",
+            &output_file,
+            "--backend",
+            "file",
+            "--device",
+            &input_file,
+        ]);
+        run(args).unwrap();
+
+        let mut reader = WaveReader::open(&output_file).unwrap();
+        assert_eq!(reader.audio_format().sample_rate, 44100);
+        assert_eq!(reader.audio_format().num_channels, 2);
+        assert_eq!(reader.audio_format().bit_depth(), 16);
+        let mut data = vec![0u8; values.len() + 4];
+        assert_eq!(reader.read(&mut data).unwrap(), values.len());
+        assert_eq!(data[..values.len()], values);
+
+        fs::remove_file(input_file).unwrap();
+        fs::remove_file(output_file).unwrap();
+    }
+
+    #[test]
+    fn silence_is_written_in_blocks_up_to_requested_duration() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 2,
+            format: SampleFormat::Int16,
+            channel_layout: ChannelLayout::STEREO,
+        };
+        let output_file = temp_file_name();
+        let output = OutputOptions {
+            conversion: Conversion::default(),
+            fill_gaps: false,
+            header_format: HeaderFormat::Auto,
+        };
+        let duration = Some(Duration::from_millis(250));
+        let mut segment = Segment::open(output_file.clone(), format, duration, &output).unwrap();
+        segment.write_silence(3 * SILENCE_BLOCK_FRAMES).unwrap();
+        assert_eq!(segment.frames_written, 12000);
+        segment.finish().unwrap();
+
+        let mut reader = WaveReader::open(&output_file).unwrap();
+        let mut data = vec![1u8; 4 * 13000];
+        assert_eq!(reader.read(&mut data).unwrap(), 4 * 12000);
+        assert!(data[..4 * 12000].iter().all(|byte| *byte == 0));
+
+        fs::remove_file(output_file).unwrap();
+    }
+
+    #[test]
+    fn processing_loop_converts_to_output_format() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 1,
+            format: SampleFormat::Float32,
+            channel_layout: ChannelLayout::MONO,
+        };
+        let values: Vec<u8> = [0.5f32, -1.0, 1.5]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let (transmitter, receiver) = mpsc::channel();
+        transmitter
+            .send(AudioDataMessage::AudioData(AudioChunk::new(
+                values,
+                0,
+                Instant::now(),
+            )))
+            .unwrap();
+        drop(transmitter);
+
+        let output_file = temp_file_name();
+        let output = OutputOptions {
+            conversion: Conversion {
+                sample_format: Some(SampleFormat::Int24),
+                ..Conversion::default()
+            },
+            fill_gaps: false,
+            header_format: HeaderFormat::Auto,
+        };
+        let is_running = Arc::new(AtomicBool::new(true));
+        run_processing_loop(&output_file, receiver, format, None, output, is_running).unwrap();
+
+        let mut reader = WaveReader::open(&output_file).unwrap();
+        assert_eq!(reader.audio_format().format, SampleFormat::Int24);
+        let mut data = vec![0u8; 12];
+        assert_eq!(reader.read(&mut data).unwrap(), 9);
+        assert_eq!(
+            data[..9],
+            [0x00, 0x00, 0x40, 0x00, 0x00, 0x80, 0xFF, 0xFF, 0x7F]
+        );
+
+        fs::remove_file(output_file).unwrap();
+    }
+
+    fn temp_file_name() -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("wavrec-test-{}.wav", Uuid::new_v4()));
+        path.to_str().unwrap().to_owned()
     }
-    info!("Creating file: {file_name}");
-    file_writer.commit()?;
-    file_writer.close()?;
-    Ok(())
 }
@@ -1,33 +1,85 @@
 use std::{
-    env,
     error::Error,
     fmt::Display,
-    fs::{self, File},
-    io::{BufWriter, Read, Write},
-    path::Path,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
 };
 
-use log::{debug, error, trace};
-use uuid::Uuid;
+use clap::ValueEnum;
+use log::{debug, error, info, trace};
 
-use crate::{audio::AudioFormatInfo, Nothing, Res};
+use crate::{
+    audio::{layout::ChannelLayout, AudioFormatInfo, SampleFormat},
+    Nothing, Res,
+};
 
 type TwoByteField = [u8; 2];
 type FourByteField = [u8; 4];
 
+/// `fmt ` chunk type format used by `WAVE_FORMAT_EXTENSIBLE` files, where the actual type format
+/// is given by the first two bytes of the sub format GUID.
+const EXTENSIBLE_TYPE_FORMAT: u16 = 0xFFFE;
+
+/// The bytes of the `KSDATAFORMAT_SUBTYPE_*` GUIDs after the type format, which is the same for
+/// every type format.
+const SUB_FORMAT_GUID_SUFFIX: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Largest `fmt ` or `ds64` chunk read, which is far more than either needs. A larger size is
+/// taken to be a corrupt header, rather than allocating whatever it claims.
+const MAX_HEADER_CHUNK_BYTES: usize = 4096;
+
+/// Which `fmt ` chunk layout to write.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// `WAVE_FORMAT_EXTENSIBLE` when the format requires it, classic otherwise.
+    #[default]
+    Auto,
+    /// The classic 16 byte `fmt ` chunk, which older tools may expect.
+    Classic,
+    /// Always `WAVE_FORMAT_EXTENSIBLE`.
+    Extensible,
+}
+
+impl HeaderFormat {
+    /// Whether to write a `WAVE_FORMAT_EXTENSIBLE` header for the format.
+    fn is_extensible(&self, format: &AudioFormatInfo) -> bool {
+        match self {
+            HeaderFormat::Auto => {
+                // Integer audio of more than 16 bits, more than 2 channels, samples which don't
+                // fill their container, or speaker positions other than the usual ones can't be
+                // described by the classic header.
+                let is_wide_integer = format.type_format_header() == 1 && format.bit_depth() > 16;
+                is_wide_integer
+                    || format.num_channels > 2
+                    || format.bit_depth() != format.container_bits()
+                    || format.channel_layout != ChannelLayout::default_for(format.num_channels)
+            }
+            HeaderFormat::Classic => false,
+            HeaderFormat::Extensible => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 enum WaveError {
-    MaxFileSizeReached,
+    InvalidFile(&'static str),
+    UnsupportedFormat,
+    ByteRateTooLarge(u64),
 }
 
 impl Error for WaveError {}
 
 impl Display for WaveError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let message = match self {
-            WaveError::MaxFileSizeReached => "WAV file cannot exceed 4 GiB in size",
-        };
-        write!(f, "{}", message)
+        match self {
+            WaveError::InvalidFile(reason) => write!(f, "{}", reason),
+            WaveError::UnsupportedFormat => write!(f, "WAV file sample format is not supported"),
+            WaveError::ByteRateTooLarge(rate) => {
+                write!(f, "{rate} bytes per second doesn't fit in a WAV header")
+            }
+        }
     }
 }
 
@@ -69,28 +121,82 @@ struct WaveHeader {
     /// `Number of channels * bit depth / 8`
     block_alignment: TwoByteField,
 
-    /// Audio bit depth.
+    /// Audio bit depth. The size of the sample container, for `WAVE_FORMAT_EXTENSIBLE` files.
     bit_depth: TwoByteField,
+
+    /// The `WAVE_FORMAT_EXTENSIBLE` fields, when the type format is `0xFFFE`.
+    extension: Option<WaveFormatExtension>,
+}
+
+/// The fields which `WAVE_FORMAT_EXTENSIBLE` adds to the `fmt ` chunk. See
+/// <https://learn.microsoft.com/en-us/windows/win32/api/mmreg/ns-mmreg-waveformatextensible>.
+struct WaveFormatExtension {
+    /// Size of the remaining fields. Always `22`.
+    extension_size: TwoByteField,
+
+    /// Number of bits of each sample which carry audio.
+    valid_bits: TwoByteField,
+
+    /// Speaker positions of the channels, as `SPEAKER_*` flags.
+    channel_mask: FourByteField,
+
+    /// GUID of the actual type format, which is given by its first two bytes.
+    sub_format: [u8; 16],
+}
+
+impl WaveFormatExtension {
+    fn create(format: &AudioFormatInfo) -> WaveFormatExtension {
+        let mut sub_format = [0u8; 16];
+        sub_format[..2].copy_from_slice(&format.type_format_header().to_le_bytes());
+        sub_format[2..].copy_from_slice(&SUB_FORMAT_GUID_SUFFIX);
+        WaveFormatExtension {
+            extension_size: 22u16.to_le_bytes(),
+            valid_bits: (format.bit_depth() as u16).to_le_bytes(),
+            channel_mask: format.channel_layout.mask().to_le_bytes(),
+            sub_format,
+        }
+    }
 }
 
 impl WaveHeader {
     const BYTES_IN_HEADER: usize = 44;
 
+    /// Size of the header, including the `WAVE_FORMAT_EXTENSIBLE` fields.
+    const BYTES_IN_EXTENSIBLE_HEADER: usize = 68;
+
     /// Create a new [`WaveHeader`] based on the given [`AudioFormatInfo`] and data size.
-    fn create(format: AudioFormatInfo, data_size: usize) -> Res<WaveHeader> {
+    fn create(
+        format: AudioFormatInfo,
+        data_size: usize,
+        header_format: HeaderFormat,
+    ) -> Res<WaveHeader> {
         trace!("Preparing WAV header data");
+        let extension = header_format
+            .is_extensible(&format)
+            .then(|| WaveFormatExtension::create(&format));
+        let header_size = match extension {
+            Some(_) => Self::BYTES_IN_EXTENSIBLE_HEADER,
+            None => Self::BYTES_IN_HEADER,
+        };
         let file_description_header = b"RIFF".to_owned();
-        let file_size: FourByteField =
-            ((data_size + (Self::BYTES_IN_HEADER - 8)) as u32).to_le_bytes();
+        let file_size: FourByteField = ((data_size + (header_size - 8)) as u32).to_le_bytes();
         let wave_description_header = b"WAVE".to_owned();
         let fmt_description = b"fmt ".to_owned();
-        let wave_description_chunk_size = 16u32.to_le_bytes().to_owned();
-        let type_format = format.type_format_header().to_le_bytes();
+        // The fmt chunk is everything after its size, and before the data chunk header.
+        let wave_description_chunk_size = ((header_size - 28) as u32).to_le_bytes();
+        let type_format = match extension {
+            Some(_) => EXTENSIBLE_TYPE_FORMAT,
+            None => format.type_format_header(),
+        }
+        .to_le_bytes();
         let num_channels = (format.num_channels as u16).to_le_bytes();
         let sample_rate = format.sample_rate.to_le_bytes();
-        let bytes_per_second = format.bytes_per_second().to_le_bytes();
+        let bytes_per_second = u32::try_from(format.bytes_per_second())
+            .map_err(|_| WaveError::ByteRateTooLarge(format.bytes_per_second()))?
+            .to_le_bytes();
         let block_alignment = format.block_alignment().to_le_bytes();
-        let bit_depth: TwoByteField = (format.bit_depth() as u16).to_le_bytes();
+        // Plain PCM headers can only describe the container size.
+        let bit_depth: TwoByteField = (format.container_bits() as u16).to_le_bytes();
 
         Ok(WaveHeader {
             file_description_header,
@@ -104,12 +210,13 @@ impl WaveHeader {
             bytes_per_second,
             block_alignment,
             bit_depth,
+            extension,
         })
     }
 
     /// Build the formatted WAV file header, ready for writing.
     fn as_bytes(&self) -> Vec<u8> {
-        let mut data: Vec<u8> = Vec::with_capacity(Self::BYTES_IN_HEADER);
+        let mut data: Vec<u8> = Vec::with_capacity(Self::BYTES_IN_EXTENSIBLE_HEADER);
         data.extend_from_slice(&self.file_description_header);
         data.extend_from_slice(&self.file_size);
         data.extend_from_slice(&self.wave_description_header);
@@ -121,145 +228,387 @@ impl WaveHeader {
         data.extend_from_slice(&self.bytes_per_second);
         data.extend_from_slice(&self.block_alignment);
         data.extend_from_slice(&self.bit_depth);
+        if let Some(extension) = &self.extension {
+            data.extend_from_slice(&extension.extension_size);
+            data.extend_from_slice(&extension.valid_bits);
+            data.extend_from_slice(&extension.channel_mask);
+            data.extend_from_slice(&extension.sub_format);
+        }
         data
     }
 }
 
-/// Represents the data section of a WAV file, including the 'data' header.
-struct WaveData {
-    data_header: FourByteField,
-    size_in_bytes: FourByteField,
-    data: Vec<u8>,
+/// The sizes of an RF64 file, which are too large for the 32 bit size fields. They're written in
+/// a `ds64` chunk, right after the `WAVE` marker, and the 32 bit fields are set to `0xFFFFFFFF`.
+/// See EBU Tech 3306, and ITU-R BS.2088 for BW64, which uses the same chunk.
+///
+/// Files which may need the chunk reserve its space with a `JUNK` chunk of the same size, which
+/// readers skip, so the file can become RF64 without moving the audio.
+struct DataSize64 {
+    /// File size less the 8 bytes of the `RF64` marker and the `riff_size` field.
+    riff_size: u64,
+
+    /// Size of the audio in the `data` chunk.
+    data_size: u64,
+
+    /// Number of frames of audio.
+    sample_count: u64,
 }
 
-impl WaveData {
-    /// Create the data section of the WAV file.
-    fn create(data: Vec<u8>) -> Res<WaveData> {
-        trace!("Preparing WAV data section");
-        let data_header = b"data".to_owned();
-        let size_in_bytes: FourByteField = (data.len() as u32).to_le_bytes().to_owned();
-        Ok(WaveData {
-            data_header,
-            size_in_bytes,
-            data,
-        })
-    }
-    /// Return the formatted bytes in the data section, ready for writing.
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(8 + self.data.len());
-        data.extend_from_slice(&self.data_header);
-        data.extend_from_slice(&self.size_in_bytes);
-        data.extend(&self.data);
+impl DataSize64 {
+    /// Size of the chunk, including its id and size fields.
+    const BYTES_IN_CHUNK: usize = 36;
+
+    /// Build the `ds64` chunk, ready for writing. The table of other large chunks is left empty.
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::BYTES_IN_CHUNK);
+        data.extend_from_slice(b"ds64");
+        data.extend_from_slice(&(Self::BYTES_IN_CHUNK as u32 - 8).to_le_bytes());
+        data.extend_from_slice(&self.riff_size.to_le_bytes());
+        data.extend_from_slice(&self.data_size.to_le_bytes());
+        data.extend_from_slice(&self.sample_count.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
         data
     }
-}
 
-/// Represents a complete WAV file, separated into header and data sections. The `header` and
-/// `data` properties should contain everything necessary to write a valid WAV file.
-pub struct WaveFile {
-    header: WaveHeader,
-    data: WaveData,
-}
+    /// Build the `JUNK` chunk which reserves space for the `ds64` chunk.
+    fn placeholder_bytes() -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::BYTES_IN_CHUNK);
+        data.extend_from_slice(b"JUNK");
+        data.extend_from_slice(&(Self::BYTES_IN_CHUNK as u32 - 8).to_le_bytes());
+        data.resize(Self::BYTES_IN_CHUNK, 0);
+        data
+    }
 
-impl WaveFile {
-    /// Prepare the data for a new WAV file.
-    pub fn create(data: Vec<u8>, format: AudioFormatInfo) -> Res<Self> {
-        debug!("Preparing WAV file data");
-        let header = WaveHeader::create(format, data.len())?;
-        let data = WaveData::create(data)?;
-        Ok(WaveFile { header, data })
-    }
-
-    /// Write the WAV data to file.
-    pub fn write(&self, file_name: &str) -> Nothing {
-        debug!("Writing to file: {file_name}");
-        let header_bytes = self.header.as_bytes();
-        let data_bytes = self.data.as_bytes();
-        let mut file = File::create(file_name)?;
-        file.write_all(&header_bytes)?;
-        file.write_all(&data_bytes)?;
-        Ok(())
+    /// Parse the contents of a `ds64` chunk.
+    fn parse(chunk: &[u8]) -> Res<DataSize64> {
+        if chunk.len() < 24 {
+            return Err(Box::new(WaveError::InvalidFile(
+                "WAV ds64 chunk is too short",
+            )));
+        }
+        Ok(DataSize64 {
+            riff_size: u64::from_le_bytes(chunk[0..8].try_into()?),
+            data_size: u64::from_le_bytes(chunk[8..16].try_into()?),
+            sample_count: u64::from_le_bytes(chunk[16..24].try_into()?),
+        })
     }
 }
 
-/// Buffered WAV file writer. Opening a WAV file allows writing to a buffer, which can later be
-/// written to disk.
+/// Streaming WAV file writer. Opening a WAV file writes a placeholder header, audio is written
+/// straight after it as it arrives, and committing the file fills in the sizes in the header, so
+/// memory use doesn't grow with the length of the recording.
+///
+/// The header reserves space for a `ds64` chunk, so recordings too large for the 32 bit sizes of a
+/// WAV file, about 4 GiB, are committed as RF64 files instead.
 ///
 /// To use, a writer should be opened, written to, committed and closed.
 pub struct WaveWriter {
     buffered_writer: BufWriter<File>,
-    file_name: String,
-    tmp_file_name: String,
-    bytes_written: usize,
+    bytes_written: u64,
     audio_format_info: AudioFormatInfo,
+
+    /// The `fmt ` chunk, which doesn't change as audio is written.
+    fmt_chunk: Vec<u8>,
+
+    /// Largest RIFF size a plain WAV file can have, before it's written as RF64.
+    max_riff_size: u64,
+
+    /// Whether the header has been updated for all the audio written.
+    is_committed: bool,
 }
 
 impl WaveWriter {
-    // 32 bit integer max value - the header size - the size of the data section fields
-    const WAV_MAX_BYTES: usize = u32::MAX as usize - WaveHeader::BYTES_IN_HEADER - 8;
-
-    /// Prepares a new WaveWriter for writing audio data to disk.
-    ///
-    /// This uses a temporary file as a data buffer, which will later be written to a correctly
-    /// formatted WAV file, when the [`WaveWriter::commit`] method is called.
-    pub fn open(file_name: &str, audio_format_info: AudioFormatInfo) -> Res<Self> {
-        let mut tmp_dir = env::temp_dir();
-        let tmp_file_id = Uuid::new_v4().to_string();
-        let tmp_file_name = format!("wavdata-{}", tmp_file_id);
-        tmp_dir.push(&tmp_file_name);
-
-        debug!("Creating temporary file: {tmp_file_name}");
-
-        let file = File::create(&tmp_dir)?;
-        let buffered_writer = BufWriter::new(file);
-        let bytes_written = 0;
-        let file_name = file_name.to_owned();
-
-        Ok(Self {
+    /// Create the WAV file, and write a header for an empty file, which is updated when the
+    /// [`WaveWriter::commit`] method is called.
+    pub fn open(
+        file_name: &str,
+        audio_format_info: AudioFormatInfo,
+        header_format: HeaderFormat,
+    ) -> Res<Self> {
+        debug!("Creating WAV file: {file_name}");
+        let buffered_writer = BufWriter::new(File::create(file_name)?);
+        let header = WaveHeader::create(audio_format_info, 0, header_format)?;
+        // The header is the fmt chunk, after the RIFF marker and size, and the WAVE marker.
+        let fmt_chunk = header.as_bytes()[12..].to_vec();
+        let mut writer = Self {
             buffered_writer,
-            file_name,
-            tmp_file_name: tmp_dir.to_str().unwrap().to_owned(),
-            bytes_written,
+            bytes_written: 0,
             audio_format_info,
-        })
+            fmt_chunk,
+            max_riff_size: u32::MAX as u64,
+            is_committed: false,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    /// Return the size of everything before the audio.
+    fn header_size(&self) -> u64 {
+        // The RIFF marker and size, the WAVE marker, the ds64 or JUNK chunk, the fmt chunk and the
+        // data chunk header.
+        (12 + DataSize64::BYTES_IN_CHUNK + self.fmt_chunk.len() + 8) as u64
+    }
+
+    /// Return the RIFF size of the file, for the audio written so far.
+    fn riff_size(&self) -> u64 {
+        self.header_size() - 8 + self.bytes_written + self.bytes_written % 2
     }
 
-    /// Write a chunk of data to the buffer. Audio data should be appropriately formatted.
+    /// Whether the file is too large for a plain WAV file.
+    fn is_rf64(&self) -> bool {
+        self.riff_size() > self.max_riff_size
+    }
+
+    /// Write the header and the `data` chunk header for the audio written so far, at the current
+    /// position.
+    fn write_header(&mut self) -> Nothing {
+        let riff_size = self.riff_size();
+        let (riff_id, size_chunk, riff_size, data_size) = match self.is_rf64() {
+            true => {
+                let size_chunk = DataSize64 {
+                    riff_size,
+                    data_size: self.bytes_written,
+                    sample_count: self.bytes_written
+                        / self.audio_format_info.block_alignment() as u64,
+                };
+                (b"RF64", size_chunk.as_bytes(), u32::MAX, u32::MAX)
+            }
+            false => (
+                b"RIFF",
+                DataSize64::placeholder_bytes(),
+                riff_size as u32,
+                self.bytes_written as u32,
+            ),
+        };
+        self.buffered_writer.write_all(riff_id)?;
+        self.buffered_writer.write_all(&riff_size.to_le_bytes())?;
+        self.buffered_writer.write_all(b"WAVE")?;
+        self.buffered_writer.write_all(&size_chunk)?;
+        self.buffered_writer.write_all(&self.fmt_chunk)?;
+        self.buffered_writer.write_all(b"data")?;
+        self.buffered_writer.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Write a chunk of data to the file. Audio data should be appropriately formatted.
     pub fn write(&mut self, data: Vec<u8>) -> Nothing {
-        let bytes_to_write = data.len() + self.bytes_written;
-        if bytes_to_write > Self::WAV_MAX_BYTES {
-            error!("The maximum file size has been reached");
-            return Err(Box::new(WaveError::MaxFileSizeReached));
+        let was_rf64 = self.is_rf64();
+        self.is_committed = false;
+        self.buffered_writer.write_all(&data)?;
+        self.bytes_written += data.len() as u64;
+        if !was_rf64 && self.is_rf64() {
+            info!("Recording is too large for a WAV file, it will be written as RF64");
         }
-        self.bytes_written += self.buffered_writer.write(&data)?;
         Ok(())
     }
 
-    /// Commit the written audio data to disk
+    /// Fill in the sizes in the header, for the audio written so far, and flush the file to disk.
     pub fn commit(&mut self) -> Nothing {
-        debug!("Preparing to write from temp file to WAV file");
+        debug!(
+            "Updating WAV header for {} bytes of audio",
+            self.bytes_written
+        );
+        // Chunks are padded to an even number of bytes. The pad byte isn't part of the audio, and
+        // is overwritten by any audio written after the commit.
+        let data_end = SeekFrom::Start(self.header_size() + self.bytes_written);
+        if self.bytes_written % 2 == 1 {
+            self.buffered_writer.seek(data_end)?;
+            self.buffered_writer.write_all(&[0])?;
+        }
+        self.buffered_writer.rewind()?;
+        self.write_header()?;
+        self.buffered_writer.seek(data_end)?;
         self.buffered_writer.flush()?;
-        let mut data = Vec::new();
-        File::open(&self.tmp_file_name)?.read_to_end(&mut data)?;
+        self.is_committed = true;
+        Ok(())
+    }
 
-        let wav = WaveFile::create(data, self.audio_format_info)?;
-        wav.write(&self.file_name)?;
+    /// Close the file.
+    pub fn close(mut self) -> Nothing {
+        debug!("Closing WAV file");
+        self.buffered_writer.flush()?;
         Ok(())
     }
+}
 
-    /// Clean up the temporary file used by the [`BufWriter`].
-    pub fn close(self) -> Nothing {
-        debug!("Removing temporary file");
-        if Path::new(&self.tmp_file_name).exists() {
-            fs::remove_file(&self.tmp_file_name)?;
+impl Drop for WaveWriter {
+    /// Update the header for the audio written, if the writer wasn't committed, e.g. after an error
+    /// writing the audio, so whatever audio reached the file can still be played.
+    fn drop(&mut self) {
+        if self.is_committed {
+            return;
         }
-        Ok(())
+        if let Err(err) = self.commit() {
+            error!("Failed to update WAV header: {err}");
+        }
+    }
+}
+
+/// Reads the audio data from an existing WAV file, one buffer at a time.
+pub struct WaveReader {
+    buffered_reader: BufReader<File>,
+    bytes_remaining: usize,
+    audio_format_info: AudioFormatInfo,
+}
+
+impl WaveReader {
+    /// Open a WAV file, and read its header. The reader is left at the start of the audio data.
+    /// RF64 and BW64 files, which can hold more than 4 GiB of audio, are read as well.
+    pub fn open(file_name: &str) -> Res<Self> {
+        debug!("Opening WAV file: {file_name}");
+        let mut buffered_reader = BufReader::new(File::open(file_name)?);
+
+        let mut riff_header = [0u8; 12];
+        buffered_reader.read_exact(&mut riff_header)?;
+        let is_riff = matches!(&riff_header[0..4], b"RIFF" | b"RF64" | b"BW64");
+        if !is_riff || riff_header[8..12] != *b"WAVE" {
+            return Err(Box::new(WaveError::InvalidFile("Not a RIFF WAVE file")));
+        }
+
+        let mut audio_format_info = None;
+        let mut data_size_64 = None;
+        loop {
+            let mut chunk_header = [0u8; 8];
+            if buffered_reader.read_exact(&mut chunk_header).is_err() {
+                return Err(Box::new(WaveError::InvalidFile(
+                    "WAV file has no data chunk",
+                )));
+            }
+            let chunk_size = u32::from_le_bytes(chunk_header[4..8].try_into()?) as usize;
+            let is_header_chunk = matches!(&chunk_header[0..4], b"fmt " | b"ds64");
+            if is_header_chunk && chunk_size > MAX_HEADER_CHUNK_BYTES {
+                return Err(Box::new(WaveError::InvalidFile(
+                    "WAV header chunk is too large",
+                )));
+            }
+
+            match &chunk_header[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0u8; chunk_size];
+                    buffered_reader.read_exact(&mut fmt)?;
+                    audio_format_info = Some(Self::parse_fmt_chunk(&fmt)?);
+                }
+                b"ds64" => {
+                    let mut ds64 = vec![0u8; chunk_size];
+                    buffered_reader.read_exact(&mut ds64)?;
+                    data_size_64 = Some(DataSize64::parse(&ds64)?);
+                }
+                b"data" => {
+                    let audio_format_info = audio_format_info.ok_or(WaveError::InvalidFile(
+                        "WAV data chunk found before fmt chunk",
+                    ))?;
+                    // RF64 files give the size in the ds64 chunk instead.
+                    let bytes_remaining = match &data_size_64 {
+                        Some(ds64) if chunk_size == u32::MAX as usize => ds64.data_size as usize,
+                        _ => chunk_size,
+                    };
+                    return Ok(Self {
+                        buffered_reader,
+                        bytes_remaining,
+                        audio_format_info,
+                    });
+                }
+                id => {
+                    trace!("Skipping WAV chunk: {}", String::from_utf8_lossy(id));
+                    // Chunks are padded to an even number of bytes.
+                    let padded_size = chunk_size + chunk_size % 2;
+                    buffered_reader.seek(SeekFrom::Current(padded_size as i64))?;
+                }
+            }
+        }
+    }
+
+    /// Parse the contents of the `fmt ` chunk.
+    fn parse_fmt_chunk(fmt: &[u8]) -> Res<AudioFormatInfo> {
+        if fmt.len() < 16 {
+            return Err(Box::new(WaveError::InvalidFile(
+                "WAV fmt chunk is too short",
+            )));
+        }
+        let mut type_format = u16::from_le_bytes(fmt[0..2].try_into()?);
+        let num_channels = u16::from_le_bytes(fmt[2..4].try_into()?);
+        let sample_rate = u32::from_le_bytes(fmt[4..8].try_into()?);
+        let bit_depth = u16::from_le_bytes(fmt[14..16].try_into()?);
+
+        let mut valid_bits = bit_depth;
+        let mut channel_mask = None;
+        if type_format == EXTENSIBLE_TYPE_FORMAT && fmt.len() >= 26 {
+            valid_bits = u16::from_le_bytes(fmt[18..20].try_into()?);
+            channel_mask = Some(u32::from_le_bytes(fmt[20..24].try_into()?));
+            type_format = u16::from_le_bytes(fmt[24..26].try_into()?);
+        }
+
+        let format = match (type_format, bit_depth, valid_bits) {
+            (1, 32, 24) => SampleFormat::Int24In32,
+            _ => SampleFormat::from_type_format_header(type_format, bit_depth)
+                .ok_or(WaveError::UnsupportedFormat)?,
+        };
+        if sample_rate == 0 {
+            return Err(Box::new(WaveError::InvalidFile(
+                "WAV file has a sample rate of 0 Hz",
+            )));
+        }
+        let num_channels = u8::try_from(num_channels).map_err(|_| WaveError::UnsupportedFormat)?;
+        if num_channels == 0 {
+            return Err(Box::new(WaveError::UnsupportedFormat));
+        }
+        // Classic headers have no channel mask, so the usual positions are assumed.
+        let channel_layout = channel_mask.map_or_else(
+            || ChannelLayout::default_for(num_channels),
+            ChannelLayout::from_mask,
+        );
+
+        let format = AudioFormatInfo {
+            sample_rate,
+            num_channels,
+            format,
+            channel_layout,
+        };
+        // Whole frames are read, so a frame must have a size.
+        if format.block_alignment() == 0 {
+            return Err(Box::new(WaveError::UnsupportedFormat));
+        }
+        Ok(format)
+    }
+
+    /// Return the format of the audio data in the file.
+    pub fn audio_format(&self) -> AudioFormatInfo {
+        self.audio_format_info
+    }
+
+    /// Read audio data into the buffer, returning the number of bytes read. Only whole frames are
+    /// read. `0` is returned once all the audio data has been read.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Res<usize> {
+        let block_alignment = self.audio_format_info.block_alignment() as usize;
+        let max_bytes = buffer.len().min(self.bytes_remaining);
+        let max_bytes = max_bytes - max_bytes % block_alignment;
+
+        let mut bytes_read = 0;
+        while bytes_read < max_bytes {
+            match self
+                .buffered_reader
+                .read(&mut buffer[bytes_read..max_bytes])?
+            {
+                // The file is shorter than the data chunk claims, drop any partial frame.
+                0 => {
+                    bytes_read -= bytes_read % block_alignment;
+                    self.bytes_remaining = 0;
+                    return Ok(bytes_read);
+                }
+                n => bytes_read += n,
+            }
+        }
+        self.bytes_remaining -= bytes_read;
+        Ok(bytes_read)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::audio::SampleFormat;
+    use std::{env, fs};
+
+    use uuid::Uuid;
 
     use super::*;
 
@@ -293,6 +642,22 @@ mod tests {
         validate_wave_header_fields(44100, SampleFormat::Float32, 2, 100);
         validate_wave_header_fields(48000, SampleFormat::Float32, 2, 100);
         validate_wave_header_fields(96000, SampleFormat::Float32, 2, 100);
+
+        validate_wave_header_fields(8000, SampleFormat::UInt8, 1, 100);
+        validate_wave_header_fields(48000, SampleFormat::Int24In32, 2, 100);
+        validate_wave_header_fields(96000, SampleFormat::Float64, 2, 100);
+    }
+
+    #[test]
+    fn test_create_wave_header_rejects_byte_rate_larger_than_32_bits() {
+        let format = AudioFormatInfo {
+            sample_rate: 100_000_000,
+            num_channels: 8,
+            format: SampleFormat::Float64,
+            channel_layout: ChannelLayout::SURROUND_7_1,
+        };
+        let result = WaveHeader::create(format, 0, HeaderFormat::Auto);
+        assert!(result.is_err_and(|err| err.to_string().contains("6400000000")));
     }
 
     #[test]
@@ -327,56 +692,387 @@ mod tests {
         validate_wave_header_bytes(44100, SampleFormat::Float32, 2, 100);
         validate_wave_header_bytes(48000, SampleFormat::Float32, 2, 100);
         validate_wave_header_bytes(96000, SampleFormat::Float32, 2, 100);
+
+        validate_wave_header_bytes(8000, SampleFormat::UInt8, 1, 100);
+        validate_wave_header_bytes(48000, SampleFormat::Int24In32, 2, 100);
+        validate_wave_header_bytes(96000, SampleFormat::Float64, 2, 100);
+    }
+
+    #[test]
+    fn test_auto_header_format_is_only_extensible_when_required() {
+        let format = |num_channels, format| AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels,
+            format,
+            channel_layout: ChannelLayout::default_for(num_channels),
+        };
+        let auto = HeaderFormat::Auto;
+        assert!(!auto.is_extensible(&format(2, SampleFormat::Int16)));
+        assert!(!auto.is_extensible(&format(1, SampleFormat::UInt8)));
+        assert!(!auto.is_extensible(&format(2, SampleFormat::Float32)));
+        assert!(auto.is_extensible(&format(2, SampleFormat::Int24)));
+        assert!(auto.is_extensible(&format(2, SampleFormat::Int24In32)));
+        assert!(auto.is_extensible(&format(6, SampleFormat::Int16)));
+        let unpositioned = AudioFormatInfo {
+            channel_layout: ChannelLayout::UNSPECIFIED,
+            ..format(2, SampleFormat::Int16)
+        };
+        assert!(auto.is_extensible(&unpositioned));
+        assert!(!HeaderFormat::Classic.is_extensible(&format(6, SampleFormat::Int24)));
+        assert!(HeaderFormat::Extensible.is_extensible(&format(2, SampleFormat::Int16)));
+    }
+
+    #[test]
+    fn test_extensible_header_bytes_contain_extension_fields() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 6,
+            format: SampleFormat::Int24In32,
+            channel_layout: ChannelLayout::default_for(6),
+        };
+        let header = WaveHeader::create(format, 100, HeaderFormat::Auto)
+            .unwrap()
+            .as_bytes();
+        assert_eq!(header.len(), WaveHeader::BYTES_IN_EXTENSIBLE_HEADER - 8);
+        assert_eq!(header[4..8], (100u32 + 60).to_le_bytes());
+        assert_eq!(header[16..20], 40u32.to_le_bytes());
+        assert_eq!(header[20..22], 0xFFFEu16.to_le_bytes());
+        assert_eq!(header[32..34], 24u16.to_le_bytes());
+        assert_eq!(header[34..36], 32u16.to_le_bytes());
+        assert_eq!(header[36..38], 22u16.to_le_bytes());
+        assert_eq!(header[38..40], 24u16.to_le_bytes());
+        assert_eq!(header[40..44], 0x3Fu32.to_le_bytes());
+        assert_eq!(header[44..46], 1u16.to_le_bytes());
+        assert_eq!(header[46..60], SUB_FORMAT_GUID_SUFFIX);
+    }
+
+    #[test]
+    fn test_wave_reader_reads_valid_bits_from_extensible_header() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 2,
+            format: SampleFormat::Int24In32,
+            channel_layout: ChannelLayout::default_for(2),
+        };
+        let file_name = temp_file_name();
+        write_wave_file(&file_name, &[0u8; 16], format, HeaderFormat::Auto);
+        assert_eq!(WaveReader::open(&file_name).unwrap().audio_format(), format);
+
+        write_wave_file(&file_name, &[0u8; 16], format, HeaderFormat::Classic);
+        let read_format = WaveReader::open(&file_name).unwrap().audio_format();
+        assert_eq!(read_format.format, SampleFormat::Int32);
+
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_wave_reader_reads_channel_layout_from_extensible_header() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 4,
+            format: SampleFormat::Int16,
+            // Front left and right, side left and right.
+            channel_layout: ChannelLayout::from_mask(0x603),
+        };
+        let file_name = temp_file_name();
+        write_wave_file(&file_name, &[0u8; 16], format, HeaderFormat::Auto);
+        assert_eq!(WaveReader::open(&file_name).unwrap().audio_format(), format);
+
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_wave_reader_reads_format_and_data_written_by_wave_writer() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 2,
+            format: SampleFormat::Int24,
+            channel_layout: ChannelLayout::default_for(2),
+        };
+        let values: Vec<u8> = (0..60).collect();
+        let file_name = temp_file_name();
+        write_wave_file(&file_name, &values, format, HeaderFormat::Auto);
+
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let read_format = reader.audio_format();
+        assert_eq!(read_format.sample_rate, 48000);
+        assert_eq!(read_format.num_channels, 2);
+        assert_eq!(read_format.bit_depth(), 24);
+        assert_eq!(read_format.type_format_header(), 1);
+
+        let mut data = vec![0u8; 100];
+        assert_eq!(reader.read(&mut data).unwrap(), values.len());
+        assert_eq!(data[..values.len()], values);
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_contains_correct_static_data() {
-        let data = WaveData::create(vec![]).unwrap();
-        assert_eq!(data.data_header, *b"data");
+    fn test_wave_writer_reserves_space_for_ds64_chunk() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 6,
+            format: SampleFormat::Int24,
+            channel_layout: ChannelLayout::SURROUND_5_1,
+        };
+        let values: Vec<u8> = (0..180).collect();
+        let file_name = temp_file_name();
+        // A plain WAV file, with a JUNK chunk before the fmt chunk.
+        let header = WaveHeader::create(format, values.len(), HeaderFormat::Auto)
+            .unwrap()
+            .as_bytes();
+        let mut expected = header[..12].to_vec();
+        expected.extend(DataSize64::placeholder_bytes());
+        expected.extend_from_slice(&header[12..]);
+        expected.extend(data_chunk(&values));
+        let riff_size = expected.len() as u32 - 8;
+        expected[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+        let mut writer = WaveWriter::open(&file_name, format, HeaderFormat::Auto).unwrap();
+        for chunk in values.chunks(36) {
+            writer.write(chunk.to_vec()).unwrap();
+        }
+        writer.commit().unwrap();
+        writer.close().unwrap();
+        assert_eq!(fs::read(&file_name).unwrap(), expected);
+
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_conains_correct_size() {
-        let data = WaveData::create(vec![]).unwrap();
-        assert_eq!(data.size_in_bytes, 0u32.to_le_bytes());
+    fn test_wave_writer_pads_odd_sized_data() {
+        let format = AudioFormatInfo {
+            sample_rate: 8000,
+            num_channels: 1,
+            format: SampleFormat::UInt8,
+            channel_layout: ChannelLayout::MONO,
+        };
+        let file_name = temp_file_name();
+        let mut writer = WaveWriter::open(&file_name, format, HeaderFormat::Auto).unwrap();
+        writer.write(vec![1, 2, 3]).unwrap();
+        writer.commit().unwrap();
+        writer.close().unwrap();
+
+        let bytes = fs::read(&file_name).unwrap();
+        assert_eq!(bytes.len(), 80 + 4);
+        assert_eq!(bytes[4..8], 76u32.to_le_bytes());
+        assert_eq!(bytes[76..80], 3u32.to_le_bytes());
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let mut data = vec![0u8; 8];
+        assert_eq!(reader.read(&mut data).unwrap(), 3);
+        assert_eq!(data[..3], [1, 2, 3]);
+
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_wave_writer_continues_after_commit() {
+        let format = AudioFormatInfo {
+            sample_rate: 8000,
+            num_channels: 1,
+            format: SampleFormat::UInt8,
+            channel_layout: ChannelLayout::MONO,
+        };
+        let file_name = temp_file_name();
+        let mut writer = WaveWriter::open(&file_name, format, HeaderFormat::Auto).unwrap();
+        writer.write(vec![1, 2, 3]).unwrap();
+        writer.commit().unwrap();
+        // Committing again mustn't move the pad byte.
+        writer.commit().unwrap();
+        writer.write(vec![4, 5, 6, 7]).unwrap();
+        writer.commit().unwrap();
+        writer.close().unwrap();
+
+        let bytes = fs::read(&file_name).unwrap();
+        assert_eq!(bytes.len(), 80 + 8);
+        assert_eq!(bytes[76..80], 7u32.to_le_bytes());
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let mut data = vec![0u8; 16];
+        assert_eq!(reader.read(&mut data).unwrap(), 7);
+        assert_eq!(data[..7], [1, 2, 3, 4, 5, 6, 7]);
+
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_wave_writer_updates_header_when_dropped_without_commit() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 2,
+            format: SampleFormat::Int16,
+            channel_layout: ChannelLayout::STEREO,
+        };
+        let file_name = temp_file_name();
+        let mut writer = WaveWriter::open(&file_name, format, HeaderFormat::Auto).unwrap();
+        writer.write(vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        drop(writer);
+
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let mut data = vec![0u8; 16];
+        assert_eq!(reader.read(&mut data).unwrap(), 8);
+        assert_eq!(data[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
 
-        let data = WaveData::create(vec![0u8; 100]).unwrap();
-        assert_eq!(data.size_in_bytes, 100u32.to_le_bytes());
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_contains_correct_data() {
-        let data = WaveData::create(vec![]).unwrap();
-        assert_eq!(data.data, vec![]);
+    fn test_wave_writer_writes_rf64_when_sizes_are_too_large() {
+        let format = AudioFormatInfo {
+            sample_rate: 48000,
+            num_channels: 2,
+            format: SampleFormat::Int16,
+            channel_layout: ChannelLayout::STEREO,
+        };
+        let values: Vec<u8> = (0..200).collect();
+        let file_name = temp_file_name();
+        let mut writer = WaveWriter::open(&file_name, format, HeaderFormat::Auto).unwrap();
+        // Too small for the audio, as u32::MAX is for a large recording.
+        writer.max_riff_size = 100;
+        writer.write(values.clone()).unwrap();
+        writer.commit().unwrap();
+        writer.close().unwrap();
+
+        let bytes = fs::read(&file_name).unwrap();
+        assert_eq!(bytes[0..4], *b"RF64");
+        assert_eq!(bytes[4..8], u32::MAX.to_le_bytes());
+        assert_eq!(bytes[12..16], *b"ds64");
+        let ds64 = DataSize64::parse(&bytes[20..48]).unwrap();
+        assert_eq!(ds64.riff_size, bytes.len() as u64 - 8);
+        assert_eq!(ds64.data_size, 200);
+        assert_eq!(ds64.sample_count, 50);
+        assert_eq!(bytes[72..76], *b"data");
+        assert_eq!(bytes[76..80], u32::MAX.to_le_bytes());
+
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        assert_eq!(reader.audio_format(), format);
+        let mut data = vec![0u8; 400];
+        assert_eq!(reader.read(&mut data).unwrap(), 200);
+        assert_eq!(data[..200], values);
+
+        fs::remove_file(file_name).unwrap();
+    }
 
-        let values: Vec<u8> = vec![1, 2, 3, 4];
-        let data = WaveData::create(values.clone()).unwrap();
-        assert_eq!(data.data, values);
+    #[test]
+    fn test_wave_reader_only_reads_whole_frames() {
+        let format = AudioFormatInfo {
+            sample_rate: 44100,
+            num_channels: 2,
+            format: SampleFormat::Int16,
+            channel_layout: ChannelLayout::default_for(2),
+        };
+        let file_name = temp_file_name();
+        write_wave_file(&file_name, &[1u8; 16], format, HeaderFormat::Auto);
+
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let mut data = vec![0u8; 6];
+        assert_eq!(reader.read(&mut data).unwrap(), 4);
+        assert_eq!(reader.read(&mut data).unwrap(), 4);
+        assert_eq!(reader.read(&mut data).unwrap(), 4);
+        assert_eq!(reader.read(&mut data).unwrap(), 4);
+        assert_eq!(reader.read(&mut data).unwrap(), 0);
+
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_bytes_contains_correct_static_data() {
-        let data = WaveData::create(vec![]).unwrap().as_bytes();
-        assert_eq!(data[0..4], *b"data");
+    fn test_wave_reader_skips_unknown_chunks() {
+        let format = AudioFormatInfo {
+            sample_rate: 44100,
+            num_channels: 1,
+            format: SampleFormat::Float32,
+            channel_layout: ChannelLayout::default_for(1),
+        };
+        let mut bytes = WaveHeader::create(format, 4, HeaderFormat::Auto)
+            .unwrap()
+            .as_bytes();
+        // Odd sized chunk, followed by a padding byte.
+        bytes.extend_from_slice(b"LIST");
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend(data_chunk(&[1, 2, 3, 4]));
+        let file_name = temp_file_name();
+        fs::write(&file_name, bytes).unwrap();
+
+        let mut reader = WaveReader::open(&file_name).unwrap();
+        let mut data = vec![0u8; 4];
+        assert_eq!(reader.read(&mut data).unwrap(), 4);
+        assert_eq!(data, vec![1, 2, 3, 4]);
+
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_bytes_contains_correct_size() {
-        let data = WaveData::create(vec![]).unwrap().as_bytes();
-        assert_eq!(data[4..8], 0u32.to_le_bytes());
+    fn test_wave_reader_rejects_zero_sample_rate() {
+        let mut fmt =
+            create_wave_header(44100, SampleFormat::Int16, 2, 0).as_bytes()[20..36].to_vec();
+        assert!(WaveReader::parse_fmt_chunk(&fmt).is_ok());
+        fmt[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(WaveReader::parse_fmt_chunk(&fmt).is_err());
+    }
 
-        let data = WaveData::create(vec![1, 2, 3, 4]).unwrap().as_bytes();
-        assert_eq!(data[4..8], 4u32.to_le_bytes());
+    #[test]
+    fn test_wave_reader_rejects_zero_channels() {
+        let mut bytes = create_wave_header(44100, SampleFormat::Int16, 2, 4).as_bytes();
+        bytes[22..24].copy_from_slice(&0u16.to_le_bytes());
+        bytes.extend(data_chunk(&[1, 2, 3, 4]));
+        let file_name = temp_file_name();
+        fs::write(&file_name, bytes).unwrap();
+        assert!(WaveReader::open(&file_name).is_err());
+        fs::remove_file(file_name).unwrap();
     }
 
     #[test]
-    fn test_wave_data_bytes_contains_correct_data() {
-        let data = WaveData::create(vec![]).unwrap().as_bytes();
-        assert_eq!(data[8..], vec![]);
+    fn test_wave_reader_rejects_oversized_header_chunks() {
+        let mut bytes = create_wave_header(44100, SampleFormat::Int16, 2, 0).as_bytes();
+        // A fmt chunk claiming to be nearly 4 GiB.
+        bytes[16..20].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        let file_name = temp_file_name();
+        fs::write(&file_name, bytes).unwrap();
+        let err = WaveReader::open(&file_name).err().unwrap();
+        assert_eq!(err.to_string(), "WAV header chunk is too large");
+
+        let mut bytes = b"RF64\xFF\xFF\xFF\xFFWAVEds64".to_vec();
+        bytes.extend(0xFFFF_FFF0u32.to_le_bytes());
+        fs::write(&file_name, bytes).unwrap();
+        let err = WaveReader::open(&file_name).err().unwrap();
+        assert_eq!(err.to_string(), "WAV header chunk is too large");
+        fs::remove_file(file_name).unwrap();
+    }
 
-        let values: Vec<u8> = vec![1, 2, 3, 4];
-        let data = WaveData::create(values.clone()).unwrap().as_bytes();
-        assert_eq!(data[8..], values);
+    #[test]
+    fn test_wave_reader_rejects_files_which_are_not_wav() {
+        let file_name = temp_file_name();
+        fs::write(&file_name, b"this is not a WAV file").unwrap();
+        assert!(WaveReader::open(&file_name).is_err());
+        fs::remove_file(file_name).unwrap();
+    }
+
+    /// Write a WAV file containing the audio.
+    fn write_wave_file(
+        file_name: &str,
+        data: &[u8],
+        format: AudioFormatInfo,
+        header_format: HeaderFormat,
+    ) {
+        let mut writer = WaveWriter::open(file_name, format, header_format).unwrap();
+        writer.write(data.to_vec()).unwrap();
+        writer.commit().unwrap();
+        writer.close().unwrap();
+    }
+
+    /// Return the bytes of a `data` chunk containing the audio.
+    fn data_chunk(data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"data".to_vec();
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn temp_file_name() -> String {
+        let mut path = env::temp_dir();
+        path.push(format!("wavrec-test-{}.wav", Uuid::new_v4()));
+        path.to_str().unwrap().to_owned()
     }
 
     fn create_wave_header(
@@ -389,8 +1085,9 @@ mod tests {
             sample_rate,
             num_channels,
             format,
+            channel_layout: ChannelLayout::default_for(num_channels),
         };
-        WaveHeader::create(format, data_size).unwrap()
+        WaveHeader::create(format, data_size, HeaderFormat::Classic).unwrap()
     }
 
     fn validate_wave_header_fields(
@@ -414,18 +1111,18 @@ mod tests {
         assert_eq!(u16::from_le_bytes(header.num_channels), num_channels.into());
         assert_eq!(u32::from_le_bytes(header.sample_rate), sample_rate);
         assert_eq!(
-            u32::from_le_bytes(header.bytes_per_second),
-            (sample_rate * format.bit_depth() as u32 * num_channels as u32) / 8
+            u32::from_le_bytes(header.bytes_per_second) as u64,
+            (sample_rate as u64 * format.container_bits() as u64 * num_channels as u64) / 8
         );
 
         assert_eq!(
             u16::from_le_bytes(header.block_alignment),
-            ((num_channels * format.bit_depth()) / 8).into()
+            ((num_channels * format.container_bits()) / 8).into()
         );
 
         assert_eq!(
             u16::from_le_bytes(header.bit_depth),
-            format.bit_depth().into()
+            format.container_bits().into()
         );
     }
 
@@ -449,15 +1146,19 @@ mod tests {
         // Bytes per second
         assert_eq!(
             header[28..32],
-            ((sample_rate * format.bit_depth() as u32 * num_channels as u32) / 8).to_le_bytes()
+            ((sample_rate * format.container_bits() as u32 * num_channels as u32) / 8)
+                .to_le_bytes()
         );
 
         // Block alignment
         assert_eq!(
             header[32..34],
-            (((num_channels * format.bit_depth()) / 8) as u16).to_le_bytes()
+            (((num_channels * format.container_bits()) / 8) as u16).to_le_bytes()
         );
 
-        assert_eq!(header[34..36], (format.bit_depth() as u16).to_le_bytes());
+        assert_eq!(
+            header[34..36],
+            (format.container_bits() as u16).to_le_bytes()
+        );
     }
 }